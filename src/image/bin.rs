@@ -0,0 +1,156 @@
+//! Raw binary images.
+//!
+//! A binary file carries no address information, so it is placed at the given offset, or
+//! at the start of the first bank of the target memory when no offset is given. The whole
+//! image must fit in writable memory from that address.
+
+use core::fmt;
+
+use super::firmware::FirmwareImage;
+use super::Segment;
+use crate::util::memory::{Accessibility, MemoryMap};
+
+/// Errors that can occur when placing a binary image
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinError {
+    /// The memory map has no banks to place the image in
+    EmptyMemoryMap,
+    /// The start address is not in writable memory
+    NotWritable(usize),
+    /// The image extends past the end of the writable memory
+    OutOfBounds { address: usize, length: usize, limit: usize },
+}
+
+impl fmt::Display for BinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinError::EmptyMemoryMap => write!(f, "The memory map has no banks"),
+            BinError::NotWritable(address) => write!(f, "Address 0x{:08X} is not in writable memory", address),
+            BinError::OutOfBounds { address, length, limit } => write!(
+                f,
+                "Image of 0x{:X} bytes at 0x{:08X} extends past the end of writable memory at 0x{:08X}",
+                length, address, limit
+            ),
+        }
+    }
+}
+
+/// Places raw binary data in the given memory
+/// # Arguments
+/// * `data` - The binary file content
+/// * `offset` - The address to place the data at. The first bank is used if not given
+/// * `memory_map` - The memory map of the target alt setting
+///
+/// # Return
+/// A single segment holding the data
+pub fn place(data: Vec<u8>, offset: Option<usize>, memory_map: &MemoryMap) -> Result<Segment, BinError> {
+    let address = match offset {
+        Some(address) => address,
+        None => memory_map.banks().first().ok_or(BinError::EmptyMemoryMap)?.address,
+    };
+
+    // Find the end of the continuous writable memory, starting at the address
+    let mut limit: Option<usize> = None;
+    for bank in memory_map.banks() {
+        for sector in bank.sectors() {
+            let end = sector.address + sector.total_size();
+            let writable = sector.is_accessible(Accessibility::WRITE);
+
+            match limit {
+                None if writable && address >= sector.address && address < end => limit = Some(end),
+                Some(current) if writable && current == sector.address => limit = Some(end),
+                _ => {}
+            }
+        }
+    }
+
+    let limit = limit.ok_or(BinError::NotWritable(address))?;
+    if address.checked_add(data.len()).map_or(true, |end| end > limit) {
+        return Err(BinError::OutOfBounds {
+            address,
+            length: data.len(),
+            limit,
+        });
+    }
+
+    Ok(Segment::new(address, data))
+}
+
+/// Writes the image as a raw binary, starting at the first address of the image
+/// # Arguments
+/// * `image` - The image to write
+/// * `fill` - The byte value to fill the gaps between the segments with
+///
+/// # Return
+/// The start address and the binary data. The data is empty for an empty image
+pub fn write(image: &FirmwareImage, fill: u8) -> (usize, Vec<u8>) {
+    let mut filled = image.clone();
+    filled.fill_gaps(fill);
+    match filled.into_segments().pop() {
+        Some(segment) => (segment.address, segment.data),
+        None => (0, Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::stm32dfu::parse_memory_layout_string;
+    use crate::util::memory::{Bank, Sector};
+
+    #[test]
+    fn test_place_binary() {
+        let memmap = parse_memory_layout_string("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,03*128Kg").unwrap();
+
+        // Defaults to the start of the first bank
+        let segment = place(vec![0; 0x100], None, &memmap).unwrap();
+        assert_eq!(0x0800_0000, segment.address);
+
+        // Placed at an offset, spanning several sectors
+        let segment = place(vec![0; 0x20000], Some(0x0800_C000), &memmap).unwrap();
+        assert_eq!(0x0800_C000, segment.address);
+
+        // Ending exactly at the end of flash
+        assert!(place(vec![0; 0x100], Some(0x0807_FF00), &memmap).is_ok());
+    }
+
+    #[test]
+    fn test_place_binary_errors() {
+        let memmap = parse_memory_layout_string("@Internal Flash  /0x08000000/02*016Ka,02*016Kg").unwrap();
+
+        assert_eq!(
+            Err(BinError::OutOfBounds {
+                address: 0x0800_8000,
+                length: 0x8001,
+                limit: 0x0801_0000
+            }),
+            place(vec![0; 0x8001], Some(0x0800_8000), &memmap)
+        );
+        assert_eq!(Err(BinError::NotWritable(0x0800_0000)), place(vec![0; 16], None, &memmap));
+        assert_eq!(Err(BinError::NotWritable(0x0900_0000)), place(vec![0; 16], Some(0x0900_0000), &memmap));
+
+        // The end of the image would wrap around the address space
+        let top = Sector::new(0, usize::MAX - 0xFFF, 1, 0x800, Accessibility::READ_WRITE_ERASE);
+        let memmap = MemoryMap::new("Top", vec![Bank::from_sectors(0, vec![top])]);
+        assert_eq!(
+            Err(BinError::OutOfBounds {
+                address: usize::MAX - 0x8FF,
+                length: 0x1000,
+                limit: usize::MAX - 0x7FF
+            }),
+            place(vec![0; 0x1000], Some(usize::MAX - 0x8FF), &memmap)
+        );
+    }
+
+    #[test]
+    fn test_write_binary() {
+        let image = FirmwareImage::from_segments(vec![
+            Segment::new(0x0800_0002, vec![0x11, 0x22]),
+            Segment::new(0x0800_0006, vec![0x33]),
+        ])
+        .unwrap();
+
+        assert_eq!((0x0800_0002, vec![0x11, 0x22, 0x00, 0x00, 0x33]), write(&image, 0x00));
+        assert_eq!((0, vec![]), write(&FirmwareImage::new(), 0xFF));
+    }
+}
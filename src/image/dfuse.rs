@@ -0,0 +1,349 @@
+//! Reader and writer for the ST DfuSe file format (.dfu).
+//!
+//! The file has the following layout, with all values little endian:
+//! - Prefix: "DfuSe", bVersion (0x01), DFUImageSize (4), bTargets (1)
+//! - For each target:
+//!   - Target prefix: "Target", bAlternateSetting (1), bTargetNamed (4),
+//!     szTargetName (255), dwTargetSize (4), dwNbElements (4)
+//!   - For each element: dwElementAddress (4), dwElementSize (4), data
+//! - DFU suffix, with bcdDFU 0x011A
+//!
+//! Each target is meant for one alt setting of the device. The target is mapped to the
+//! alt setting whose memory layout has the same name as the target, and falls back to
+//! bAlternateSetting. Either way all the elements must fit in the memory of the alt setting.
+
+use core::fmt;
+
+use super::suffix::{DfuSuffix, SuffixError};
+use super::Segment;
+use crate::usb::descriptor::DFUSE_VERSION;
+use crate::usb::stm32dfu::parse_memory_layout_string;
+use crate::util::memory::MemoryMap;
+
+/// The signature at the start of the file
+const PREFIX_SIGNATURE: &[u8] = b"DfuSe";
+/// The signature at the start of each target
+const TARGET_SIGNATURE: &[u8] = b"Target";
+/// Length of the file prefix
+const PREFIX_LENGTH: usize = 11;
+/// Length of the target prefix
+const TARGET_PREFIX_LENGTH: usize = 274;
+/// Length of the target name field
+const TARGET_NAME_LENGTH: usize = 255;
+
+/// Errors that can occur when reading a DfuSe file
+#[derive(Debug, Clone, PartialEq)]
+pub enum DfuSeFileError {
+    /// The file does not start with "DfuSe"
+    InvalidPrefix,
+    /// The bVersion field is not supported
+    UnsupportedVersion(u8),
+    /// Target number `.0` does not start with "Target"
+    InvalidTargetPrefix(usize),
+    /// The file ends before a target or element it defines
+    Truncated,
+    /// The DFU suffix is invalid
+    Suffix(SuffixError),
+    /// No alt setting of the device matches the target
+    NoMatchingAltSetting { alt_setting: u8, name: Option<String> },
+}
+
+/// A target in the file, holding the data for one alt setting
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuSeTarget {
+    /// The alt setting the target was created for
+    pub alt_setting: u8,
+    /// The target name, if the target is named
+    pub name: Option<String>,
+    /// The image elements, in file order
+    pub elements: Vec<Segment>,
+}
+
+/// The content of a DfuSe file
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuSeFile {
+    /// The targets of the file
+    pub targets: Vec<DfuSeTarget>,
+    /// The DFU suffix
+    pub suffix: DfuSuffix,
+}
+
+impl fmt::Display for DfuSeFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DfuSeFileError::InvalidPrefix => write!(f, "Not a DfuSe file"),
+            DfuSeFileError::UnsupportedVersion(version) => write!(f, "Unsupported DfuSe version [{}]", version),
+            DfuSeFileError::InvalidTargetPrefix(index) => write!(f, "Target [{}] has an invalid prefix", index),
+            DfuSeFileError::Truncated => write!(f, "DfuSe file is truncated"),
+            DfuSeFileError::Suffix(e) => write!(f, "{}", e),
+            DfuSeFileError::NoMatchingAltSetting { alt_setting, name } => write!(
+                f,
+                "No alt setting of the device matches target [{}] \"{}\"",
+                alt_setting,
+                name.as_deref().unwrap_or("")
+            ),
+        }
+    }
+}
+
+impl From<SuffixError> for DfuSeFileError {
+    fn from(e: SuffixError) -> Self {
+        DfuSeFileError::Suffix(e)
+    }
+}
+
+/// Reads a little endian u32 at the given offset
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, DfuSeFileError> {
+    let bytes = data.get(offset..offset + 4).ok_or(DfuSeFileError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Checks if the data starts with the DfuSe prefix signature
+pub fn is_dfuse(data: &[u8]) -> bool {
+    data.starts_with(PREFIX_SIGNATURE)
+}
+
+/// Parses a DfuSe file, verifying the suffix CRC
+/// # Arguments
+/// * `data` - The complete file content
+pub fn parse(data: &[u8]) -> Result<DfuSeFile, DfuSeFileError> {
+    if !is_dfuse(data) {
+        return Err(DfuSeFileError::InvalidPrefix);
+    }
+
+    let (suffix, content) = DfuSuffix::parse(data)?;
+    if content.len() < PREFIX_LENGTH {
+        return Err(DfuSeFileError::Truncated);
+    }
+    if content[5] != 0x01 {
+        return Err(DfuSeFileError::UnsupportedVersion(content[5]));
+    }
+
+    let target_count = content[10] as usize;
+    let mut offset = PREFIX_LENGTH;
+    let mut targets: Vec<DfuSeTarget> = Vec::new();
+
+    for index in 0..target_count {
+        let prefix = content
+            .get(offset..offset + TARGET_PREFIX_LENGTH)
+            .ok_or(DfuSeFileError::Truncated)?;
+        if !prefix.starts_with(TARGET_SIGNATURE) {
+            return Err(DfuSeFileError::InvalidTargetPrefix(index));
+        }
+
+        let alt_setting = prefix[6];
+        let named = read_u32(prefix, 7)? != 0;
+        let name_field = &prefix[11..11 + TARGET_NAME_LENGTH];
+        let element_count = read_u32(prefix, 270)? as usize;
+
+        // The name is zero terminated
+        let name = if named {
+            let length = name_field.iter().position(|b| *b == 0).unwrap_or(TARGET_NAME_LENGTH);
+            Some(String::from_utf8_lossy(&name_field[..length]).into_owned())
+        } else {
+            None
+        };
+        offset += TARGET_PREFIX_LENGTH;
+
+        // Then read the elements
+        let mut elements: Vec<Segment> = Vec::new();
+        for _ in 0..element_count {
+            let address = read_u32(content, offset)? as usize;
+            let size = read_u32(content, offset + 4)? as usize;
+            let element_data = content
+                .get(offset + 8..offset + 8 + size)
+                .ok_or(DfuSeFileError::Truncated)?;
+
+            elements.push(Segment::new(address, element_data.to_vec()));
+            offset += 8 + size;
+        }
+
+        targets.push(DfuSeTarget {
+            alt_setting,
+            name,
+            elements,
+        });
+    }
+
+    Ok(DfuSeFile { targets, suffix })
+}
+
+impl DfuSeTarget {
+    /// Checks if all elements of the target are located within the memory map
+    pub fn fits(&self, memory_map: &MemoryMap) -> bool {
+        let sectors: Vec<_> = memory_map.banks().iter().flat_map(|b| b.sectors()).collect();
+        let contains = |address: usize| {
+            sectors
+                .iter()
+                .any(|s| address >= s.address && address < s.address + s.total_size())
+        };
+
+        self.elements.iter().all(|element| {
+            // Sectors are continuous within a bank, so checking both ends is enough
+            element.data.is_empty() || (contains(element.address) && contains(element.end_address() - 1))
+        })
+    }
+
+    /// Finds the alt setting of the device the target should be downloaded to
+    /// # Arguments
+    /// * `alt_strings` - The interface strings of the alt settings, indexed by alt setting
+    ///
+    /// # Return
+    /// The alt setting whose memory layout has the name of the target, or the bAlternateSetting
+    /// of the target if no name matches. All elements must fit the memory of the alt setting.
+    pub fn find_alt_setting<S: AsRef<str>>(&self, alt_strings: &[S]) -> Result<u8, DfuSeFileError> {
+        let maps: Vec<Option<MemoryMap>> = alt_strings
+            .iter()
+            .map(|s| parse_memory_layout_string(s.as_ref()).ok())
+            .collect();
+
+        // First look for a memory layout with a matching name
+        if let Some(name) = &self.name {
+            let by_name = maps.iter().position(|m| match m {
+                Some(map) => map.name == name.trim() && self.fits(map),
+                None => false,
+            });
+            if let Some(alt) = by_name {
+                return Ok(alt as u8);
+            }
+        }
+
+        // Then fall back on the alt setting given in the file
+        match maps.get(self.alt_setting as usize) {
+            Some(Some(map)) if self.fits(map) => Ok(self.alt_setting),
+            _ => Err(DfuSeFileError::NoMatchingAltSetting {
+                alt_setting: self.alt_setting,
+                name: self.name.clone(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for DfuSeTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Target [{}] \"{}\": [{} elements]",
+            self.alt_setting,
+            self.name.as_deref().unwrap_or(""),
+            self.elements.len()
+        )
+    }
+}
+
+/// Writes a DfuSe file holding the given targets
+/// # Arguments
+/// * `targets` - The targets, each holding the elements for one alt setting
+/// * `vendor_id` - The vendor ID of the suffix
+/// * `product_id` - The product ID of the suffix
+/// * `device` - The bcdDevice of the suffix
+///
+/// # Return
+/// The complete file content, including the suffix
+pub fn write(targets: &[DfuSeTarget], vendor_id: u16, product_id: u16, device: u16) -> Vec<u8> {
+    let mut out = PREFIX_SIGNATURE.to_vec();
+    out.push(1);
+    out.extend_from_slice(&[0; 4]);
+    out.push(targets.len() as u8);
+
+    for target in targets {
+        let size: usize = target.elements.iter().map(|e| e.data.len() + 8).sum();
+
+        // The name is zero padded, and cut to leave room for a terminating zero
+        let mut name = [0u8; TARGET_NAME_LENGTH];
+        if let Some(n) = &target.name {
+            let length = n.len().min(TARGET_NAME_LENGTH - 1);
+            name[..length].copy_from_slice(&n.as_bytes()[..length]);
+        }
+
+        out.extend_from_slice(TARGET_SIGNATURE);
+        out.push(target.alt_setting);
+        out.extend_from_slice(&u32::from(target.name.is_some()).to_le_bytes());
+        out.extend_from_slice(&name);
+        out.extend_from_slice(&(size as u32).to_le_bytes());
+        out.extend_from_slice(&(target.elements.len() as u32).to_le_bytes());
+        for element in &target.elements {
+            out.extend_from_slice(&(element.address as u32).to_le_bytes());
+            out.extend_from_slice(&(element.data.len() as u32).to_le_bytes());
+            out.extend_from_slice(&element.data);
+        }
+    }
+
+    // DFUImageSize covers the file without the suffix
+    let image_size = (out.len() as u32).to_le_bytes();
+    out[6..10].copy_from_slice(&image_size);
+    DfuSuffix::append(&mut out, vendor_id, product_id, device, DFUSE_VERSION);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::sim::STM32F4_ALT_SETTINGS;
+
+    fn test_targets() -> Vec<DfuSeTarget> {
+        vec![
+            DfuSeTarget {
+                alt_setting: 0,
+                name: Some("ST...".to_string()),
+                elements: vec![
+                    Segment::new(0x0800_0000, vec![0x11; 0x100]),
+                    Segment::new(0x0800_8000, vec![0x22; 0x10]),
+                ],
+            },
+            DfuSeTarget {
+                alt_setting: 7,
+                name: Some("Option Bytes".to_string()),
+                elements: vec![Segment::new(0x1FFF_C000, vec![0xAA; 16])],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_dfuse_file() {
+        let targets = test_targets();
+        let file = parse(&write(&targets, 0x0483, 0xDF11, 0xFFFF)).unwrap();
+
+        assert_eq!(targets, file.targets);
+        assert_eq!(0x0483, file.suffix.vendor_id);
+        assert_eq!(0x011A, file.suffix.dfu_version);
+    }
+
+    #[test]
+    fn test_parse_dfuse_errors() {
+        let mut data = write(&test_targets(), 0x0483, 0xDF11, 0xFFFF);
+        data[20] ^= 0x01;
+        assert!(matches!(parse(&data), Err(DfuSeFileError::Suffix(SuffixError::CrcMismatch { .. }))));
+
+        assert_eq!(Err(DfuSeFileError::InvalidPrefix), parse(b"DfuSx"));
+
+        let mut data = write(&test_targets()[..1], 0x0483, 0xDF11, 0xFFFF);
+        data.truncate(PREFIX_LENGTH + 2);
+        DfuSuffix::append(&mut data, 0x0483, 0xDF11, 0xFFFF, 0x011A);
+        assert_eq!(Err(DfuSeFileError::Truncated), parse(&data));
+    }
+
+    #[test]
+    fn test_find_alt_setting() {
+        let targets = test_targets();
+
+        // Unknown name, so bAlternateSetting is used
+        assert_eq!(Ok(0), targets[0].find_alt_setting(&STM32F4_ALT_SETTINGS));
+        // Matched by name, even if bAlternateSetting is wrong
+        assert_eq!(Ok(1), targets[1].find_alt_setting(&STM32F4_ALT_SETTINGS));
+
+        // The elements does not fit the alt setting
+        let target = DfuSeTarget {
+            alt_setting: 2,
+            name: None,
+            elements: vec![Segment::new(0x0800_0000, vec![0; 4])],
+        };
+        assert_eq!(
+            Err(DfuSeFileError::NoMatchingAltSetting {
+                alt_setting: 2,
+                name: None
+            }),
+            target.find_alt_setting(&STM32F4_ALT_SETTINGS)
+        );
+    }
+}
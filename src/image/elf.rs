@@ -0,0 +1,276 @@
+//! ELF file loader.
+//!
+//! Only the program headers are used: every PT_LOAD segment with file content is placed
+//! at its physical address (LMA, p_paddr), which is where the data lives in flash. Both
+//! ELF32 and ELF64, in little and big endian, are supported.
+
+use core::fmt;
+
+use super::firmware::{FirmwareError, FirmwareImage};
+use super::Segment;
+
+/// The ELF identification magic
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+/// Values of the e_ident fields
+const ELF_CLASS_32: u8 = 1;
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_DATA_MSB: u8 = 2;
+
+/// Program header type of loadable segments
+const PT_LOAD: u32 = 1;
+
+/// Errors that can occur when reading an ELF file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ElfError {
+    /// The file does not start with the ELF magic
+    InvalidMagic,
+    /// The EI_CLASS field is neither ELF32 nor ELF64
+    UnsupportedClass(u8),
+    /// The EI_DATA field is neither little nor big endian
+    UnsupportedEncoding(u8),
+    /// The file ends before a header or segment it references
+    Truncated,
+    /// Two loadable segments are placed at the same address
+    Overlap(usize),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::InvalidMagic => write!(f, "Not an ELF file"),
+            ElfError::UnsupportedClass(class) => write!(f, "Unsupported ELF class [{}]", class),
+            ElfError::UnsupportedEncoding(data) => write!(f, "Unsupported ELF data encoding [{}]", data),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::Overlap(address) => write!(f, "Loadable segments overlap at 0x{:08X}", address),
+        }
+    }
+}
+
+impl From<FirmwareError> for ElfError {
+    fn from(e: FirmwareError) -> Self {
+        match e {
+            FirmwareError::Overlap(address) => ElfError::Overlap(address),
+        }
+    }
+}
+
+/// Checks if the data starts with the ELF magic
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= 4 && data[..4] == ELF_MAGIC
+}
+
+/// Reads fields from the file, using the word size and endianness of the file
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    /// Returns the given number of bytes at the offset. The offset comes from the file,
+    /// so a range past the end of the address space is treated as past the end of the file.
+    fn bytes(&self, offset: usize, count: usize) -> Result<&'a [u8], ElfError> {
+        let end = offset.checked_add(count).ok_or(ElfError::Truncated)?;
+        self.data.get(offset..end).ok_or(ElfError::Truncated)
+    }
+
+    /// Reads an unsigned value of 1 to 8 bytes
+    fn uint(&self, offset: usize, count: usize) -> Result<u64, ElfError> {
+        let bytes = self.bytes(offset, count)?;
+        let fold = |acc: u64, b: &u8| acc << 8 | u64::from(*b);
+        Ok(if self.big_endian {
+            bytes.iter().fold(0, fold)
+        } else {
+            bytes.iter().rev().fold(0, fold)
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Result<usize, ElfError> {
+        Ok(self.uint(offset, 2)? as usize)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ElfError> {
+        Ok(self.uint(offset, 4)? as u32)
+    }
+
+    /// Reads an address or offset, which is 4 or 8 bytes depending on the class
+    fn word(&self, offset: usize) -> Result<usize, ElfError> {
+        Ok(self.uint(offset, if self.is_64 { 8 } else { 4 })? as usize)
+    }
+}
+
+/// Parses an ELF file, and extracts the loadable segments
+/// # Arguments
+/// * `data` - The file content
+///
+/// # Return
+/// The loadable segments at their physical addresses, and the entry point
+pub fn parse(data: &[u8]) -> Result<FirmwareImage, ElfError> {
+    if data.len() < 16 || !is_elf(data) {
+        return Err(ElfError::InvalidMagic);
+    }
+
+    let is_64 = match data[4] {
+        ELF_CLASS_32 => false,
+        ELF_CLASS_64 => true,
+        class => return Err(ElfError::UnsupportedClass(class)),
+    };
+    let big_endian = match data[5] {
+        ELF_DATA_LSB => false,
+        ELF_DATA_MSB => true,
+        encoding => return Err(ElfError::UnsupportedEncoding(encoding)),
+    };
+    let reader = Reader { data, is_64, big_endian };
+
+    // The location of the header fields depends on the word size
+    let (entry, phoff, phentsize, phnum) = if is_64 {
+        (reader.word(24)?, reader.word(32)?, reader.u16(54)?, reader.u16(56)?)
+    } else {
+        (reader.word(24)?, reader.word(28)?, reader.u16(42)?, reader.u16(44)?)
+    };
+
+    let mut segments: Vec<Segment> = Vec::new();
+    for index in 0..phnum {
+        let header = index
+            .checked_mul(phentsize)
+            .and_then(|relative| relative.checked_add(phoff))
+            .ok_or(ElfError::Truncated)?;
+
+        if reader.u32(header)? != PT_LOAD {
+            continue;
+        }
+
+        let (offset, paddr, filesz) = if is_64 {
+            (reader.word(header + 8)?, reader.word(header + 24)?, reader.word(header + 32)?)
+        } else {
+            (reader.word(header + 4)?, reader.word(header + 12)?, reader.word(header + 16)?)
+        };
+
+        // Segments without file content (e.g. .bss) have nothing to program
+        if filesz == 0 {
+            continue;
+        }
+
+        segments.push(Segment::new(paddr, reader.bytes(offset, filesz)?.to_vec()));
+    }
+
+    let mut image = FirmwareImage::from_segments(segments)?;
+    image.entry_point = Some(entry);
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A program header used to build test files: (type, paddr, content, memsz)
+    type TestSegment<'a> = (u32, u64, &'a [u8], u64);
+
+    /// Builds an ELF file with the given program headers
+    fn build_elf(is_64: bool, big_endian: bool, entry: u64, segments: &[TestSegment]) -> Vec<u8> {
+        let put = |out: &mut Vec<u8>, value: u64, count: usize| {
+            let bytes = value.to_le_bytes();
+            if big_endian {
+                out.extend(bytes[..count].iter().rev());
+            } else {
+                out.extend_from_slice(&bytes[..count]);
+            }
+        };
+        let word = if is_64 { 8 } else { 4 };
+        let ehsize = if is_64 { 64 } else { 52 };
+        let phentsize = if is_64 { 56 } else { 32 };
+
+        let mut out = vec![0x7F, b'E', b'L', b'F', if is_64 { 2 } else { 1 }, if big_endian { 2 } else { 1 }, 1];
+        out.resize(16, 0);
+        put(&mut out, 2, 2); // e_type
+        put(&mut out, 40, 2); // e_machine
+        put(&mut out, 1, 4); // e_version
+        put(&mut out, entry, word);
+        put(&mut out, ehsize as u64, word); // e_phoff
+        put(&mut out, 0, word); // e_shoff
+        put(&mut out, 0, 4); // e_flags
+        put(&mut out, ehsize as u64, 2);
+        put(&mut out, phentsize as u64, 2);
+        put(&mut out, segments.len() as u64, 2);
+        put(&mut out, 0, 6); // e_shentsize, e_shnum, e_shstrndx
+
+        // Segment content follows the program headers
+        let mut offset = (ehsize + phentsize * segments.len()) as u64;
+        for (p_type, paddr, content, memsz) in segments {
+            let filesz = content.len() as u64;
+            put(&mut out, u64::from(*p_type), 4);
+            if is_64 {
+                put(&mut out, 5, 4); // p_flags
+            }
+            put(&mut out, offset, word);
+            put(&mut out, paddr + 0x1000_0000, word); // p_vaddr differs from the LMA
+            put(&mut out, *paddr, word);
+            put(&mut out, filesz, word);
+            put(&mut out, *memsz, word);
+            if !is_64 {
+                put(&mut out, 5, 4); // p_flags
+            }
+            put(&mut out, 4, word); // p_align
+            offset += filesz;
+        }
+        for (_, _, content, _) in segments {
+            out.extend_from_slice(content);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_elf32_little_endian() {
+        let text: Vec<u8> = (0..64u8).collect();
+        let data = [0xD0u8; 8];
+        let elf = build_elf(
+            false,
+            false,
+            0x0800_0101,
+            &[
+                (PT_LOAD, 0x0800_0000, &text, 64),
+                (6, 0x0000_0000, &[1, 2, 3, 4], 4),
+                (PT_LOAD, 0x0800_0040, &data, 8),
+                (PT_LOAD, 0x2000_0000, &[], 0x400),
+            ],
+        );
+        let image = parse(&elf).unwrap();
+
+        let mut expected = text.clone();
+        expected.extend_from_slice(&data);
+        assert_eq!(&[Segment::new(0x0800_0000, expected)], image.segments());
+        assert_eq!(Some(0x0800_0101), image.entry_point);
+    }
+
+    #[test]
+    fn test_parse_elf64_big_endian() {
+        let elf = build_elf(true, true, 0x1_0000_0000, &[(PT_LOAD, 0x8000, &[0xAB; 16], 32)]);
+        let image = parse(&elf).unwrap();
+
+        assert_eq!(&[Segment::new(0x8000, vec![0xAB; 16])], image.segments());
+        assert_eq!(Some(0x1_0000_0000), image.entry_point);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(ElfError::InvalidMagic), parse(b"\x7FELV0000000000000000"));
+
+        let mut elf = build_elf(false, false, 0, &[(PT_LOAD, 0, &[1, 2, 3], 3)]);
+        elf[4] = 3;
+        assert_eq!(Err(ElfError::UnsupportedClass(3)), parse(&elf));
+
+        let elf = build_elf(false, false, 0, &[(PT_LOAD, 0, &[1, 2, 3], 3)]);
+        assert_eq!(Err(ElfError::Truncated), parse(&elf[..elf.len() - 1]));
+
+        // Offsets past the end of the address space must not overflow
+        let mut elf = build_elf(true, false, 0, &[(PT_LOAD, 0, &[1, 2, 3], 3), (PT_LOAD, 3, &[4], 1)]);
+        elf[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Err(ElfError::Truncated), parse(&elf));
+
+        let mut elf = build_elf(true, false, 0, &[(PT_LOAD, 0, &[1, 2, 3], 3)]);
+        elf[72..80].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(Err(ElfError::Truncated), parse(&elf));
+    }
+}
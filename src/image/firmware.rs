@@ -0,0 +1,316 @@
+//! The in-memory firmware image, shared by all image loaders.
+//!
+//! A firmware image is a set of segments, kept sorted by address and never overlapping.
+//! Segments which directly follow each other are joined when added, so the image always
+//! holds the fewest possible segments. Erase planning, verification and conversion all
+//! work on this model, no matter which file format the image was loaded from.
+
+use core::fmt;
+
+use super::Segment;
+
+/// Errors that can occur when building a firmware image
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirmwareError {
+    /// Data is given twice for the address
+    Overlap(usize),
+}
+
+/// A firmware image, as sorted non-overlapping segments
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FirmwareImage {
+    /// The segments, sorted by address
+    segments: Vec<Segment>,
+    /// The entry point of the firmware, if known
+    pub entry_point: Option<usize>,
+}
+
+/// Iterator over the blocks of an image. See [`FirmwareImage::blocks`]
+pub struct Blocks<'a> {
+    image: &'a FirmwareImage,
+    block_size: usize,
+    fill: u8,
+    address: usize,
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FirmwareError::Overlap(address) => write!(f, "Image data overlaps at 0x{:08X}", address),
+        }
+    }
+}
+
+impl FirmwareImage {
+    /// Creates an empty image
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an image from segments given in any order
+    /// # Arguments
+    /// * `segments` - The segments, which must not overlap
+    pub fn from_segments(segments: Vec<Segment>) -> Result<Self, FirmwareError> {
+        let mut image = FirmwareImage::new();
+        for segment in segments {
+            image.add_segment(segment)?;
+        }
+        Ok(image)
+    }
+
+    /// Returns the segments, sorted by address
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Consumes the image, returning the segments
+    pub fn into_segments(self) -> Vec<Segment> {
+        self.segments
+    }
+
+    /// Checks if the image holds any data
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Returns the number of data bytes in the image, not counting gaps
+    pub fn data_size(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    /// Returns the address of the first byte of the image
+    pub fn start_address(&self) -> Option<usize> {
+        self.segments.first().map(|s| s.address)
+    }
+
+    /// Returns the address following the last byte of the image
+    pub fn end_address(&self) -> Option<usize> {
+        self.segments.last().map(Segment::end_address)
+    }
+
+    /// Checks if the image has data anywhere in the given address range
+    pub fn overlaps(&self, address: usize, length: usize) -> bool {
+        // A range reaching past the end of the address space runs up to its end
+        let end = address.saturating_add(length);
+        length > 0 && self.segments.iter().any(|s| s.address < end && s.end_address() > address)
+    }
+
+    /// Adds a segment to the image, joining it with the segments it directly follows or precedes
+    /// # Arguments
+    /// * `segment` - The segment to add. It must not overlap any data already in the image
+    pub fn add_segment(&mut self, segment: Segment) -> Result<(), FirmwareError> {
+        if segment.data.is_empty() {
+            return Ok(());
+        }
+
+        // Find where to insert, and make sure the neighbours do not overlap
+        let index = self.segments.partition_point(|s| s.address < segment.address);
+        if let Some(previous) = index.checked_sub(1).map(|i| &self.segments[i]) {
+            if previous.end_address() > segment.address {
+                return Err(FirmwareError::Overlap(segment.address));
+            }
+        }
+        if let Some(next) = self.segments.get(index) {
+            if next.address < segment.end_address() {
+                return Err(FirmwareError::Overlap(next.address));
+            }
+        }
+
+        self.segments.insert(index, segment);
+
+        // Join with the following segment first, so the index stays valid
+        if index + 1 < self.segments.len() && self.segments[index].end_address() == self.segments[index + 1].address {
+            let next = self.segments.remove(index + 1);
+            self.segments[index].data.extend(next.data);
+        }
+        if index > 0 && self.segments[index - 1].end_address() == self.segments[index].address {
+            let current = self.segments.remove(index);
+            self.segments[index - 1].data.extend(current.data);
+        }
+        Ok(())
+    }
+
+    /// Merges all the segments of another image into this one. The entry point of this image is
+    /// kept, unless it has none.
+    pub fn merge(&mut self, other: FirmwareImage) -> Result<(), FirmwareError> {
+        if self.entry_point.is_none() {
+            self.entry_point = other.entry_point;
+        }
+        for segment in other.segments {
+            self.add_segment(segment)?;
+        }
+        Ok(())
+    }
+
+    /// Fills the gaps between the segments, leaving a single segment covering the whole image
+    /// # Arguments
+    /// * `fill` - The byte value to fill the gaps with
+    pub fn fill_gaps(&mut self, fill: u8) {
+        let mut segments = self.segments.drain(..);
+        if let Some(mut joined) = segments.next() {
+            for segment in segments {
+                joined.data.resize(segment.address - joined.address, fill);
+                joined.data.extend(segment.data);
+            }
+            self.segments = vec![joined];
+        }
+    }
+
+    /// Returns the part of the image within the given address range. The entry point is not kept.
+    /// # Arguments
+    /// * `address` - The first address of the range
+    /// * `length` - The length of the range in bytes
+    ///
+    /// # Return
+    /// The part of the image, or None if the range reaches past the end of the address space
+    pub fn slice(&self, address: usize, length: usize) -> Option<FirmwareImage> {
+        let end = address.checked_add(length)?;
+        let segments = self
+            .segments
+            .iter()
+            .filter(|s| s.address < end && s.end_address() > address)
+            .map(|s| {
+                let start = address.max(s.address);
+                let stop = end.min(s.end_address());
+                Segment::new(start, s.data[start - s.address..stop - s.address].to_vec())
+            })
+            .collect();
+
+        Some(FirmwareImage {
+            segments,
+            entry_point: None,
+        })
+    }
+
+    /// Iterates the image in blocks aligned to the block size. Only blocks holding image data
+    /// are returned, each as a segment of exactly one block, with missing bytes set to the fill value.
+    /// # Arguments
+    /// * `block_size` - The size and alignment of the blocks
+    /// * `fill` - The byte value used where the image has no data
+    ///
+    /// # Return
+    /// The iterator, or None if the block size is zero
+    pub fn blocks(&self, block_size: usize, fill: u8) -> Option<Blocks<'_>> {
+        if block_size == 0 {
+            return None;
+        }
+        Some(Blocks {
+            image: self,
+            block_size,
+            fill,
+            address: 0,
+        })
+    }
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Segment;
+
+    /// Returns the next block holding image data. A block reaching past the end of the
+    /// address space ends the iteration.
+    fn next(&mut self) -> Option<Segment> {
+        // Find the first segment with data at or after the current address
+        let segment = self.image.segments.iter().find(|s| s.end_address() > self.address)?;
+        let block_address = self.address.max(segment.address - segment.address % self.block_size);
+        self.address = block_address.checked_add(self.block_size)?;
+
+        let mut data = vec![self.fill; self.block_size];
+        for part in self.image.slice(block_address, self.block_size)?.segments {
+            let offset = part.address - block_address;
+            data[offset..offset + part.data.len()].copy_from_slice(&part.data);
+        }
+        Some(Segment::new(block_address, data))
+    }
+}
+
+impl fmt::Display for FirmwareImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in &self.segments {
+            writeln!(f, " - Segment @ [0x{:08X}]: [0x{:X} bytes]", segment.address, segment.data.len())?;
+        }
+        if let Some(entry) = self.entry_point {
+            writeln!(f, " - Entry point @ [0x{:08X}]", entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_segments_sorts_and_joins() {
+        let image = FirmwareImage::from_segments(vec![
+            Segment::new(0x1010, vec![3; 0x10]),
+            Segment::new(0x1000, vec![1; 0x08]),
+            Segment::new(0x2000, vec![4; 0x04]),
+            Segment::new(0x1008, vec![2; 0x08]),
+        ])
+        .unwrap();
+
+        assert_eq!(2, image.segments().len());
+        assert_eq!(0x1000, image.segments()[0].address);
+        assert_eq!(0x20, image.segments()[0].data.len());
+        assert_eq!(Some(0x2004), image.end_address());
+        assert_eq!(0x24, image.data_size());
+    }
+
+    #[test]
+    fn test_overlap_detection() {
+        let mut image = FirmwareImage::from_segments(vec![Segment::new(0x1000, vec![0; 0x10])]).unwrap();
+
+        assert_eq!(Err(FirmwareError::Overlap(0x100F)), image.add_segment(Segment::new(0x100F, vec![0; 2])));
+        assert_eq!(Err(FirmwareError::Overlap(0x1000)), image.add_segment(Segment::new(0x0FF0, vec![0; 0x11])));
+        assert!(image.overlaps(0x0FFF, 2));
+        assert!(!image.overlaps(0x1010, 0x100));
+        assert!(image.overlaps(0x100F, usize::MAX));
+
+        let other = FirmwareImage::from_segments(vec![Segment::new(0x0FF0, vec![0; 0x10])]).unwrap();
+        assert!(image.merge(other).is_ok());
+        assert_eq!(1, image.segments().len());
+    }
+
+    #[test]
+    fn test_fill_gaps_and_slice() {
+        let mut image = FirmwareImage::from_segments(vec![
+            Segment::new(0x100, vec![0x11; 4]),
+            Segment::new(0x108, vec![0x22; 4]),
+        ])
+        .unwrap();
+
+        let slice = image.slice(0x102, 0x8).unwrap();
+        assert_eq!(
+            &[Segment::new(0x102, vec![0x11; 2]), Segment::new(0x108, vec![0x22; 2])],
+            slice.segments()
+        );
+        assert_eq!(None, image.slice(0x102, usize::MAX));
+
+        image.fill_gaps(0xFF);
+        assert_eq!(
+            &[Segment::new(0x100, vec![0x11, 0x11, 0x11, 0x11, 0xFF, 0xFF, 0xFF, 0xFF, 0x22, 0x22, 0x22, 0x22])],
+            image.segments()
+        );
+    }
+
+    #[test]
+    fn test_aligned_blocks() {
+        let image = FirmwareImage::from_segments(vec![
+            Segment::new(0x0E, vec![0x11; 4]),
+            Segment::new(0x18, vec![0x22; 2]),
+            Segment::new(0x40, vec![0x33; 1]),
+        ])
+        .unwrap();
+
+        let blocks: Vec<Segment> = image.blocks(0x10, 0xFF).unwrap().collect();
+        assert_eq!(3, blocks.len());
+        assert_eq!(0x00, blocks[0].address);
+        assert_eq!(&[0xFF, 0x11, 0x11], &blocks[0].data[0x0D..]);
+        assert_eq!(0x10, blocks[1].address);
+        assert_eq!(&[0x11, 0x11, 0xFF, 0xFF], &blocks[1].data[..4]);
+        assert_eq!(&[0x22, 0x22], &blocks[1].data[8..10]);
+        assert_eq!(0x40, blocks[2].address);
+        assert!(blocks.iter().all(|b| b.data.len() == 0x10));
+        assert!(image.blocks(0, 0xFF).is_none());
+    }
+}
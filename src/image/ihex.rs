@@ -0,0 +1,348 @@
+//! Intel HEX file parser and writer.
+//!
+//! Each line in the file is a record on the format `:LLAAAATT<data>CC`, where LL is the
+//! number of data bytes, AAAA the 16 bit address offset, TT the record type and CC the
+//! two's complement checksum of all the preceding bytes. The supported record types are:
+//! - 00: Data
+//! - 01: End of file
+//! - 02: Extended segment address (base address is the value times 16)
+//! - 03: Start segment address (CS:IP)
+//! - 04: Extended linear address (upper 16 bits of the address)
+//! - 05: Start linear address (EIP)
+
+use core::fmt;
+use core::fmt::Write;
+
+use super::firmware::{FirmwareError, FirmwareImage};
+use super::Segment;
+
+/// Number of data bytes per record when writing
+const WRITE_RECORD_SIZE: usize = 16;
+
+/// The highest address that can be written, using extended linear address records
+const MAX_ADDRESS: usize = 0xFFFF_FFFF;
+
+/// Record types
+const RECORD_DATA: u8 = 0x00;
+const RECORD_END_OF_FILE: u8 = 0x01;
+const RECORD_EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const RECORD_START_SEGMENT_ADDRESS: u8 = 0x03;
+const RECORD_EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const RECORD_START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Errors that can occur when parsing a HEX file. The line numbers start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HexError {
+    /// The line does not start with ':'
+    InvalidStartCode { line: usize },
+    /// The line contains characters which are not hex digits, or an odd number of them
+    InvalidHexDigit { line: usize },
+    /// The byte count does not match the length of the line
+    InvalidLength { line: usize },
+    /// The checksum does not match the record
+    ChecksumMismatch { line: usize, expected: u8, actual: u8 },
+    /// The record type is not known
+    UnsupportedRecordType { line: usize, record_type: u8 },
+    /// The record has an invalid length for its type
+    InvalidRecord { line: usize },
+    /// The file ended without an end of file record
+    MissingEndOfFile,
+    /// Data is given twice for the address
+    Overlap(usize),
+    /// The address does not fit the 32 bit addresses of the format, and can not be written
+    AddressOutOfRange(usize),
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HexError::InvalidStartCode { line } => write!(f, "Line {}: Record does not start with ':'", line),
+            HexError::InvalidHexDigit { line } => write!(f, "Line {}: Invalid hex digits in record", line),
+            HexError::InvalidLength { line } => write!(f, "Line {}: Byte count does not match record length", line),
+            HexError::ChecksumMismatch { line, expected, actual } => write!(
+                f,
+                "Line {}: Checksum mismatch, record has 0x{:02X} but calculated 0x{:02X}",
+                line, actual, expected
+            ),
+            HexError::UnsupportedRecordType { line, record_type } => {
+                write!(f, "Line {}: Unsupported record type 0x{:02X}", line, record_type)
+            }
+            HexError::InvalidRecord { line } => write!(f, "Line {}: Invalid length for record type", line),
+            HexError::MissingEndOfFile => write!(f, "File ended without an end of file record"),
+            HexError::Overlap(address) => write!(f, "Data is given twice for address 0x{:08X}", address),
+            HexError::AddressOutOfRange(address) => write!(f, "Address 0x{:08X} does not fit in 32 bits", address),
+        }
+    }
+}
+
+impl From<FirmwareError> for HexError {
+    fn from(e: FirmwareError) -> Self {
+        match e {
+            FirmwareError::Overlap(address) => HexError::Overlap(address),
+        }
+    }
+}
+
+/// Parses the content of a HEX file
+/// # Arguments
+/// * `text` - The file content
+///
+/// # Return
+/// The firmware image, with the entry point given by a start segment or start linear address record
+pub fn parse(text: &str) -> Result<FirmwareImage, HexError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut entry_point: Option<usize> = None;
+    let mut base_address: usize = 0;
+
+    for (line_index, line_text) in text.lines().enumerate() {
+        let line = line_index + 1;
+        let record = line_text.trim();
+
+        // Allow blank lines
+        if record.is_empty() {
+            continue;
+        }
+
+        let bytes = decode_record(line, record)?;
+        let record_type = bytes[3];
+        let offset = usize::from(u16::from_be_bytes([bytes[1], bytes[2]]));
+        let data = &bytes[4..bytes.len() - 1];
+
+        match record_type {
+            RECORD_DATA => {
+                let address = base_address + offset;
+
+                // Extend the current segment if the data follows directly
+                match segments.last_mut() {
+                    Some(last) if last.end_address() == address => last.data.extend_from_slice(data),
+                    _ => segments.push(Segment::new(address, data.to_vec())),
+                }
+            }
+            RECORD_END_OF_FILE => {
+                let mut image = FirmwareImage::from_segments(segments)?;
+                image.entry_point = entry_point;
+                return Ok(image);
+            }
+            RECORD_EXTENDED_SEGMENT_ADDRESS => {
+                base_address = usize::from(read_u16(line, data)?) << 4;
+            }
+            RECORD_EXTENDED_LINEAR_ADDRESS => {
+                base_address = usize::from(read_u16(line, data)?) << 16;
+            }
+            RECORD_START_SEGMENT_ADDRESS => {
+                let value = read_u32(line, data)?;
+                // CS:IP gives the linear address CS * 16 + IP
+                entry_point = Some((((value >> 16) << 4) + (value & 0xFFFF)) as usize);
+            }
+            RECORD_START_LINEAR_ADDRESS => {
+                entry_point = Some(read_u32(line, data)? as usize);
+            }
+            _ => return Err(HexError::UnsupportedRecordType { line, record_type }),
+        }
+    }
+
+    Err(HexError::MissingEndOfFile)
+}
+
+/// Writes the image as a HEX file, using extended linear address records
+/// # Arguments
+/// * `image` - The image to write. The entry point is written as a start linear address record
+///
+/// # Return
+/// The file content, or an error if the data or entry point is above the 32 bit address range
+pub fn write(image: &FirmwareImage) -> Result<String, HexError> {
+    if let Some(end) = image.end_address().filter(|end| *end > MAX_ADDRESS + 1) {
+        let first = image.start_address().unwrap_or(end).max(MAX_ADDRESS + 1);
+        return Err(HexError::AddressOutOfRange(first));
+    }
+    if let Some(entry) = image.entry_point.filter(|entry| *entry > MAX_ADDRESS) {
+        return Err(HexError::AddressOutOfRange(entry));
+    }
+
+    let mut out = String::new();
+    let mut base_address: Option<usize> = None;
+
+    for segment in image.segments() {
+        let mut address = segment.address;
+        let mut remain = &segment.data[..];
+
+        while !remain.is_empty() {
+            // Records can not cross a 64K boundary
+            let upper = address >> 16;
+            if base_address != Some(upper) {
+                write_record(&mut out, 0, RECORD_EXTENDED_LINEAR_ADDRESS, &(upper as u16).to_be_bytes());
+                base_address = Some(upper);
+            }
+
+            let length = remain.len().min(WRITE_RECORD_SIZE).min(0x10000 - (address & 0xFFFF));
+            write_record(&mut out, address as u16, RECORD_DATA, &remain[..length]);
+            address += length;
+            remain = &remain[length..];
+        }
+    }
+
+    if let Some(entry) = image.entry_point {
+        write_record(&mut out, 0, RECORD_START_LINEAR_ADDRESS, &(entry as u32).to_be_bytes());
+    }
+    write_record(&mut out, 0, RECORD_END_OF_FILE, &[]);
+    Ok(out)
+}
+
+/// Appends a record, with the byte count and checksum, to the output
+fn write_record(out: &mut String, offset: u16, record_type: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum_of(&bytes));
+
+    out.push(':');
+    for byte in bytes {
+        let _ = write!(out, "{:02X}", byte);
+    }
+    out.push('\n');
+}
+
+/// Decodes a record to bytes, verifying the length and checksum
+fn decode_record(line: usize, record: &str) -> Result<Vec<u8>, HexError> {
+    if !record.starts_with(':') {
+        return Err(HexError::InvalidStartCode { line });
+    }
+
+    let bytes = decode_hex(&record[1..]).ok_or(HexError::InvalidHexDigit { line })?;
+
+    // Byte count, address (2), type and checksum
+    if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+        return Err(HexError::InvalidLength { line });
+    }
+
+    // The sum of all bytes, including the checksum, must be zero
+    let (content, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = checksum_of(content);
+    if expected != checksum[0] {
+        return Err(HexError::ChecksumMismatch {
+            line,
+            expected,
+            actual: checksum[0],
+        });
+    }
+
+    Ok(bytes)
+}
+
+/// Calculates the two's complement checksum of the given bytes
+pub fn checksum_of(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+/// Decodes a string of hex digit pairs into bytes
+pub(crate) fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Reads the big endian 16 bit value of an address record
+fn read_u16(line: usize, data: &[u8]) -> Result<u16, HexError> {
+    if data.len() != 2 {
+        return Err(HexError::InvalidRecord { line });
+    }
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// Reads the big endian 32 bit value of a start address record
+fn read_u32(line: usize, data: &[u8]) -> Result<u32, HexError> {
+    if data.len() != 4 {
+        return Err(HexError::InvalidRecord { line });
+    }
+    Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_linear_addresses() {
+        let text = "\
+:020000040800F2
+:10000000000002200D0100080F0100081101000886
+:04001000AABBCCDDDE
+:020000040801F1
+:02FFFE001234BB
+:0400000508000131BD
+:00000001FF
+";
+        let image = parse(text).unwrap();
+
+        assert_eq!(2, image.segments().len());
+        assert_eq!(0x0800_0000, image.segments()[0].address);
+        assert_eq!(20, image.segments()[0].data.len());
+        assert_eq!(&[0xAA, 0xBB, 0xCC, 0xDD], &image.segments()[0].data[16..]);
+        assert_eq!(Segment::new(0x0801_FFFE, vec![0x12, 0x34]), image.segments()[1]);
+        assert_eq!(Some(0x0800_0131), image.entry_point);
+    }
+
+    #[test]
+    fn test_parse_segment_addresses() {
+        let text = ":020000021000EC\r\n:0300300002337A1E\r\n:0400000312345678E5\r\n:00000001FF\r\n";
+        let image = parse(text).unwrap();
+
+        assert_eq!(&[Segment::new(0x10030, vec![0x02, 0x33, 0x7A])], image.segments());
+        assert_eq!(Some(0x1234 * 16 + 0x5678), image.entry_point);
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        assert_eq!(
+            Err(HexError::ChecksumMismatch {
+                line: 2,
+                expected: 0x1E,
+                actual: 0x1F
+            }),
+            parse(":020000021000EC\n:0300300002337A1F\n:00000001FF\n")
+        );
+        assert_eq!(
+            Err(HexError::InvalidStartCode { line: 1 }),
+            parse("0300300002337A1E\n")
+        );
+        assert_eq!(
+            Err(HexError::InvalidLength { line: 1 }),
+            parse(":0400300002337A1D\n")
+        );
+        assert_eq!(
+            Err(HexError::UnsupportedRecordType {
+                line: 1,
+                record_type: 0x06
+            }),
+            parse(":00000006FA\n")
+        );
+        assert_eq!(Err(HexError::MissingEndOfFile), parse(":0300300002337A1E\n"));
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let mut image = FirmwareImage::from_segments(vec![
+            Segment::new(0x0800_FFF8, (0u8..0x20).collect()),
+            Segment::new(0x2000_0000, vec![0xAB; 3]),
+        ])
+        .unwrap();
+        image.entry_point = Some(0x0800_0131);
+
+        let text = write(&image).unwrap();
+        assert!(text.starts_with(":020000040800F2\n"));
+        assert!(text.ends_with(":0400000508000131BD\n:00000001FF\n"));
+        assert_eq!(image, parse(&text).unwrap());
+
+        // Data and entry points above 32 bits are rejected instead of truncated
+        image.entry_point = Some(0x1_0000_0000);
+        assert_eq!(Err(HexError::AddressOutOfRange(0x1_0000_0000)), write(&image));
+        let image = FirmwareImage::from_segments(vec![Segment::new(0xFFFF_FFF0, vec![0; 0x20])]).unwrap();
+        assert_eq!(Err(HexError::AddressOutOfRange(0x1_0000_0000)), write(&image));
+    }
+}
@@ -0,0 +1,118 @@
+//! Loading of firmware images from the supported file formats.
+
+use suffix::DfuSuffix;
+
+pub mod bin;
+pub mod dfuse;
+pub mod elf;
+pub mod firmware;
+pub mod ihex;
+pub mod srec;
+pub mod suffix;
+pub mod uf2;
+
+/// Enumeration defining the supported image formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Elf(Option<usize>),
+    Hex(Option<usize>),
+    Dfu(Option<usize>),
+    Bin(Option<usize>),
+    Srec(Option<usize>),
+    Uf2(Option<usize>)
+}
+
+/// A continuous block of data, located at the given address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The address of the first byte
+    pub address: usize,
+    /// The segment data
+    pub data: Vec<u8>,
+}
+
+impl ImageFormat {
+    /// Returns the name of the format, as used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Elf(_) => "elf",
+            ImageFormat::Hex(_) => "hex",
+            ImageFormat::Dfu(_) => "dfu",
+            ImageFormat::Bin(_) => "bin",
+            ImageFormat::Srec(_) => "srec",
+            ImageFormat::Uf2(_) => "uf2",
+        }
+    }
+
+    /// Detects the format from the content of the file. Raw binaries have nothing to detect,
+    /// so they give no format.
+    /// # Arguments
+    /// * `data` - The file content
+    /// * `offset` - Optional offset which is returned with the format
+    pub fn detect(data: &[u8], offset: Option<usize>) -> Option<ImageFormat> {
+        // The binary formats have magic numbers. DfuSe files also carry the DFU suffix,
+        // so check the prefix first.
+        if elf::is_elf(data) {
+            return Some(ImageFormat::Elf(offset));
+        }
+        if uf2::is_uf2(data) {
+            return Some(ImageFormat::Uf2(offset));
+        }
+        if dfuse::is_dfuse(data) || DfuSuffix::is_present(data) {
+            return Some(ImageFormat::Dfu(offset));
+        }
+
+        // The text formats are recognized by the first record
+        let text = String::from_utf8_lossy(data);
+        let first_line = text.lines().map(str::trim).find(|l| !l.is_empty())?;
+        let is_hex_digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        if let Some(record) = first_line.strip_prefix(':') {
+            if is_hex_digits(record) {
+                return Some(ImageFormat::Hex(offset));
+            }
+        }
+        if let Some(record) = first_line.strip_prefix('S') {
+            if record.starts_with(|c: char| c.is_ascii_digit()) && is_hex_digits(record) {
+                return Some(ImageFormat::Srec(offset));
+            }
+        }
+        None
+    }
+}
+
+impl Segment {
+    /// Creates a new segment
+    pub fn new(address: usize, data: Vec<u8>) -> Self {
+        Segment { address, data }
+    }
+
+    /// Returns the address following the last byte of the segment
+    pub fn end_address(&self) -> usize {
+        self.address + self.data.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(Some(ImageFormat::Elf(None)), ImageFormat::detect(&[0x7F, b'E', b'L', b'F', 1, 1], None));
+        assert_eq!(Some(ImageFormat::Dfu(None)), ImageFormat::detect(b"DfuSe\x01\x00\x00\x00\x00\x00", None));
+        assert_eq!(
+            Some(ImageFormat::Hex(Some(0x100))),
+            ImageFormat::detect(b"\r\n:020000040800F2\r\n:00000001FF\r\n", Some(0x100))
+        );
+        assert_eq!(Some(ImageFormat::Srec(None)), ImageFormat::detect(b"S00600004844521B\n", None));
+
+        let mut plain_dfu = vec![0x20, 0x00, 0x02, 0x20];
+        DfuSuffix::append(&mut plain_dfu, 0x0483, 0xDF11, 0xFFFF, 0x0100);
+        assert_eq!(Some(ImageFormat::Dfu(None)), ImageFormat::detect(&plain_dfu, None));
+
+        // Raw binaries, and text which is not a record
+        assert_eq!(None, ImageFormat::detect(&[0x00, 0x20, 0x00, 0x20, 0x0D, 0x01, 0x00, 0x08], None));
+        assert_eq!(None, ImageFormat::detect(b"Something else", None));
+        assert_eq!(None, ImageFormat::detect(b":not hex", None));
+    }
+}
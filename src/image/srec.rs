@@ -0,0 +1,342 @@
+//! Motorola S-record (SREC) file parser and writer.
+//!
+//! Each line in the file is a record on the format `STLLAAAA<data>CC`, where T is the record
+//! type, LL the number of bytes following (address, data and checksum), AAAA the address of
+//! 2, 3 or 4 bytes depending on the type, and CC the one's complement of the sum of all the
+//! bytes from LL up to the checksum. The supported record types are:
+//! - S0: Header, with the data holding a text such as the module name
+//! - S1, S2, S3: Data with a 16, 24 or 32 bit address
+//! - S5, S6: Number of data records so far, as a 16 or 24 bit value
+//! - S7, S8, S9: Start address of 32, 24 or 16 bits, ending the file
+//!
+//! A file without a start address record is accepted, as some tools leave it out.
+
+use core::fmt;
+use core::fmt::Write;
+
+use super::firmware::{FirmwareError, FirmwareImage};
+use super::ihex::decode_hex;
+use super::Segment;
+
+/// Number of data bytes per record when writing
+const WRITE_RECORD_SIZE: usize = 16;
+
+/// Number of header bytes fitting the S0 record, after its 16 bit address and checksum
+const MAX_HEADER_SIZE: usize = 0xFF - 3;
+
+/// Errors that can occur when parsing a SREC file. The line numbers start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SrecError {
+    /// The line does not start with 'S'
+    InvalidStartCode { line: usize },
+    /// The line contains characters which are not hex digits, or an odd number of them
+    InvalidHexDigit { line: usize },
+    /// The byte count does not match the length of the line, or is too short for the address
+    InvalidLength { line: usize },
+    /// The checksum does not match the record
+    ChecksumMismatch { line: usize, expected: u8, actual: u8 },
+    /// The record type is not known
+    UnsupportedRecordType { line: usize, record_type: char },
+    /// The count record does not match the number of data records
+    CountMismatch { line: usize, expected: usize, actual: usize },
+    /// Data is given twice for the address
+    Overlap(usize),
+}
+
+/// The content of a SREC file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrecFile {
+    /// The text of the header record, if any
+    pub header: Option<String>,
+    /// The firmware, with the entry point given by the start address record
+    pub image: FirmwareImage,
+}
+
+impl fmt::Display for SrecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SrecError::InvalidStartCode { line } => write!(f, "Line {}: Record does not start with 'S'", line),
+            SrecError::InvalidHexDigit { line } => write!(f, "Line {}: Invalid hex digits in record", line),
+            SrecError::InvalidLength { line } => write!(f, "Line {}: Byte count does not match record length", line),
+            SrecError::ChecksumMismatch { line, expected, actual } => write!(
+                f,
+                "Line {}: Checksum mismatch, record has 0x{:02X} but calculated 0x{:02X}",
+                line, actual, expected
+            ),
+            SrecError::UnsupportedRecordType { line, record_type } => {
+                write!(f, "Line {}: Unsupported record type S{}", line, record_type)
+            }
+            SrecError::CountMismatch { line, expected, actual } => write!(
+                f,
+                "Line {}: Record count is {}, but {} data records were found",
+                line, expected, actual
+            ),
+            SrecError::Overlap(address) => write!(f, "Data is given twice for address 0x{:08X}", address),
+        }
+    }
+}
+
+impl From<FirmwareError> for SrecError {
+    fn from(e: FirmwareError) -> Self {
+        match e {
+            FirmwareError::Overlap(address) => SrecError::Overlap(address),
+        }
+    }
+}
+
+/// Parses the content of a SREC file
+/// # Arguments
+/// * `text` - The file content
+///
+/// # Return
+/// The header text and the firmware image of the file
+pub fn parse(text: &str) -> Result<SrecFile, SrecError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut header: Option<String> = None;
+    let mut entry_point: Option<usize> = None;
+    let mut data_records: usize = 0;
+
+    for (line_index, line_text) in text.lines().enumerate() {
+        let line = line_index + 1;
+        let record = line_text.trim();
+
+        // Allow blank lines
+        if record.is_empty() {
+            continue;
+        }
+
+        let (record_type, bytes) = decode_record(line, record)?;
+        let (address, data) = split_address(line, record_type, &bytes[1..bytes.len() - 1])?;
+
+        match record_type {
+            '0' => {
+                let text = String::from_utf8_lossy(data);
+                header = Some(text.trim_end_matches('\0').to_string());
+            }
+            '1' | '2' | '3' => {
+                data_records += 1;
+
+                // Extend the current segment if the data follows directly
+                match segments.last_mut() {
+                    Some(last) if last.end_address() == address => last.data.extend_from_slice(data),
+                    _ => segments.push(Segment::new(address, data.to_vec())),
+                }
+            }
+            '5' | '6' => {
+                if address != data_records {
+                    return Err(SrecError::CountMismatch {
+                        line,
+                        expected: address,
+                        actual: data_records,
+                    });
+                }
+            }
+            _ => {
+                // One of the start address records, which ends the file
+                entry_point = Some(address);
+                break;
+            }
+        }
+    }
+
+    let mut image = FirmwareImage::from_segments(segments)?;
+    image.entry_point = entry_point;
+    Ok(SrecFile { header, image })
+}
+
+/// Writes the image as a SREC file. The smallest address size fitting the whole image is used.
+/// # Arguments
+/// * `image` - The image to write. The entry point is written in the start address record
+/// * `header` - The text of the header record, if any. Text longer than the 252 bytes a
+///   record holds is cut off
+///
+/// # Return
+/// The file content
+pub fn write(image: &FirmwareImage, header: Option<&str>) -> String {
+    let end = image.end_address().unwrap_or(0).max(image.entry_point.unwrap_or(0) + 1);
+    let (data_type, start_type) = if end <= 0x1_0000 {
+        ('1', '9')
+    } else if end <= 0x100_0000 {
+        ('2', '8')
+    } else {
+        ('3', '7')
+    };
+
+    let mut out = String::new();
+    if let Some(text) = header {
+        let text = text.as_bytes();
+        write_record(&mut out, '0', 0, &text[..text.len().min(MAX_HEADER_SIZE)]);
+    }
+
+    let mut data_records: usize = 0;
+    for segment in image.segments() {
+        for (index, chunk) in segment.data.chunks(WRITE_RECORD_SIZE).enumerate() {
+            write_record(&mut out, data_type, segment.address + index * WRITE_RECORD_SIZE, chunk);
+            data_records += 1;
+        }
+    }
+
+    // The count record is optional, and only written when the count fits
+    if data_records <= 0xFFFF {
+        write_record(&mut out, '5', data_records, &[]);
+    } else if data_records <= 0xFF_FFFF {
+        write_record(&mut out, '6', data_records, &[]);
+    }
+
+    write_record(&mut out, start_type, image.entry_point.unwrap_or(0), &[]);
+    out
+}
+
+/// Appends a record, with the byte count and checksum, to the output
+fn write_record(out: &mut String, record_type: char, address: usize, data: &[u8]) {
+    let address_size = address_size(record_type);
+    let mut bytes = vec![(address_size + data.len() + 1) as u8];
+    bytes.extend_from_slice(&(address as u32).to_be_bytes()[4 - address_size..]);
+    bytes.extend_from_slice(data);
+    bytes.push(checksum_of(&bytes));
+
+    out.push('S');
+    out.push(record_type);
+    for byte in bytes {
+        let _ = write!(out, "{:02X}", byte);
+    }
+    out.push('\n');
+}
+
+/// Decodes a record to its type and bytes, verifying the length and checksum
+fn decode_record(line: usize, record: &str) -> Result<(char, Vec<u8>), SrecError> {
+    let mut chars = record.chars();
+    if chars.next() != Some('S') {
+        return Err(SrecError::InvalidStartCode { line });
+    }
+
+    let record_type = chars.next().ok_or(SrecError::InvalidLength { line })?;
+    if !matches!(record_type, '0'..='3' | '5'..='9') {
+        return Err(SrecError::UnsupportedRecordType { line, record_type });
+    }
+
+    let bytes = decode_hex(chars.as_str()).ok_or(SrecError::InvalidHexDigit { line })?;
+
+    // Byte count and checksum, and the byte count covers everything after itself
+    if bytes.len() < 2 || bytes.len() != usize::from(bytes[0]) + 1 {
+        return Err(SrecError::InvalidLength { line });
+    }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - 1);
+    let expected = checksum_of(content);
+    if expected != checksum[0] {
+        return Err(SrecError::ChecksumMismatch {
+            line,
+            expected,
+            actual: checksum[0],
+        });
+    }
+
+    Ok((record_type, bytes))
+}
+
+/// Splits the address and the data of a record, using the address size of the record type
+fn split_address(line: usize, record_type: char, bytes: &[u8]) -> Result<(usize, &[u8]), SrecError> {
+    let address_size = address_size(record_type);
+    if bytes.len() < address_size {
+        return Err(SrecError::InvalidLength { line });
+    }
+
+    let (address, data) = bytes.split_at(address_size);
+    let address = address.iter().fold(0usize, |value, b| (value << 8) | usize::from(*b));
+    Ok((address, data))
+}
+
+/// Returns the number of address bytes of the record type
+fn address_size(record_type: char) -> usize {
+    match record_type {
+        '0' | '1' | '5' | '9' => 2,
+        '2' | '6' | '8' => 3,
+        _ => 4,
+    }
+}
+
+/// Calculates the one's complement checksum of the given bytes
+pub fn checksum_of(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_all_address_sizes() {
+        let text = "\
+S00F000068656C6C6F202020202000003C
+S1130000285F245F2212226A000424290008237C2A
+S2070100007A55AA7E
+S30A080000000001020304E3
+S5030003F9
+S70508000000F2
+";
+        let file = parse(text).unwrap();
+
+        assert_eq!(Some("hello     ".to_string()), file.header);
+        let segments = file.image.segments();
+        assert_eq!(3, segments.len());
+        assert_eq!(0x0000, segments[0].address);
+        assert_eq!(16, segments[0].data.len());
+        assert_eq!(Segment::new(0x01_0000, vec![0x7A, 0x55, 0xAA]), segments[1]);
+        assert_eq!(Segment::new(0x0800_0000, vec![0x00, 0x01, 0x02, 0x03, 0x04]), segments[2]);
+        assert_eq!(Some(0x0800_0000), file.image.entry_point);
+    }
+
+    #[test]
+    fn test_parse_errors_report_line() {
+        assert_eq!(
+            Err(SrecError::ChecksumMismatch {
+                line: 2,
+                expected: 0xF2,
+                actual: 0xF3
+            }),
+            parse("S00600004844521B\nS70508000000F3\n")
+        );
+        assert_eq!(Err(SrecError::InvalidStartCode { line: 1 }), parse(":00000001FF\n"));
+        assert_eq!(Err(SrecError::InvalidLength { line: 1 }), parse("S1050000AA\n"));
+        assert_eq!(
+            Err(SrecError::UnsupportedRecordType { line: 1, record_type: '4' }),
+            parse("S4030000FC\n")
+        );
+        assert_eq!(
+            Err(SrecError::CountMismatch {
+                line: 2,
+                expected: 2,
+                actual: 1
+            }),
+            parse("S1040000AA51\nS5030002FA\n")
+        );
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let mut image = FirmwareImage::from_segments(vec![
+            Segment::new(0x0800_0000, (0u8..0x28).collect()),
+            Segment::new(0x0801_0000, vec![0xAB; 3]),
+        ])
+        .unwrap();
+        image.entry_point = Some(0x0800_0000);
+
+        let text = write(&image, Some("HDR"));
+        assert!(text.starts_with("S00600004844521B\n"));
+        assert!(text.ends_with("S5030004F8\nS70508000000F2\n"));
+
+        let file = parse(&text).unwrap();
+        assert_eq!(Some("HDR".to_string()), file.header);
+        assert_eq!(image, file.image);
+
+        // Small images use 16 bit addresses
+        let image = FirmwareImage::from_segments(vec![Segment::new(0x100, vec![0x55])]).unwrap();
+        assert_eq!("S104010055A5\nS5030001FB\nS9030000FC\n", write(&image, None));
+
+        // The header is cut to fit the byte count of its record
+        let header = "H".repeat(300);
+        let file = parse(&write(&image, Some(&header))).unwrap();
+        assert_eq!(Some(header[..252].to_string()), file.header);
+    }
+}
@@ -0,0 +1,182 @@
+//! The DFU file suffix, as defined by the DFU 1.1 specification.
+//!
+//! The suffix is 16 bytes appended to the firmware, stored in reverse order, so reading
+//! the file backwards gives: dwCRC, bLength, ucDfuSignature ("DFU"), bcdDFU, idVendor,
+//! idProduct and bcdDevice. The CRC covers the complete file except the CRC itself.
+
+use core::fmt;
+
+use crate::util::crc::dfu_crc32;
+
+/// The length of the suffix
+pub const SUFFIX_LENGTH: usize = 16;
+
+/// The signature as stored in the file
+const SUFFIX_SIGNATURE: [u8; 3] = [b'U', b'F', b'D'];
+
+/// Errors that can occur when reading the suffix
+#[derive(Debug, Clone, PartialEq)]
+pub enum SuffixError {
+    /// The file is shorter than the suffix
+    TooShort,
+    /// The "UFD" signature is missing
+    InvalidSignature,
+    /// The bLength field is not valid
+    InvalidLength(u8),
+    /// The CRC of the file does not match the one in the suffix
+    CrcMismatch { expected: u32, actual: u32 },
+}
+
+/// The content of the DFU suffix. Vendor, product and device values of 0xFFFF
+/// means the file is valid for any device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DfuSuffix {
+    /// bcdDevice, the release number of the device
+    pub device: u16,
+    /// idProduct
+    pub product_id: u16,
+    /// idVendor
+    pub vendor_id: u16,
+    /// bcdDFU, 0x0100 for plain DFU, 0x011A for DfuSe
+    pub dfu_version: u16,
+    /// dwCRC
+    pub crc: u32,
+}
+
+impl fmt::Display for SuffixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SuffixError::TooShort => write!(f, "File is too short to contain a DFU suffix"),
+            SuffixError::InvalidSignature => write!(f, "DFU suffix signature is missing"),
+            SuffixError::InvalidLength(length) => write!(f, "Invalid DFU suffix length [{}]", length),
+            SuffixError::CrcMismatch { expected, actual } => write!(
+                f,
+                "DFU suffix CRC mismatch, suffix has 0x{:08X} but calculated 0x{:08X}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl DfuSuffix {
+    /// Checks if the file ends with something that looks like a DFU suffix
+    pub fn is_present(data: &[u8]) -> bool {
+        data.len() >= SUFFIX_LENGTH && data[data.len() - 8..data.len() - 5] == SUFFIX_SIGNATURE
+    }
+
+    /// Reads and verifies the suffix at the end of the file
+    /// # Arguments
+    /// * `data` - The complete file content
+    ///
+    /// # Return
+    /// The suffix, and the file content without the suffix
+    pub fn parse(data: &[u8]) -> Result<(DfuSuffix, &[u8]), SuffixError> {
+        if data.len() < SUFFIX_LENGTH {
+            return Err(SuffixError::TooShort);
+        }
+
+        let (payload, suffix) = data.split_at(data.len() - SUFFIX_LENGTH);
+        if suffix[8..11] != SUFFIX_SIGNATURE {
+            return Err(SuffixError::InvalidSignature);
+        }
+        if suffix[11] as usize != SUFFIX_LENGTH {
+            return Err(SuffixError::InvalidLength(suffix[11]));
+        }
+
+        let read_u16 = |offset: usize| u16::from_le_bytes([suffix[offset], suffix[offset + 1]]);
+        let crc = u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]);
+
+        // The CRC covers everything but the CRC field itself
+        let expected = dfu_crc32(&data[..data.len() - 4]);
+        if expected != crc {
+            return Err(SuffixError::CrcMismatch { expected, actual: crc });
+        }
+
+        Ok((
+            DfuSuffix {
+                device: read_u16(0),
+                product_id: read_u16(2),
+                vendor_id: read_u16(4),
+                dfu_version: read_u16(6),
+                crc,
+            },
+            payload,
+        ))
+    }
+
+    /// Checks if the file is meant for the device with the given IDs.
+    /// An ID of 0xFFFF in the suffix matches any device.
+    pub fn matches(&self, vendor_id: u16, product_id: u16) -> bool {
+        (self.vendor_id == 0xFFFF || self.vendor_id == vendor_id)
+            && (self.product_id == 0xFFFF || self.product_id == product_id)
+    }
+
+    /// Appends a suffix with the given IDs to the data, calculating the CRC
+    pub fn append(data: &mut Vec<u8>, vendor_id: u16, product_id: u16, device: u16, dfu_version: u16) {
+        data.extend_from_slice(&device.to_le_bytes());
+        data.extend_from_slice(&product_id.to_le_bytes());
+        data.extend_from_slice(&vendor_id.to_le_bytes());
+        data.extend_from_slice(&dfu_version.to_le_bytes());
+        data.extend_from_slice(&SUFFIX_SIGNATURE);
+        data.push(SUFFIX_LENGTH as u8);
+
+        let crc = dfu_crc32(data);
+        data.extend_from_slice(&crc.to_le_bytes());
+    }
+}
+
+impl fmt::Display for DfuSuffix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ID [{:04x}:{:04x}], Device [{:04X}], DFU [{:04X}], CRC [0x{:08X}]",
+            self.vendor_id, self.product_id, self.device, self.dfu_version, self.crc
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suffix_round_trip() {
+        let mut data = vec![1, 2, 3, 4, 5];
+        DfuSuffix::append(&mut data, 0x0483, 0xDF11, 0x2200, 0x0100);
+        assert_eq!(5 + SUFFIX_LENGTH, data.len());
+        assert!(DfuSuffix::is_present(&data));
+
+        let (suffix, payload) = DfuSuffix::parse(&data).unwrap();
+        assert_eq!(&[1, 2, 3, 4, 5], payload);
+        assert_eq!(0x0483, suffix.vendor_id);
+        assert_eq!(0xDF11, suffix.product_id);
+        assert_eq!(0x2200, suffix.device);
+        assert_eq!(0x0100, suffix.dfu_version);
+
+        assert!(suffix.matches(0x0483, 0xDF11));
+        assert!(!suffix.matches(0x0483, 0xDF12));
+        assert!(!suffix.matches(0x1209, 0xDF11));
+    }
+
+    #[test]
+    fn test_suffix_wildcard_ids() {
+        let mut data = vec![0xAA; 64];
+        DfuSuffix::append(&mut data, 0xFFFF, 0xFFFF, 0xFFFF, 0x0100);
+        let (suffix, _) = DfuSuffix::parse(&data).unwrap();
+
+        assert!(suffix.matches(0x1209, 0x0001));
+    }
+
+    #[test]
+    fn test_suffix_errors() {
+        let mut data = vec![0u8; 32];
+        assert!(!DfuSuffix::is_present(&data));
+        assert_eq!(Err(SuffixError::InvalidSignature), DfuSuffix::parse(&data));
+        assert_eq!(Err(SuffixError::TooShort), DfuSuffix::parse(&data[..8]));
+
+        data.truncate(4);
+        DfuSuffix::append(&mut data, 0x1234, 0x5678, 0xFFFF, 0x0100);
+        data[0] = 0xFF;
+        assert!(matches!(DfuSuffix::parse(&data), Err(SuffixError::CrcMismatch { .. })));
+    }
+}
@@ -0,0 +1,205 @@
+//! UF2 file reader.
+//!
+//! A UF2 file is a sequence of 512 byte blocks, each carrying up to 476 bytes of payload
+//! for the given target address. All values are little endian. The block layout is:
+//! - 0: First magic number, 0x0A324655 ("UF2\n")
+//! - 4: Second magic number, 0x9E5D5157
+//! - 8: Flags
+//! - 12: Target address of the payload
+//! - 16: Payload size
+//! - 20: Sequential block number, and 24: Total number of blocks
+//! - 28: File size, or the family ID when the family ID flag is set
+//! - 32: Payload data, padded to 476 bytes
+//! - 508: Final magic number, 0x0AB16F30
+//!
+//! Blocks flagged "not main flash" are skipped. When filtering on a family ID, blocks
+//! carrying a different family ID are skipped, while blocks without a family ID are kept.
+
+use core::fmt;
+
+use super::firmware::{FirmwareError, FirmwareImage};
+use super::Segment;
+
+/// The size of every block
+pub const BLOCK_SIZE: usize = 512;
+
+/// The maximum payload of a block
+const MAX_PAYLOAD_SIZE: usize = 476;
+
+/// Magic numbers
+const MAGIC_START_0: u32 = 0x0A32_4655;
+const MAGIC_START_1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// Block flags
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+
+/// Errors that can occur when reading a UF2 file. The block numbers start at 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Uf2Error {
+    /// The file size is not a multiple of the block size
+    InvalidLength(usize),
+    /// One of the magic numbers of the block is wrong
+    InvalidMagic { block: usize },
+    /// The payload size of the block is larger than the block can hold
+    InvalidPayloadSize { block: usize, size: usize },
+    /// No block holds data for main flash of the requested family
+    NoData,
+    /// Data is given twice for the address
+    Overlap(usize),
+}
+
+/// The content of a UF2 file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uf2File {
+    /// The family IDs found in the file, in order of appearance
+    pub families: Vec<u32>,
+    /// The main flash data of the file
+    pub image: FirmwareImage,
+}
+
+impl fmt::Display for Uf2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Uf2Error::InvalidLength(length) => {
+                write!(f, "File size [{}] is not a multiple of the {} byte block size", length, BLOCK_SIZE)
+            }
+            Uf2Error::InvalidMagic { block } => write!(f, "Block {}: Invalid magic number", block),
+            Uf2Error::InvalidPayloadSize { block, size } => write!(f, "Block {}: Invalid payload size [{}]", block, size),
+            Uf2Error::NoData => write!(f, "No block holds main flash data for the requested family"),
+            Uf2Error::Overlap(address) => write!(f, "Data is given twice for address 0x{:08X}", address),
+        }
+    }
+}
+
+impl From<FirmwareError> for Uf2Error {
+    fn from(e: FirmwareError) -> Self {
+        match e {
+            FirmwareError::Overlap(address) => Uf2Error::Overlap(address),
+        }
+    }
+}
+
+/// Checks if the data starts with the UF2 magic numbers
+pub fn is_uf2(data: &[u8]) -> bool {
+    data.len() >= 8 && read_u32(data, 0) == MAGIC_START_0 && read_u32(data, 4) == MAGIC_START_1
+}
+
+/// Parses the content of a UF2 file
+/// # Arguments
+/// * `data` - The file content
+/// * `family` - Only keep blocks of this family ID, if given
+///
+/// # Return
+/// The family IDs in the file, and the main flash data of the blocks kept
+pub fn parse(data: &[u8], family: Option<u32>) -> Result<Uf2File, Uf2Error> {
+    if data.is_empty() || data.len() % BLOCK_SIZE != 0 {
+        return Err(Uf2Error::InvalidLength(data.len()));
+    }
+
+    let mut families: Vec<u32> = Vec::new();
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (block, block_data) in data.chunks(BLOCK_SIZE).enumerate() {
+        if !is_uf2(block_data) || read_u32(block_data, 508) != MAGIC_END {
+            return Err(Uf2Error::InvalidMagic { block });
+        }
+
+        let flags = read_u32(block_data, 8);
+        let address = read_u32(block_data, 12) as usize;
+        let size = read_u32(block_data, 16) as usize;
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(Uf2Error::InvalidPayloadSize { block, size });
+        }
+
+        // The family ID is only given when flagged, otherwise the field holds the file size
+        if flags & FLAG_FAMILY_ID_PRESENT != 0 {
+            let block_family = read_u32(block_data, 28);
+            if !families.contains(&block_family) {
+                families.push(block_family);
+            }
+            if family.map_or(false, |f| f != block_family) {
+                continue;
+            }
+        }
+
+        if flags & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+
+        // Extend the current segment if the data follows directly
+        let payload = &block_data[32..32 + size];
+        match segments.last_mut() {
+            Some(last) if last.end_address() == address => last.data.extend_from_slice(payload),
+            _ => segments.push(Segment::new(address, payload.to_vec())),
+        }
+    }
+
+    let image = FirmwareImage::from_segments(segments)?;
+    if image.is_empty() {
+        return Err(Uf2Error::NoData);
+    }
+    Ok(Uf2File { families, image })
+}
+
+/// Reads a little endian 32 bit value at the offset
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a single UF2 block
+    fn build_block(flags: u32, address: u32, payload: &[u8], field: u32) -> Vec<u8> {
+        let mut block = Vec::new();
+        for value in &[MAGIC_START_0, MAGIC_START_1, flags, address, payload.len() as u32, 0, 1, field] {
+            block.extend_from_slice(&value.to_le_bytes());
+        }
+        block.extend_from_slice(payload);
+        block.resize(508, 0);
+        block.extend_from_slice(&MAGIC_END.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        let rp2040: u32 = 0xE48B_FF56;
+        let mut data = build_block(FLAG_FAMILY_ID_PRESENT, 0x1000_0000, &[0x11; 256], rp2040);
+        data.extend(build_block(FLAG_FAMILY_ID_PRESENT, 0x1000_0100, &[0x22; 256], rp2040));
+        data.extend(build_block(FLAG_NOT_MAIN_FLASH, 0x2000_0000, &[0x33; 16], 0));
+        data.extend(build_block(FLAG_FAMILY_ID_PRESENT, 0x0800_0000, &[0x44; 16], 0x57755A57));
+        assert!(is_uf2(&data));
+
+        let file = parse(&data, None).unwrap();
+        assert_eq!(vec![rp2040, 0x57755A57], file.families);
+        assert_eq!(2, file.image.segments().len());
+        assert_eq!(0x0800_0000, file.image.segments()[0].address);
+        assert_eq!(0x1000_0000, file.image.segments()[1].address);
+        assert_eq!(0x200, file.image.segments()[1].data.len());
+
+        // Only the RP2040 blocks are kept when filtering
+        let file = parse(&data, Some(rp2040)).unwrap();
+        assert_eq!(1, file.image.segments().len());
+        assert_eq!(0x1000_0000, file.image.segments()[0].address);
+
+        assert_eq!(Err(Uf2Error::NoData), parse(&data, Some(0x1234_5678)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let block = build_block(0, 0x1000_0000, &[0; 16], 0);
+        assert_eq!(Err(Uf2Error::InvalidLength(511)), parse(&block[..511], None));
+
+        let mut data = block.clone();
+        data.extend_from_slice(&block);
+        data[BLOCK_SIZE + 510] = 0;
+        assert_eq!(Err(Uf2Error::InvalidMagic { block: 1 }), parse(&data, None));
+
+        let mut data = block;
+        data[16..20].copy_from_slice(&477u32.to_le_bytes());
+        assert_eq!(Err(Uf2Error::InvalidPayloadSize { block: 0, size: 477 }), parse(&data, None));
+    }
+}
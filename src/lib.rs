@@ -0,0 +1,6 @@
+//! Rust DFU library. Contains the USB/DFU protocol implementation and the
+//! utilities used by the `rdfu` command line tool.
+
+pub mod image;
+pub mod usb;
+pub mod util;
@@ -1,40 +1,48 @@
-mod util;
-
-mod usb;
-
-use std::{path::Path};
+use std::{fs, io, path::Path, process};
+use std::convert::TryFrom;
+use std::io::Write;
 use std::ffi::OsStr;
-use clap::{Arg, App};
-
-use util::{parse, UnwrapOrDie};
+use std::time::Duration;
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+use rusb::{Device, GlobalContext};
 
+use rdfu::image::dfuse::{self, DfuSeTarget};
+use rdfu::image::suffix::DfuSuffix;
+use rdfu::image::firmware::FirmwareImage;
+use rdfu::image::{bin, elf, ihex, srec, uf2, ImageFormat, Segment};
+use rdfu::usb::descriptor::{DfuAttributes, DfuFunctionalDescriptor};
+use rdfu::usb::dfu::{Dfu, DfuError};
+use rdfu::usb::enumerate::{self, DeviceFilter, DfuDevice};
+use rdfu::usb::option_bytes::{Family, OptionBytes, RdpLevel};
+use rdfu::usb::otp::{self, OtpLayout};
+use rdfu::usb::stm32dfu::{parse_memory_layout_string, DfuSeError, Stm32DfuSe, VerifyReport};
+use rdfu::usb::transport::{DfuTransport, RusbTransport, TransportError};
+use rdfu::util::memory::{Accessibility, Block};
+use rdfu::util::{parse, UnwrapOrDie, UnwrapOrReport};
 
 
-/// Enumeration defining the supported image formats
-#[derive(Debug)]
-enum ImageFormat {
-    Elf(Option<usize>),
-    Hex(Option<usize>),
-    Dfu(Option<usize>),
-    Bin(Option<usize>)
-}
-
 // Define version here
 const APP_NAME: &str = "Rust DFU Firmware Uploader";
 const VERSION: &str = "1.0";
 
+// Time allowed for a detached device to show up in DFU mode, in addition to its detach timeout
+const REENUMERATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Time allowed for a device to mass erase its flash and reset after removing the readout protection
+const UNPROTECT_TIMEOUT: Duration = Duration::from_secs(60);
+
 fn main() {
     // Create the CLI Parser
     let appdef = 
         App::new(APP_NAME)
             .version(VERSION)
             .author("Created by: Johnny Egeland (c) 2021")
-            .about("Utility to upload Firmware images to DFU capable hardware. Supports a number of formats: iHEX, ELF, DFU and BIN")
+            .about("Utility to upload Firmware images to DFU capable hardware. Supports a number of formats: iHEX, ELF, DFU, SREC, UF2 and BIN")
             .arg(Arg::with_name("format")
                 .short("f")
                 .long("format")
                 .value_name("FORMAT")
-                .help("Explicitly specify the format (extension is used by default): dfu, hex, elf or bin")
+                .help("Explicitly specify the format (detected from the content and extension by default): dfu, hex, elf, srec, uf2 or bin")
                 .takes_value(true))
             .arg(Arg::with_name("offset")
                 .short("o")
@@ -42,17 +50,255 @@ fn main() {
                 .value_name("OFFSET")
                 .help("Explicitly specify the target offset to apply. For 'bin' files, this is the address to upload to. Use 0x<offset> to specify in hex.")
                 .takes_value(true))
+            .arg(Arg::with_name("family")
+                .long("family")
+                .value_name("FAMILY")
+                .help("Only use the blocks of this family ID from UF2 files. Use 0x<id> to specify in hex.")
+                .takes_value(true))
+            .args(&device_args())
+            .arg(Arg::with_name("verify")
+                .long("verify")
+                .help("Read back the memory after downloading, and compare it with the image. \
+                    Differing bytes are counted per erase block, which is a flash sector on STM32 devices"))
+            .arg(Arg::with_name("preserve")
+                .long("preserve")
+                .help("Read the bytes outside the image in the blocks to erase, and write them back"))
+            .arg(Arg::with_name("diff")
+                .long("diff")
+                .help("Read back the blocks to erase, and only erase and write the ones differing from the image"))
+            .arg(Arg::with_name("unprotect")
+                .long("unprotect")
+                .help("Remove the readout protection of a protected DfuSe device before downloading. This mass erases the flash"))
+            .arg(Arg::with_name("ignore-id")
+                .long("ignore-id")
+                .help("Download DFU files even if the vendor and product ID of the file suffix do not match the device"))
             .arg(Arg::with_name("image")
                 .value_name("IMAGE")
                 .help("The firmware image file to upload via DFU")
                 .required(true)
-                .index(1));
+                .index(1))
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(SubCommand::with_name("list")
+                .about("Lists the DFU capable devices, with the memory layout of each alt setting")
+                .arg(Arg::with_name("json")
+                    .long("json")
+                    .help("Print the list as JSON")))
+            .subcommand(SubCommand::with_name("convert")
+                .about("Converts a firmware image to another format: bin, hex, srec or dfu (DfuSe)")
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Explicitly specify the input format (detected from the content and extension by default)")
+                    .takes_value(true))
+                .arg(Arg::with_name("to")
+                    .short("t")
+                    .long("to")
+                    .value_name("FORMAT")
+                    .help("Explicitly specify the output format (extension is used by default)")
+                    .takes_value(true))
+                .arg(Arg::with_name("offset")
+                    .short("o")
+                    .long("offset")
+                    .value_name("OFFSET")
+                    .help("The address of 'bin' input files. Use 0x<offset> to specify in hex.")
+                    .takes_value(true))
+                .arg(Arg::with_name("family")
+                    .long("family")
+                    .value_name("FAMILY")
+                    .help("Only use the blocks of this family ID from UF2 files")
+                    .takes_value(true))
+                .arg(Arg::with_name("fill")
+                    .long("fill")
+                    .value_name("BYTE")
+                    .help("The byte used to fill gaps in 'bin' output")
+                    .default_value("0xFF")
+                    .takes_value(true))
+                .arg(Arg::with_name("alt")
+                    .short("a")
+                    .long("alt")
+                    .value_name("ALT")
+                    .help("The alt setting of the DfuSe target to write. For DfuSe input, only the target of this alt setting is used")
+                    .takes_value(true))
+                .arg(Arg::with_name("target-name")
+                    .long("target-name")
+                    .value_name("NAME")
+                    .help("The name of the DfuSe target to write")
+                    .takes_value(true))
+                .arg(Arg::with_name("layout")
+                    .long("layout")
+                    .value_name("LAYOUT")
+                    .help("The memory layout of an alt setting, as listed by 'rdfu list', such as \"@Option Bytes /0x1FFFC000/01*016 e\". \
+                           Can be given several times, for alt setting 0, 1 and so on. DfuSe output gets one target per layout holding image data")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .conflicts_with_all(&["alt", "target-name"])
+                    .takes_value(true))
+                .arg(Arg::with_name("vid")
+                    .long("vid")
+                    .value_name("VID")
+                    .help("The vendor ID of the DfuSe file")
+                    .default_value("0x0483")
+                    .takes_value(true))
+                .arg(Arg::with_name("pid")
+                    .long("pid")
+                    .value_name("PID")
+                    .help("The product ID of the DfuSe file")
+                    .default_value("0xDF11")
+                    .takes_value(true))
+                .arg(Arg::with_name("bcd-device")
+                    .long("bcd-device")
+                    .value_name("BCD")
+                    .help("The device release number of the DfuSe file, 0xFFFF for any")
+                    .default_value("0xFFFF")
+                    .takes_value(true))
+                .arg(Arg::with_name("input")
+                    .value_name("INPUT")
+                    .help("The firmware image file to convert")
+                    .required(true)
+                    .index(1))
+                .arg(Arg::with_name("output")
+                    .value_name("OUTPUT")
+                    .help("The file to write")
+                    .required(true)
+                    .index(2)))
+            .subcommand(SubCommand::with_name("read")
+                .about("Reads memory from the device, and writes it as bin, hex or srec. The readable memory of the alt setting is read by default")
+                .args(&device_args())
+                .arg(Arg::with_name("address")
+                    .long("address")
+                    .value_name("ADDRESS")
+                    .help("The address to read from. Use 0x<address> to specify in hex.")
+                    .takes_value(true))
+                .arg(Arg::with_name("length")
+                    .long("length")
+                    .value_name("LENGTH")
+                    .help("The number of bytes to read (to the end of the readable memory by default). Use 0x<length> to specify in hex.")
+                    .takes_value(true))
+                .arg(Arg::with_name("to")
+                    .short("t")
+                    .long("to")
+                    .value_name("FORMAT")
+                    .help("Explicitly specify the output format: bin, hex or srec (extension is used by default)")
+                    .takes_value(true))
+                .arg(Arg::with_name("fill")
+                    .long("fill")
+                    .value_name("BYTE")
+                    .help("The byte used to fill gaps in 'bin' output")
+                    .default_value("0xFF")
+                    .takes_value(true))
+                .arg(Arg::with_name("output")
+                    .value_name("OUTPUT")
+                    .help("The file to write")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("option-bytes")
+                .about("Reads and decodes the STM32 option bytes, and changes fields by name")
+                .args(&device_args())
+                .arg(Arg::with_name("family")
+                    .long("family")
+                    .value_name("FAMILY")
+                    .help("The STM32 family, to decode the fields for: stm32f4 (also STM32F2) or stm32f42x")
+                    .default_value("stm32f4")
+                    .takes_value(true))
+                .arg(Arg::with_name("set")
+                    .long("set")
+                    .value_name("FIELD=VALUE")
+                    .help("Set a field, such as rdp=1, bor=off, wdg_sw=0 or nwrp=0xFFF. Can be given several times")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("otp-write")
+                .about("Programs the STM32 one-time programmable memory, refusing changes which can not be made")
+                .args(&device_args())
+                .arg(Arg::with_name("format")
+                    .short("f")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Explicitly specify the image format (detected from the content and extension by default)")
+                    .takes_value(true))
+                .arg(Arg::with_name("offset")
+                    .short("o")
+                    .long("offset")
+                    .value_name("OFFSET")
+                    .help("The address of 'bin' images (the start of the OTP memory by default). Use 0x<offset> to specify in hex.")
+                    .takes_value(true))
+                .arg(Arg::with_name("lock")
+                    .long("lock")
+                    .help("Allow the image to write lock bytes, which makes the OTP blocks read-only for good"))
+                .arg(Arg::with_name("image")
+                    .value_name("IMAGE")
+                    .help("The data to program")
+                    .required(true)
+                    .index(1)))
+            .subcommand(SubCommand::with_name("erase")
+                .about("Erases the whole memory, a bank or an address range of a DfuSe alt setting")
+                .args(&device_args())
+                .arg(Arg::with_name("all")
+                    .long("all")
+                    .help("Mass erase the device. Refused if other alt settings list erasable memory outside this one"))
+                .arg(Arg::with_name("bank")
+                    .long("bank")
+                    .value_name("BANK")
+                    .help("Erase every block of the bank with this index")
+                    .takes_value(true))
+                .arg(Arg::with_name("address")
+                    .long("address")
+                    .value_name("ADDRESS")
+                    .help("Erase the blocks touched by the range starting here. Use 0x<address> to specify in hex.")
+                    .requires("length")
+                    .takes_value(true))
+                .arg(Arg::with_name("length")
+                    .long("length")
+                    .value_name("LENGTH")
+                    .help("The length of the range to erase. Use 0x<length> to specify in hex.")
+                    .requires("address")
+                    .takes_value(true))
+                .group(ArgGroup::with_name("mode")
+                    .args(&["all", "bank", "address"])
+                    .required(true))
+                .arg(Arg::with_name("yes")
+                    .long("yes")
+                    .help("Erase without asking for confirmation")));
 
     // Get the matches object, and extract parameters needed
     let cli_matches = appdef.get_matches();
 
-    // At this point, start by printing appname and version
-    println!("{} v{}", APP_NAME, VERSION);
+    // At this point, start by printing appname and version. JSON output must not be mixed with other text.
+    let json_output = cli_matches.subcommand_matches("list").map_or(false, |m| m.is_present("json"));
+    if !json_output {
+        println!("{} v{}", APP_NAME, VERSION);
+    }
+
+    if let Some(list_matches) = cli_matches.subcommand_matches("list") {
+        list(list_matches);
+        return;
+    }
+
+    if let Some(convert_matches) = cli_matches.subcommand_matches("convert") {
+        convert(convert_matches);
+        return;
+    }
+
+    if let Some(read_matches) = cli_matches.subcommand_matches("read") {
+        read(read_matches);
+        return;
+    }
+
+    if let Some(option_matches) = cli_matches.subcommand_matches("option-bytes") {
+        option_bytes(option_matches);
+        return;
+    }
+
+    if let Some(otp_matches) = cli_matches.subcommand_matches("otp-write") {
+        otp_write(otp_matches);
+        return;
+    }
+
+    if let Some(erase_matches) = cli_matches.subcommand_matches("erase") {
+        erase(erase_matches);
+        return;
+    }
 
     // Parse the Offset
     let fw_offset = if let Some(offstr) = cli_matches.value_of("offset") {
@@ -60,101 +306,1090 @@ fn main() {
     }
     else { None };
 
+    // Parse the UF2 family filter
+    let fw_family: Option<u32> = number_arg(&cli_matches, "family");
+
     // Get the image filename as a string
     let fw_image_file = cli_matches.value_of("image").unwrap().to_string();
 
-    // Get the format value as string as well
-    let fw_image_type = match cli_matches.value_of("format") {
-        Some(s) => parse_image_type_from_extension(&s.to_string(), fw_offset),
-        None => parse_image_type_from_extension(&get_file_extension(&fw_image_file, "elf"), fw_offset)
-    };
+    // Read the image, and find the format from the content, extension or format parameter
+    let fw_data = fs::read(&fw_image_file).unwrap_or_report(2, "Unable to read the image file");
+    let fw_image_type = select_image_format(&fw_image_file, &fw_data, cli_matches.value_of("format"), fw_offset);
 
     println!("Using image file: {}", fw_image_file);
     println!("Using format: {:?} @ 0x{:08X}", fw_image_type, fw_offset.unwrap_or(0));
 
-    // Some simple USB enumeration here.
-    for device in rusb::devices().unwrap().iter() {
+    // Load the image content
+    let (fw_content, fw_suffix) = load_image(fw_data, &fw_image_type, fw_family);
+
+    // Select the device and alt setting
+    let (mut usb_device, mut selected, alt) = select_device(&cli_matches);
+
+    // Make sure a DFU file is meant for the device
+    if let Some(suffix) = fw_suffix {
+        if !suffix.matches(selected.vendor_id, selected.product_id) && !cli_matches.is_present("ignore-id") {
+            eprintln!("Error: The image is made for device [{:04x}:{:04x}], but the device is [{:04x}:{:04x}]. Use --ignore-id to download anyway",
+                suffix.vendor_id, suffix.product_id, selected.vendor_id, selected.product_id);
+            process::exit(4);
+        }
+    }
 
-        // Get the device descriptor
-        let device_desc_result = device.device_descriptor();
-        if let Err(e) = device_desc_result {
-            println!("Unable to get device descriptor: {}", e);
-            continue;
+    // Open the device
+    let (mut transport, mut func_desc) = open_device(&usb_device, &selected);
+
+    // A protected device rejects the download, unless the protection is removed first
+    if remove_read_protection(&mut transport, &selected, func_desc, alt, cli_matches.is_present("unprotect")) {
+        drop(transport);
+        println!("Waiting for the device to mass erase the flash and reset");
+        let (dfu_usb_device, dfu_device) = enumerate::wait_for_reenumeration(&selected, UNPROTECT_TIMEOUT)
+            .unwrap_or_report(3, "Unable to find the device after removing the readout protection");
+        usb_device = dfu_usb_device;
+        selected = dfu_device;
+        println!("Using device: {}", selected);
+
+        let reopened = open_device(&usb_device, &selected);
+        transport = reopened.0;
+        func_desc = reopened.1;
+    }
+    let alt_strings = alt_strings(&selected);
+    println!("   {}", func_desc);
+
+    let device = DfuTarget {
+        verify: cli_matches.is_present("verify"),
+        preserve: cli_matches.is_present("preserve"),
+        differential: cli_matches.is_present("diff"),
+        ..DfuTarget::new(&selected, func_desc, &alt_strings)
+    };
+
+    // Then download the image
+    match fw_content {
+        ImageContent::Image(image) => device.download_segments(&mut transport, alt, image.segments()),
+        ImageContent::Targets(targets) => {
+            for target in &targets {
+                let alt = target.find_alt_setting(&alt_strings)
+                    .unwrap_or_report(4, "Unable to map the DfuSe target to the device");
+                device.download_segments(&mut transport, alt, &target.elements);
+            }
+        }
+        ImageContent::Raw(data, offset) => {
+            // Place the binary in the memory of the alt setting, if the layout is known
+            let alt_string = alt_strings.get(alt as usize).map(String::as_str).unwrap_or("");
+            match parse_memory_layout_string(alt_string) {
+                Ok(memory_map) => {
+                    let segment = bin::place(data, offset, &memory_map)
+                        .unwrap_or_report(4, "Unable to place the binary image");
+                    device.download_segments(&mut transport, alt, &[segment]);
+                }
+                Err(_) => device.download_raw(&mut transport, alt, &data),
+            }
         }
+    }
+
+    println!("Download done");
+}
+
+/// Collects the names of the alt settings of the device, which hold the memory layouts
+fn alt_strings(selected: &DfuDevice) -> Vec<String> {
+    selected.alt_settings.iter().map(|a| a.name.clone()).collect()
+}
+
+/// The content of a loaded image, before it is placed in the device memory
+enum ImageContent {
+    /// Firmware with data at fixed addresses
+    Image(FirmwareImage),
+    /// DfuSe targets, each meant for one alt setting
+    Targets(Vec<DfuSeTarget>),
+    /// Raw data without address, with an optional offset to place it at
+    Raw(Vec<u8>, Option<usize>),
+}
 
-        // Make sure there is a active configuration
-        let active_config_result = device.active_config_descriptor();
-        if let Err(e) = active_config_result {
-            println!("Unable to get active config: {}", e);
-            continue;
+/// The DFU interface of the opened device
+struct DfuTarget<'a> {
+    interface: u8,
+    descriptor: DfuFunctionalDescriptor,
+    alt_strings: &'a [String],
+    /// Read back and compare the memory after downloading
+    verify: bool,
+    /// Keep the bytes outside the image in partially erased blocks
+    preserve: bool,
+    /// Only erase and write the blocks differing from the image
+    differential: bool,
+}
+
+impl<'a> DfuTarget<'a> {
+    /// Creates a target for the DFU interface of the selected device, with all download options off
+    ///
+    /// # Arguments
+    /// * `selected` - The description of the device
+    /// * `descriptor` - The DFU functional descriptor of the device
+    /// * `alt_strings` - The names of the alt settings of the device
+    fn new(selected: &DfuDevice, descriptor: DfuFunctionalDescriptor, alt_strings: &'a [String]) -> Self {
+        DfuTarget {
+            interface: selected.interface,
+            descriptor,
+            alt_strings,
+            verify: false,
+            preserve: false,
+            differential: false,
         }
+    }
 
-        // Now unwrap should work
-        let device_desc = device_desc_result.unwrap();
-        let active_config = active_config_result.unwrap();
+    /// Selects the given alt setting
+    fn select_alt<T: DfuTransport>(&self, transport: &mut T, alt: u8) {
+        transport.set_alt_setting(self.interface, alt)
+            .unwrap_or_report(4, "Unable to select the alt setting");
+    }
 
-        // Grab the interfaces
-        let interfaces = active_config.interfaces();
-        let mut is_device_listed = false;
+    /// Selects a DfuSe alt setting, and starts a session using its memory layout
+    fn open_dfuse<'t, T: DfuTransport>(&self, transport: &'t mut T, alt: u8) -> Stm32DfuSe<'a, &'t mut T> {
+        self.select_alt(transport, alt);
 
-        // Create a mutable vector to store our string indices
-        let mut string_ix_list: Vec<u8> = Vec::new();
+        let alt_string = self.alt_strings.get(alt as usize).map(String::as_str).unwrap_or("");
+        let memory_map = parse_memory_layout_string(alt_string)
+            .unwrap_or_die(4, "The alt setting does not describe its memory layout");
+        let dfu = Dfu::from_descriptor(transport, u16::from(self.interface), &self.descriptor);
+        Stm32DfuSe::new(dfu, memory_map)
+    }
 
-        // Iterate the interfaces, and grab
-        for interface in interfaces {
-            for if_desc in interface.descriptors() {
-                // Skip interfaces which are not DFU
-                if if_desc.class_code() != 0xFE || if_desc.sub_class_code() != 0x01 { 
-                    continue; 
+    /// Downloads the segments to a DfuSe alt setting, erasing the touched blocks first
+    fn download_segments<T: DfuTransport>(&self, transport: &mut T, alt: u8, segments: &[Segment]) {
+        let mut dfuse = self.open_dfuse(transport, alt);
+        println!("Downloading to alt setting [{}] {}", alt, dfuse.memory_map().name);
+
+        let mut image = FirmwareImage::from_segments(segments.to_vec())
+            .unwrap_or_report(2, "Unable to build the image");
+        let ranges: Vec<(usize, usize)> = segments.iter().map(|s| (s.address, s.data.len())).collect();
+        let plan = dfuse.memory_map().erase_plan(&ranges).unwrap_or_report(4, "Unable to plan the erase");
+
+        // Erasing whole blocks destroys the bytes around the image, unless they are read and written back
+        if !plan.collateral.is_empty() {
+            if self.preserve {
+                println!("Preserving [0x{:X} bytes] outside the image in the erased blocks", plan.collateral_size());
+                for (address, length) in &plan.collateral {
+                    let data = dfuse.read(*address, *length).unwrap_or_report(5, "Unable to read the memory to preserve");
+                    image.add_segment(Segment::new(*address, data)).unwrap_or_report(5, "Unable to preserve the memory");
                 }
+            } else {
+                println!("Warning: Erasing destroys [0x{:X} bytes] outside the image. Use --preserve to keep them", plan.collateral_size());
+                for (address, length) in &plan.collateral {
+                    println!(" - Lost @ [0x{:08X}]: [0x{:X} bytes]", address, length);
+                }
+            }
+        }
+
+        // Only the blocks differing from the image are erased and written in differential mode
+        let (blocks, write_image) = if self.differential {
+            let (changed, write_image) = dfuse.plan_differential(&plan.blocks, &image)
+                .unwrap_or_report(5, "Unable to compare the memory");
+            println!(" - Skipping [{}] of [{}] blocks, which are unchanged", plan.blocks.len() - changed.len(), plan.blocks.len());
+
+            (changed, write_image)
+        } else {
+            (plan.blocks, image.clone())
+        };
+
+        println!(" - Erasing [{}] blocks", blocks.len());
+        dfuse.erase_blocks(&blocks).unwrap_or_report(5, "Erase failed");
+        for segment in write_image.segments() {
+            println!(" - Segment @ [0x{:08X}]: [0x{:X} bytes]", segment.address, segment.data.len());
+            dfuse.write(segment.address, &segment.data).unwrap_or_report(5, "Download failed");
+        }
+
+        if !self.verify {
+            return;
+        }
+
+        println!("Verifying alt setting [{}]", alt);
+        let mut report = VerifyReport::default();
+        for segment in image.segments() {
+            report.merge(dfuse.verify(segment.address, &segment.data).unwrap_or_report(5, "Verification failed"));
+        }
+        for (address, length) in &report.skipped {
+            println!(" - Skipped @ [0x{:08X}]: [0x{:X} bytes] The memory is not readable", address, length);
+        }
+        for mismatch in &report.mismatches {
+            println!(" - Mismatch in {}", mismatch);
+        }
+        exit_on_mismatch(report.is_match());
+    }
+
+    /// Downloads raw data using the plain DFU protocol
+    fn download_raw<T: DfuTransport>(&self, transport: &mut T, alt: u8, data: &[u8]) {
+        self.select_alt(transport, alt);
+        println!("Downloading [0x{:X} bytes] to alt setting [{}]", data.len(), alt);
+        if self.differential {
+            println!("Warning: The alt setting has no memory layout, so all the data is downloaded");
+        }
+
+        let mut dfu = Dfu::from_descriptor(transport, u16::from(self.interface), &self.descriptor);
+        dfu.download(data).unwrap_or_report(5, "Download failed");
+
+        if !self.verify {
+            return;
+        }
 
-                // Display the device if not already done
-                if !is_device_listed {
+        // The firmware can only be read back if the device stays in DFU mode after manifestation
+        let required = DfuAttributes::CAN_UPLOAD | DfuAttributes::MANIFESTATION_TOLERANT;
+        if !self.descriptor.attributes.contains(required) {
+            println!("Skipping verification: The device does not support upload after manifestation");
+            return;
+        }
+
+        println!("Verifying alt setting [{}]", alt);
+        let actual = dfu.upload_all(data.len()).unwrap_or_report(5, "Verification failed");
+        let differs = |i: &usize| actual.get(*i) != Some(&data[*i]);
+        if let Some(first) = (0..data.len()).find(differs) {
+            let count = (0..data.len()).filter(differs).count();
+            println!(" - Mismatch: [{}] bytes differ, first at offset [0x{:X}]", count, first);
+        }
+        exit_on_mismatch(actual == data);
+    }
+
+    /// Reads memory from the alt setting. DfuSe alt settings are read using the address pointer,
+    /// while others are read from the start using plain DFU.
+    /// # Arguments
+    /// * `address` - The address to read from, or the start of the readable memory if not given.
+    ///   Without a memory layout, this is only the address given to the data
+    /// * `length` - The number of bytes to read, or to the end of the readable memory if not given
+    ///
+    /// # Return
+    /// The memory read
+    fn upload<T: DfuTransport>(&self, transport: &mut T, alt: u8, address: Option<usize>, length: Option<usize>) -> FirmwareImage {
+        self.select_alt(transport, alt);
 
-                    println!("Bus {:03} Device {:03} ID {:04x}:{:04x}, Class {:02X}:{:02X}",
-                        device.bus_number(),
-                        device.address(),
-                        device_desc.vendor_id(),
-                        device_desc.product_id(),
-                        device_desc.class_code(),
-                        device_desc.sub_class_code()
-                    );
+        let alt_string = self.alt_strings.get(alt as usize).map(String::as_str).unwrap_or("");
+        let mut dfu = Dfu::from_descriptor(transport, u16::from(self.interface), &self.descriptor);
+        let mut image = FirmwareImage::new();
 
-                    is_device_listed = true;
+        match parse_memory_layout_string(alt_string) {
+            Ok(memory_map) => {
+                println!("Reading from alt setting [{}] {}", alt, memory_map.name);
+                if memory_map.regions(Accessibility::READ).is_empty() {
+                    eprintln!("Error: The alt setting has no readable memory");
+                    process::exit(4)
                 }
+                let ranges = memory_map.read_ranges(address, length).unwrap_or_report(4, "Unable to read the given range");
 
-                let string_index = if_desc.description_string_index().unwrap_or(0xFF);
+                let mut dfuse = Stm32DfuSe::new(dfu, memory_map);
+                for (address, length) in ranges {
+                    println!(" - Reading @ [0x{:08X}]: [0x{:X} bytes]", address, length);
+                    let data = dfuse.read(address, length).unwrap_or_report(5, "Upload failed");
+                    image.add_segment(Segment::new(address, data)).unwrap_or_report(5, "Upload failed");
+                }
+            }
+            Err(_) => {
+                println!("Reading from alt setting [{}]", alt);
+                let data = dfu.upload_all(length.unwrap_or(usize::MAX)).unwrap_or_report(5, "Upload failed");
+                image.add_segment(Segment::new(address.unwrap_or(0), data)).unwrap_or_report(5, "Upload failed");
+            }
+        }
+        image
+    }
+}
 
-                string_ix_list.push(string_index);
+/// Exits with an error if the verification found differences
+fn exit_on_mismatch(matching: bool) {
+    if !matching {
+        eprintln!("Error: The device memory does not match the image");
+        process::exit(5);
+    }
+    println!("Verification done");
+}
 
-                // Display interface info
-                println!(" - Interface [{}]: Class: {:02X}:{:02X}, Protocol: {:02X}, String: {:02X}",
-                    interface.number(),
-                    if_desc.class_code(),
-                    if_desc.sub_class_code(),
-                    if_desc.protocol_code(),
-                    string_index
-                );
+/// Loads the image using the given format
+/// # Arguments
+/// * `data` - The content of the image file
+/// * `format` - The format of the file
+/// * `family` - The family ID to use from UF2 files, or all if not given
+///
+/// # Return
+/// The image content, and the DFU suffix for DFU files
+fn load_image(data: Vec<u8>, format: &ImageFormat, family: Option<u32>) -> (ImageContent, Option<DfuSuffix>) {
+    let content = match format {
+        ImageFormat::Hex(_) => {
+            let text = String::from_utf8_lossy(&data);
+            let image = ihex::parse(&text).unwrap_or_report(2, "Unable to parse the HEX file");
+            print!("{}", image);
+            ImageContent::Image(image)
+        }
+        ImageFormat::Srec(_) => {
+            let text = String::from_utf8_lossy(&data);
+            let srec_file = srec::parse(&text).unwrap_or_report(2, "Unable to parse the SREC file");
+            if let Some(header) = &srec_file.header {
+                println!("SREC header: {}", header);
+            }
+            print!("{}", srec_file.image);
+            ImageContent::Image(srec_file.image)
+        }
+        ImageFormat::Uf2(_) => {
+            let uf2_file = uf2::parse(&data, family).unwrap_or_report(2, "Unable to parse the UF2 file");
+            for family in &uf2_file.families {
+                println!("UF2 family: 0x{:08X}", family);
             }
+            print!("{}", uf2_file.image);
+            ImageContent::Image(uf2_file.image)
+        }
+        ImageFormat::Elf(_) => {
+            let image = elf::parse(&data).unwrap_or_report(2, "Unable to parse the ELF file");
+            print!("{}", image);
+            ImageContent::Image(image)
+        }
+        ImageFormat::Dfu(_) if dfuse::is_dfuse(&data) => {
+            let dfuse_file = dfuse::parse(&data).unwrap_or_report(2, "Unable to parse the DfuSe file");
+            println!("DfuSe file for {}", dfuse_file.suffix);
+            for target in &dfuse_file.targets {
+                println!("{}", target);
+                print_segments(&target.elements);
+            }
+            return (ImageContent::Targets(dfuse_file.targets), Some(dfuse_file.suffix));
+        }
+        ImageFormat::Dfu(offset) => {
+            // A plain DFU file is the raw firmware followed by the suffix
+            let (suffix, payload) = DfuSuffix::parse(&data).unwrap_or_report(2, "Unable to parse the DFU file");
+            println!("DFU file for {}", suffix);
+            println!(" - Payload: [0x{:X} bytes]", payload.len());
+            return (ImageContent::Raw(payload.to_vec(), *offset), Some(suffix));
+        }
+        ImageFormat::Bin(offset) => {
+            println!(" - Binary: [0x{:X} bytes]", data.len());
+            ImageContent::Raw(data, *offset)
+        }
+    };
+    (content, None)
+}
+
+/// Prints a summary of the loaded image segments
+/// # Arguments
+/// * `segments` - The segments to list
+fn print_segments(segments: &[Segment]) {
+    for segment in segments {
+        println!(" - Segment @ [0x{:08X}]: [0x{:X} bytes]", segment.address, segment.data.len());
+    }
+}
+
+/// Returns the arguments used to select the device and alt setting
+fn device_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("device")
+            .short("d")
+            .long("device")
+            .value_name("VID:PID")
+            .help("Select the device with the given vendor and product ID, in hex")
+            .takes_value(true),
+        Arg::with_name("serial")
+            .short("s")
+            .long("serial")
+            .value_name("SERIAL")
+            .help("Select the device with the given serial number")
+            .takes_value(true),
+        Arg::with_name("path")
+            .short("p")
+            .long("path")
+            .value_name("PATH")
+            .help("Select the device at the given port path, such as 1-2.4")
+            .takes_value(true),
+        Arg::with_name("alt")
+            .short("a")
+            .long("alt")
+            .value_name("ALT")
+            .help("Select the alt setting with the given number (0 by default)")
+            .takes_value(true),
+        Arg::with_name("alt-name")
+            .short("n")
+            .long("alt-name")
+            .value_name("NAME")
+            .help("Select the alt setting with the given name, such as \"Internal Flash\"")
+            .conflicts_with("alt")
+            .takes_value(true),
+    ]
+}
+
+/// Creates the device filter from the device selection arguments
+fn device_filter(matches: &ArgMatches) -> DeviceFilter {
+    let mut filter = DeviceFilter {
+        serial: matches.value_of("serial").map(str::to_string),
+        path: matches.value_of("path").map(str::to_string),
+        alt: matches.value_of("alt").map(|altstr| {
+            parse::usize_from_string(altstr)
+                .and_then(|alt| u8::try_from(alt).map_err(|_| ()))
+                .unwrap_or_die(1, "Unable to parse the given alt parameter")
+        }),
+        alt_name: matches.value_of("alt-name").map(str::to_string),
+        ..Default::default()
+    };
+    if let Some(id) = matches.value_of("device") {
+        filter.set_id(id).unwrap_or_report(1, "Unable to parse the given device parameter");
+    }
+    filter
+}
+
+/// Enumerates the DFU devices, and selects the one matching the device selection arguments.
+/// The only DFU device is selected when no arguments are given.
+///
+/// # Return
+/// The USB device, its description and the selected alt setting
+fn select_device(matches: &ArgMatches) -> (Device<GlobalContext>, DfuDevice, u8) {
+    select_filtered_device(device_filter(matches))
+}
+
+/// Enumerates the DFU devices, and selects the one matching the filter
+///
+/// # Return
+/// The USB device, its description and the selected alt setting
+fn select_filtered_device(filter: DeviceFilter) -> (Device<GlobalContext>, DfuDevice, u8) {
+    let mut devices = enumerate::find_devices().unwrap_or_report(3, "Unable to enumerate USB devices");
+
+    let index = filter.select(devices.iter().map(|(_, d)| d)).unwrap_or_report(3, "Unable to select the device");
+    let (mut usb_device, mut selected) = devices.swap_remove(index);
+
+    // A device running its application must be detached, and is then found again in DFU mode
+    if selected.is_runtime() {
+        println!("Detaching device: {}", selected);
+        let timeout = detach_device(&usb_device, &selected);
+
+        let (dfu_usb_device, dfu_device) = enumerate::wait_for_dfu_mode(&selected, timeout)
+            .unwrap_or_report(3, "Unable to find the device after detaching");
+        usb_device = dfu_usb_device;
+        selected = dfu_device;
+    }
+
+    let alt = filter.find_alt(&selected).unwrap_or_else(|| {
+        eprintln!("Error: The device has no alt setting matching the selection");
+        process::exit(4)
+    });
+
+    println!("Using device: {}", selected);
+    (usb_device, selected, alt)
+}
+
+/// Opens the DFU interface of the device
+///
+/// # Return
+/// The transport, and the DFU functional descriptor of the device
+fn open_device(usb_device: &Device<GlobalContext>, selected: &DfuDevice) -> (RusbTransport<GlobalContext>, DfuFunctionalDescriptor) {
+    let mut transport = RusbTransport::open(usb_device, selected.interface)
+        .unwrap_or_report(3, "Unable to open USB device");
+
+    // Some devices only list the functional descriptor in the configuration descriptor
+    let func_desc = match selected.descriptor {
+        Some(desc) => desc,
+        None => DfuFunctionalDescriptor::read(&mut transport, selected.interface)
+            .unwrap_or_report(3, "Unable to read the DFU functional descriptor"),
+    };
+    (transport, func_desc)
+}
+
+/// Checks if the readout protection of a DfuSe device is active, and removes it when allowed
+/// # Arguments
+/// * `transport` - The opened device
+/// * `selected` - The description of the device
+/// * `descriptor` - The DFU functional descriptor of the device
+/// * `alt` - The alt setting to check with. Devices without a memory layout are not checked
+/// * `unprotect` - Remove the protection, instead of failing
+///
+/// # Return
+/// True if the protection was removed, and the device is resetting
+fn remove_read_protection<T: DfuTransport>(transport: &mut T, selected: &DfuDevice, descriptor: DfuFunctionalDescriptor, alt: u8, unprotect: bool) -> bool {
+    let alt_strings = alt_strings(selected);
+    if parse_memory_layout_string(alt_strings.get(alt as usize).map(String::as_str).unwrap_or("")).is_err() {
+        return false;
+    }
+
+    let device = DfuTarget::new(selected, descriptor, &alt_strings);
+    let mut dfuse = device.open_dfuse(transport, alt);
+    match dfuse.check_protection() {
+        Ok(()) => return false,
+        Err(DfuSeError::ReadProtected) => {}
+        Err(e) => {
+            eprintln!("Error: Unable to access the device memory: {}", e);
+            process::exit(5);
         }
+    }
+
+    println!("The device rejects commands with errVENDOR or errTARGET, as its readout protection (RDP level 1) is active.");
+    println!("Removing the protection mass erases the flash, and the device resets afterwards.");
+    if !unprotect {
+        eprintln!("Error: The device is read protected. Use --unprotect to remove the protection and download anyway");
+        process::exit(4);
+    }
 
-        // If device was listed, we need to read strings
-        let dev_desc_result = device.open();
-        if let Err(e) = dev_desc_result {
-            println!("Unable to open USB device: {}", e);
-            continue;
+    // The device may reset before it answers
+    println!("Removing the readout protection");
+    match dfuse.read_unprotect() {
+        Ok(()) | Err(DfuSeError::Dfu(DfuError::Transport(TransportError::NoDevice))) => true,
+        Err(e) => {
+            eprintln!("Error: Unable to remove the readout protection: {}", e);
+            process::exit(5);
         }
-        
-        let dev_desc = dev_desc_result.unwrap();
+    }
+}
 
-        //dev_desc.read_interface_string(, interface, timeout)
+/// Sends DFU_DETACH to a run-time device, resetting it if it does not detach by itself
+///
+/// # Return
+/// How long to wait for the device to show up in DFU mode
+fn detach_device(usb_device: &Device<GlobalContext>, selected: &DfuDevice) -> Duration {
+    let (mut transport, func_desc) = open_device(usb_device, selected);
+    let mut dfu = Dfu::from_descriptor(&mut transport, u16::from(selected.interface), &func_desc);
+    dfu.detach_device().unwrap_or_report(3, "Unable to detach the device");
+    Duration::from_millis(u64::from(func_desc.detach_timeout)) + REENUMERATION_TIMEOUT
+}
 
+/// Lists the DFU capable devices, as a tree of devices, alt settings, banks and sectors
+/// # Arguments
+/// * `matches` - The arguments of the list subcommand
+fn list(matches: &ArgMatches) {
+    let devices = enumerate::find_devices().unwrap_or_report(3, "Unable to enumerate USB devices");
 
+    if matches.is_present("json") {
+        let entries: Vec<String> = devices.iter().map(|(_, device)| device_to_json(device)).collect();
+        println!("{{\"devices\":[{}]}}", entries.join(","));
+        return;
+    }
 
-        //dev_desc.close();
+    if devices.is_empty() {
+        println!("No DFU capable devices found");
+        return;
     }
 
+    for (index, (_, device)) in devices.iter().enumerate() {
+        println!("Device [{}] {}", index, device);
+        if let Some(desc) = &device.descriptor {
+            println!("   {}", desc);
+        }
 
+        for alt in &device.alt_settings {
+            println!(" - Alt [{}] \"{}\"", alt.alt, alt.name);
+            match parse_memory_layout_string(&alt.name) {
+                Ok(memory_map) => {
+                    for bank in memory_map.banks() {
+                        println!("    - Bank [{}] @ [0x{:08X}]", bank.index, bank.address);
+                        for sector in bank.sectors() {
+                            println!("       - {}", sector);
+                        }
+                    }
+                }
+                Err(_) => println!("    - No memory layout"),
+            }
+        }
+    }
+}
+
+/// Formats the device, with its alt settings and memory layouts, as a JSON object
+fn device_to_json(device: &DfuDevice) -> String {
+    let optional = |value: &Option<String>| value.as_deref().map_or("null".to_string(), json_string);
+
+    let alts: Vec<String> = device.alt_settings.iter().map(|alt| {
+        let memory = match parse_memory_layout_string(&alt.name) {
+            Ok(memory_map) => {
+                let banks: Vec<String> = memory_map.banks().iter().map(|bank| {
+                    let sectors: Vec<String> = bank.sectors().iter().map(|sector| {
+                        let access: Vec<String> = [(Accessibility::READ, "read"), (Accessibility::WRITE, "write"), (Accessibility::ERASE, "erase")]
+                            .iter()
+                            .filter(|(flag, _)| sector.is_accessible(*flag))
+                            .map(|(_, name)| json_string(name))
+                            .collect();
+                        format!("{{\"index\":{},\"address\":{},\"block_count\":{},\"block_size\":{},\"access\":[{}]}}",
+                            sector.index, sector.address, sector.block_count, sector.block_size, access.join(","))
+                    }).collect();
+                    format!("{{\"index\":{},\"address\":{},\"sectors\":[{}]}}", bank.index, bank.address, sectors.join(","))
+                }).collect();
+                format!("{{\"name\":{},\"banks\":[{}]}}", json_string(memory_map.name), banks.join(","))
+            }
+            Err(_) => "null".to_string(),
+        };
+        format!("{{\"alt\":{},\"name\":{},\"memory\":{}}}", alt.alt, json_string(&alt.name), memory)
+    }).collect();
+
+    let descriptor = match &device.descriptor {
+        Some(desc) => format!("{{\"dfu_version\":{},\"transfer_size\":{},\"detach_timeout\":{},\"attributes\":{}}}",
+            desc.dfu_version, desc.transfer_size, desc.detach_timeout, desc.attributes.bits()),
+        None => "null".to_string(),
+    };
+
+    format!("{{\"vendor_id\":{},\"product_id\":{},\"bus\":{},\"address\":{},\"path\":{},\"manufacturer\":{},\"product\":{},\"serial\":{},\"interface\":{},\"protocol\":{},\"descriptor\":{},\"alt_settings\":[{}]}}",
+        device.vendor_id, device.product_id, device.bus, device.address, json_string(&device.path()),
+        optional(&device.manufacturer), optional(&device.product), optional(&device.serial),
+        device.interface, device.protocol, descriptor, alts.join(","))
+}
+
+/// Formats the text as a JSON string, with quotes and escapes
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Converts an image file to another format
+/// # Arguments
+/// * `matches` - The arguments of the convert subcommand
+fn convert(matches: &ArgMatches) {
+    let offset = number_arg(matches, "offset");
+    let family: Option<u32> = number_arg(matches, "family");
+    let alt: Option<u8> = number_arg(matches, "alt");
+
+    // Find the output format before loading, so a bad name fails early
+    let output_file = matches.value_of("output").unwrap();
+    let output_name = matches.value_of("to").map(str::to_string).unwrap_or_else(|| get_file_extension(output_file, ""));
+    let output_format = parse_image_type_from_extension(&output_name, None).unwrap_or_else(|| {
+        eprintln!("Error: Unknown output format '{}'", output_name);
+        process::exit(1)
+    });
+
+    let input_file = matches.value_of("input").unwrap();
+    let data = fs::read(input_file).unwrap_or_report(2, "Unable to read the image file");
+    let input_format = select_image_format(input_file, &data, matches.value_of("format"), offset);
+    println!("Converting {} ({}) to {} ({})", input_file, input_format.name(), output_file, output_format.name());
+
+    // Collect the content as DfuSe targets, which keeps the alt settings of DfuSe input
+    let (content, _) = load_image(data, &input_format, family);
+    let single_target = |image: FirmwareImage| {
+        let entry_point = image.entry_point;
+        let target = DfuSeTarget {
+            alt_setting: alt.unwrap_or(0),
+            name: matches.value_of("target-name").map(str::to_string),
+            elements: image.into_segments(),
+        };
+        (vec![target], entry_point)
+    };
+
+    // With memory layouts, the image is split into one target per alt setting
+    let layouts: Vec<&str> = matches.values_of("layout").into_iter().flatten().collect();
+    let image_targets = |image: FirmwareImage| {
+        if layouts.is_empty() {
+            single_target(image)
+        } else {
+            (split_targets(&image, &layouts), image.entry_point)
+        }
+    };
+
+    let (targets, entry_point) = match content {
+        ImageContent::Targets(targets) if !layouts.is_empty() => {
+            let mut image = FirmwareImage::new();
+            for target in targets {
+                let target_image = FirmwareImage::from_segments(target.elements)
+                    .unwrap_or_report(2, "Unable to build the image");
+                image.merge(target_image).unwrap_or_report(2, "Unable to merge the DfuSe targets");
+            }
+            image_targets(image)
+        }
+        ImageContent::Targets(targets) => {
+            let targets = targets.into_iter().filter(|t| alt.map_or(true, |a| a == t.alt_setting)).collect();
+            (targets, None)
+        }
+        ImageContent::Image(image) => image_targets(image),
+        ImageContent::Raw(data, offset) => {
+            if offset.is_none() && !matches!(output_format, ImageFormat::Bin(_)) {
+                eprintln!("Error: The input has no address, use --offset to give one");
+                process::exit(1);
+            }
+            let image = FirmwareImage::from_segments(vec![Segment::new(offset.unwrap_or(0), data)])
+                .unwrap_or_report(2, "Unable to build the image");
+            image_targets(image)
+        }
+    };
+
+    if targets.is_empty() {
+        eprintln!("Error: The input has no target for the given alt setting");
+        process::exit(4);
+    }
+
+    let output = match output_format {
+        ImageFormat::Dfu(_) => {
+            let vendor_id = number_arg(matches, "vid").unwrap();
+            let product_id = number_arg(matches, "pid").unwrap();
+            let device = number_arg(matches, "bcd-device").unwrap();
+            dfuse::write(&targets, vendor_id, product_id, device)
+        }
+        _ => {
+            // The other formats have no alt settings, so all the targets are merged
+            let mut image = FirmwareImage::new();
+            for target in targets {
+                let target_image = FirmwareImage::from_segments(target.elements)
+                    .unwrap_or_report(2, "Unable to build the image");
+                image.merge(target_image).unwrap_or_report(2, "Unable to merge the DfuSe targets");
+            }
+            image.entry_point = entry_point;
+            encode_image(&image, &output_format, output_file, number_arg(matches, "fill").unwrap())
+        }
+    };
+
+    fs::write(output_file, output).unwrap_or_report(2, "Unable to write the output file");
+    println!("Conversion done");
+}
+
+/// Splits the image into one DfuSe target per memory layout holding image data
+/// # Arguments
+/// * `image` - The image to split
+/// * `layouts` - The memory layout strings, the index of each being its alt setting
+///
+/// # Return
+/// The targets, named after the memory layouts. Exits if any image data is outside the layouts.
+fn split_targets(image: &FirmwareImage, layouts: &[&str]) -> Vec<DfuSeTarget> {
+    let mut targets: Vec<DfuSeTarget> = Vec::new();
+    let mut covered = FirmwareImage::new();
+
+    for (alt, layout) in layouts.iter().enumerate() {
+        let memory_map = parse_memory_layout_string(layout)
+            .unwrap_or_die(1, "Unable to parse the given memory layout");
+
+        let mut target_image = FirmwareImage::new();
+        for (address, length) in memory_map.regions(Accessibility::empty()) {
+            let part = image.slice(address, length).unwrap_or_else(|| {
+                eprintln!("Error: The memory layout reaches past the end of the address space");
+                process::exit(1)
+            });
+            covered.merge(part.clone()).unwrap_or_report(1, "The memory layouts overlap");
+            target_image.merge(part).unwrap_or_report(1, "The memory layouts overlap");
+        }
+
+        if !target_image.is_empty() {
+            targets.push(DfuSeTarget {
+                alt_setting: alt as u8,
+                name: Some(memory_map.name.trim().to_string()),
+                elements: target_image.into_segments(),
+            });
+        }
+    }
+
+    if covered.data_size() != image.data_size() {
+        eprintln!("Error: The image has [0x{:X} bytes] outside the given memory layouts", image.data_size() - covered.data_size());
+        process::exit(4);
+    }
+    targets
+}
+
+/// Reads memory from the device, and writes it to a file
+/// # Arguments
+/// * `matches` - The arguments of the read subcommand
+fn read(matches: &ArgMatches) {
+    let address = number_arg(matches, "address");
+    let length = number_arg(matches, "length");
+    let fill = number_arg(matches, "fill").unwrap();
+
+    // Find the output format before reading, so a bad name fails early
+    let output_file = matches.value_of("output").unwrap();
+    let output_name = matches.value_of("to").map(str::to_string).unwrap_or_else(|| get_file_extension(output_file, "bin"));
+    let output_format = match parse_image_type_from_extension(&output_name, None) {
+        Some(format @ ImageFormat::Bin(_)) | Some(format @ ImageFormat::Hex(_)) | Some(format @ ImageFormat::Srec(_)) => format,
+        _ => {
+            eprintln!("Error: Unsupported output format '{}', use bin, hex or srec", output_name);
+            process::exit(1)
+        }
+    };
+
+    let (usb_device, selected, alt) = select_device(matches);
+    let (mut transport, func_desc) = open_device(&usb_device, &selected);
+    let alt_strings = alt_strings(&selected);
+    println!("   {}", func_desc);
+
+    let device = DfuTarget::new(&selected, func_desc, &alt_strings);
+    let image = device.upload(&mut transport, alt, address, length);
+
+    let output = encode_image(&image, &output_format, output_file, fill);
+    fs::write(output_file, output).unwrap_or_report(2, "Unable to write the output file");
+    println!("Read [0x{:X} bytes] to {} ({})", image.data_size(), output_file, output_format.name());
+}
+
+/// Reads and decodes the STM32 option bytes, and writes back the fields given
+/// # Arguments
+/// * `matches` - The arguments of the option-bytes subcommand
+fn option_bytes(matches: &ArgMatches) {
+    let family = Family::from_name(matches.value_of("family").unwrap())
+        .unwrap_or_report(1, "Unable to parse the given family parameter");
+    let changes: Vec<(&str, &str)> = matches.values_of("set").into_iter().flatten().map(|change| {
+        change.split_once('=').unwrap_or_else(|| {
+            eprintln!("Error: Unable to parse '{}', use FIELD=VALUE", change);
+            process::exit(1)
+        })
+    }).collect();
+
+    // Use the option bytes alt setting, unless another one is selected
+    let mut filter = device_filter(matches);
+    if filter.alt.is_none() && filter.alt_name.is_none() {
+        filter.alt_name = Some("Option Bytes".to_string());
+    }
+    let (usb_device, selected, alt) = select_filtered_device(filter);
+    let (mut transport, func_desc) = open_device(&usb_device, &selected);
+    let alt_strings = alt_strings(&selected);
+
+    let device = DfuTarget::new(&selected, func_desc, &alt_strings);
+    let mut dfuse = device.open_dfuse(&mut transport, alt);
+    let (address, length) = dfuse.memory_map().regions(Accessibility::READ).first().copied().unwrap_or_else(|| {
+        eprintln!("Error: The alt setting has no readable memory");
+        process::exit(4)
+    });
+
+    let data = dfuse.read(address, length).unwrap_or_report(5, "Unable to read the option bytes");
+    let current = OptionBytes::parse(family, &data).unwrap_or_report(4, "Unable to decode the option bytes");
+    println!("Option bytes @ [0x{:08X}] for {:?}:", address, family);
+    print!("{}", current);
+
+    if changes.is_empty() {
+        return;
+    }
+
+    let mut updated = current.clone();
+    for (name, value) in changes {
+        updated.set(name, value).unwrap_or_report(1, "Unable to set the option byte field");
+    }
+    if updated == current {
+        println!("The option bytes are already set as given");
+        return;
+    }
+
+    println!("Changing:");
+    for field in family.fields().into_iter().filter(|f| current.get(f) != updated.get(f)) {
+        println!(" - {:<10} {} => {}", field.name, field.format(current.get(field)), field.format(updated.get(field)));
+    }
+
+    // Changing the readout protection either locks the device or erases it
+    let (old_level, new_level) = (current.rdp_level(), updated.rdp_level());
+    if new_level > old_level {
+        let permanent = if new_level == RdpLevel::Level2 { " Level 2 can never be undone." } else { "" };
+        confirm(&format!("This raises the readout protection from {} to {}.{}", old_level, new_level, permanent), "yes");
+    } else if new_level < old_level {
+        confirm("Lowering the readout protection mass erases the flash.", "yes");
+    }
+
+    // The device resets to load the new option bytes, and may leave before answering
+    match dfuse.write(address, &updated.to_bytes()) {
+        Ok(()) | Err(DfuSeError::Dfu(DfuError::Transport(TransportError::NoDevice))) => {}
+        Err(e) => {
+            eprintln!("Error: Unable to write the option bytes: {}", e);
+            process::exit(5);
+        }
+    }
+    println!("Option bytes written. The device resets to load them");
+}
+
+/// Programs the OTP memory, after checking that every byte can be written and confirming the changes
+/// # Arguments
+/// * `matches` - The arguments of the otp-write subcommand
+fn otp_write(matches: &ArgMatches) {
+    let offset = number_arg(matches, "offset");
+    let image_file = matches.value_of("image").unwrap();
+    let data = fs::read(image_file).unwrap_or_report(2, "Unable to read the image file");
+    let format = select_image_format(image_file, &data, matches.value_of("format"), offset);
+    let (content, _) = load_image(data, &format, None);
+
+    // Use the OTP alt setting, unless another one is selected
+    let mut filter = device_filter(matches);
+    if filter.alt.is_none() && filter.alt_name.is_none() {
+        filter.alt_name = Some("OTP Memory".to_string());
+    }
+    let (usb_device, selected, alt) = select_filtered_device(filter);
+    let (mut transport, func_desc) = open_device(&usb_device, &selected);
+    let alt_strings = alt_strings(&selected);
+
+    let device = DfuTarget::new(&selected, func_desc, &alt_strings);
+    let mut dfuse = device.open_dfuse(&mut transport, alt);
+    let layout = OtpLayout::from_memory_map(dfuse.memory_map()).unwrap_or_report(4, "The alt setting is not OTP memory");
+
+    // Binaries without an address are placed at the start of the OTP memory
+    let image = match content {
+        ImageContent::Image(image) => image,
+        ImageContent::Targets(targets) => {
+            let mut image = FirmwareImage::new();
+            for target in targets {
+                let target_image = FirmwareImage::from_segments(target.elements).unwrap_or_report(2, "Unable to build the image");
+                image.merge(target_image).unwrap_or_report(2, "Unable to merge the DfuSe targets");
+            }
+            image
+        }
+        ImageContent::Raw(data, offset) => {
+            FirmwareImage::from_segments(vec![Segment::new(offset.unwrap_or(layout.data_address), data)])
+                .unwrap_or_report(2, "Unable to build the image")
+        }
+    };
+
+    let current = dfuse.read(layout.data_address, layout.length()).unwrap_or_report(5, "Unable to read the OTP memory");
+    let new = otp::apply(&layout, &current, &image, matches.is_present("lock"))
+        .unwrap_or_report(4, "Unable to program the OTP memory");
+    let changes = otp::changes(layout.data_address, &current, &new);
+    if changes.is_empty() {
+        println!("The OTP memory already holds the image");
+        return;
+    }
+
+    println!("Changes to the OTP memory:");
+    print!("{}", otp::hex_diff(layout.data_address, &current, &new));
+    let changed: usize = changes.iter().map(|c| c.data.len()).sum();
+    confirm(&format!("Programming [{}] OTP bytes can never be undone.", changed), "yes");
+
+    for change in &changes {
+        dfuse.write(change.address, &change.data).unwrap_or_report(5, "Unable to program the OTP memory");
+    }
+
+    let result = dfuse.read(layout.data_address, layout.length()).unwrap_or_report(5, "Unable to read back the OTP memory");
+    exit_on_mismatch(result == new);
+}
+
+/// Erases the whole memory, a bank or the blocks touched by an address range, after listing
+/// the blocks and confirming
+/// # Arguments
+/// * `matches` - The arguments of the erase subcommand
+fn erase(matches: &ArgMatches) {
+    let bank = number_arg(matches, "bank");
+    let range = number_arg(matches, "address").zip(number_arg(matches, "length"));
+    if range.map_or(false, |(_, length)| length == 0) {
+        eprintln!("Error: The length of the range to erase must be positive");
+        process::exit(1);
+    }
+
+    let (usb_device, selected, alt) = select_device(matches);
+    let (mut transport, func_desc) = open_device(&usb_device, &selected);
+    let alt_strings = alt_strings(&selected);
+
+    let device = DfuTarget::new(&selected, func_desc, &alt_strings);
+    let mut dfuse = device.open_dfuse(&mut transport, alt);
+
+    // Find the blocks to erase, refusing memory which can not be erased
+    let blocks: Vec<Block> = match (bank, range) {
+        (Some(index), _) => {
+            let bank = dfuse.memory_map().banks().iter().find(|b| b.index == index).unwrap_or_else(|| {
+                eprintln!("Error: The alt setting has no bank [{}]", index);
+                process::exit(1)
+            });
+            bank.blocks(Accessibility::ERASE).unwrap_or_report(4, "Unable to erase the bank")
+        }
+        (None, Some((address, length))) => dfuse.memory_map().blocks_in_range(address, length, Accessibility::ERASE)
+            .unwrap_or_report(4, "Unable to erase the range"),
+        (None, None) => dfuse.memory_map().blocks(Accessibility::ERASE).unwrap_or_report(4, "Unable to mass erase"),
+    };
+
+    // Mass erase wipes all the erasable memory of the device. Refuse it if other alt settings
+    // describe erasable memory outside the listed blocks.
+    if matches.is_present("all") {
+        for (other, layout) in alt_strings.iter().enumerate().filter(|(other, _)| *other != alt as usize) {
+            let other_map = match parse_memory_layout_string(layout) {
+                Ok(other_map) => other_map,
+                Err(_) => continue,
+            };
+            if let Some((address, length)) = dfuse.memory_map().regions_outside(&other_map, Accessibility::ERASE).first() {
+                eprintln!("Error: Mass erase also erases [0x{:X} bytes] at 0x{:08X} of alt setting [{}], use --bank instead",
+                    length, address, other);
+                process::exit(4);
+            }
+        }
+    }
+
+    let size: usize = blocks.iter().map(|b| b.size).sum();
+    println!("Erasing [{}] blocks, [0x{:X} bytes] of {}:", blocks.len(), size, dfuse.memory_map().name);
+    for block in &blocks {
+        println!(" - {}", block);
+    }
+    if !matches.is_present("yes") {
+        confirm("The listed blocks are erased.", "yes");
+    }
+
+    let result = if matches.is_present("all") {
+        dfuse.mass_erase()
+    } else {
+        dfuse.erase_blocks(&blocks)
+    };
+    result.unwrap_or_report(5, "Unable to erase");
+    println!("Erase done");
+}
+
+/// Asks the user to type the answer to continue, and exits if anything else is typed
+/// # Arguments
+/// * `warning` - What happens when continuing
+/// * `answer` - The text the user must type
+fn confirm(warning: &str, answer: &str) {
+    println!("Warning: {}", warning);
+    print!("Type '{}' to continue: ", answer);
+    let _ = io::stdout().flush();
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap_or_report(1, "Unable to read the answer");
+    if line.trim() != answer {
+        eprintln!("Error: Not confirmed, nothing was changed");
+        process::exit(1);
+    }
+}
+
+/// Encodes the image as a bin, hex or srec file
+/// # Arguments
+/// * `image` - The image to encode
+/// * `format` - The format of the file
+/// * `output_file` - The name of the file, used as the srec header
+/// * `fill` - The byte used to fill gaps in bin files
+///
+/// # Return
+/// The file content
+fn encode_image(image: &FirmwareImage, format: &ImageFormat, output_file: &str, fill: u8) -> Vec<u8> {
+    match format {
+        ImageFormat::Hex(_) => ihex::write(image).unwrap_or_report(2, "Unable to write the HEX file").into_bytes(),
+        ImageFormat::Srec(_) => {
+            let header = Path::new(output_file).file_name().and_then(OsStr::to_str);
+            srec::write(image, header).into_bytes()
+        }
+        ImageFormat::Bin(_) => {
+            let (address, data) = bin::write(image, fill);
+            println!(" - Binary @ [0x{:08X}]: [0x{:X} bytes]", address, data.len());
+            data
+        }
+        _ => {
+            eprintln!("Error: Writing '{}' files is not supported", format.name());
+            process::exit(1);
+        }
+    }
+}
+
+/// Parses a numeric argument, exiting if it is not a number or does not fit the type
+/// # Arguments
+/// * `matches` - The arguments of the command
+/// * `name` - The name of the argument
+///
+/// # Return
+/// The value, or None if the argument is not given
+fn number_arg<T: TryFrom<usize>>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| {
+        parse::usize_from_string(value).ok().and_then(|number| T::try_from(number).ok()).unwrap_or_else(|| {
+            eprintln!("Error: Unable to parse the given {} parameter", name);
+            process::exit(1)
+        })
+    })
+}
+
+/// Selects the format of the image. A format given on the command line is always used.
+/// Otherwise the format is detected from the content, falling back to the file extension,
+/// and to raw binary when neither is known.
+/// # Arguments
+/// * `filename` - The image file name
+/// * `data` - The content of the image file
+/// * `format` - The format given on the command line, if any
+/// * `offset` - Optional offset which is returned with the format
+fn select_image_format(filename: &str, data: &[u8], format: Option<&str>, offset: Option<usize>) -> ImageFormat {
+    if let Some(name) = format {
+        return parse_image_type_from_extension(&name.to_string(), offset).unwrap_or_else(|| {
+            eprintln!("Error: Unknown format '{}'", name);
+            process::exit(1)
+        });
+    }
+
+    let detected = ImageFormat::detect(data, offset);
+    let from_extension = parse_image_type_from_extension(&get_file_extension(filename, ""), offset);
+
+    match (detected, from_extension) {
+        (Some(detected), Some(from_extension)) if detected != from_extension => {
+            println!("Warning: The file extension suggests '{}', but the content is '{}'. Use --format to override",
+                from_extension.name(), detected.name());
+            detected
+        }
+        (Some(detected), _) => detected,
+        (None, Some(from_extension)) => from_extension,
+        (None, None) => ImageFormat::Bin(offset),
+    }
 }
 
 /// Returns the file extension in lower case, or the default value as a string
@@ -177,11 +1412,17 @@ fn get_file_extension(filename: &str, default: &str) -> String {
 /// # Arguments
 /// * `extension` - The extension to get type for
 /// * `offset` - Optional offset which is returned with the type
-fn parse_image_type_from_extension(extension: &String, offset: Option<usize>) -> ImageFormat {
+///
+/// # Return
+/// The format, or None if the extension is not known
+fn parse_image_type_from_extension(extension: &String, offset: Option<usize>) -> Option<ImageFormat> {
     match extension.as_str() {
-        "dfu" => ImageFormat::Dfu(offset),
-        "bin" => ImageFormat::Bin(offset),
-        "hex" => ImageFormat::Hex(offset),
-        &_ => ImageFormat::Elf(offset),
+        "dfu" => Some(ImageFormat::Dfu(offset)),
+        "bin" => Some(ImageFormat::Bin(offset)),
+        "hex" | "ihex" => Some(ImageFormat::Hex(offset)),
+        "elf" | "axf" | "out" => Some(ImageFormat::Elf(offset)),
+        "srec" | "s19" | "s28" | "s37" | "mot" => Some(ImageFormat::Srec(offset)),
+        "uf2" => Some(ImageFormat::Uf2(offset)),
+        &_ => None,
     }
 }
@@ -0,0 +1,263 @@
+//! Parsing of the DFU functional descriptor (bDescriptorType 0x21).
+//!
+//! The functional descriptor follows the DFU interface descriptors in the configuration
+//! descriptor, and libusb hands it over as the "extra" bytes of the interface. It tells
+//! what the device supports, how long it waits for a reset after DFU_DETACH and how many
+//! bytes it accepts per control transfer.
+
+use core::fmt;
+use bitflags::bitflags;
+
+use super::enumerate::{DFU_INTERFACE_CLASS, DFU_INTERFACE_SUBCLASS};
+use super::transport::{DfuTransport, TransportError, DESCRIPTOR_TYPE_CONFIG, DESCRIPTOR_TYPE_INTERFACE};
+
+/// The descriptor type of the DFU functional descriptor
+pub const DESCRIPTOR_TYPE_DFU_FUNCTIONAL: u8 = 0x21;
+
+/// bcdDFUVersion reported by devices using the ST DfuSe extensions
+pub const DFUSE_VERSION: u16 = 0x011A;
+
+bitflags! {
+    /// Defines the bmAttributes of the functional descriptor
+    pub struct DfuAttributes: u8 {
+        /// Device supports download (bitCanDnload)
+        const CAN_DOWNLOAD = 0b0001;
+        /// Device supports upload (bitCanUpload)
+        const CAN_UPLOAD = 0b0010;
+        /// Device can communicate after the manifestation phase (bitManifestationTolerant)
+        const MANIFESTATION_TOLERANT = 0b0100;
+        /// Device performs a bus detach-attach sequence when it receives DFU_DETACH (bitWillDetach)
+        const WILL_DETACH = 0b1000;
+    }
+}
+
+/// Errors that can occur when parsing the functional descriptor
+#[derive(Debug, Clone, PartialEq)]
+pub enum DescriptorParseError {
+    /// There is no functional descriptor among the given descriptors
+    NotFound,
+    /// A descriptor has a length that does not fit the data
+    InvalidLength,
+    /// The descriptor gives a wTransferSize of 0, so no data can be transferred
+    InvalidTransferSize,
+    /// The descriptor could not be read from the device
+    Transport(TransportError),
+}
+
+/// The DFU functional descriptor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DfuFunctionalDescriptor {
+    /// The supported operations
+    pub attributes: DfuAttributes,
+    /// Time in milliseconds the device waits for a USB reset after DFU_DETACH
+    pub detach_timeout: u16,
+    /// Maximum number of bytes per control write/read transaction
+    pub transfer_size: u16,
+    /// The DFU specification release, in binary coded decimal
+    pub dfu_version: u16,
+}
+
+impl fmt::Display for DescriptorParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorParseError::NotFound => write!(f, "No DFU functional descriptor found"),
+            DescriptorParseError::InvalidLength => write!(f, "Descriptor has an invalid length"),
+            DescriptorParseError::InvalidTransferSize => write!(f, "Descriptor has a transfer size of 0"),
+            DescriptorParseError::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Implement display for the attributes
+impl fmt::Display for DfuAttributes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Collect the names of the set flags, and join them with a separator
+        let mut names: Vec<&str> = Vec::new();
+        if self.contains(DfuAttributes::WILL_DETACH) {
+            names.push("Will Detach");
+        }
+        if !self.contains(DfuAttributes::MANIFESTATION_TOLERANT) {
+            names.push("Manifestation Intolerant");
+        }
+        if self.contains(DfuAttributes::CAN_UPLOAD) {
+            names.push("Upload Supported");
+        }
+        if self.contains(DfuAttributes::CAN_DOWNLOAD) {
+            names.push("Download Supported");
+        }
+        write!(f, "{}", names.join(" | "))
+    }
+}
+
+impl DfuFunctionalDescriptor {
+    /// Finds and parses the functional descriptor in the "extra" bytes of an interface
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DescriptorParseError> {
+        let mut remain = bytes;
+
+        // Walk the descriptors, using the bLength field of each
+        while remain.len() >= 2 {
+            let length = remain[0] as usize;
+            if length < 2 || length > remain.len() {
+                return Err(DescriptorParseError::InvalidLength);
+            }
+
+            if remain[1] == DESCRIPTOR_TYPE_DFU_FUNCTIONAL {
+                return Self::parse(&remain[..length]);
+            }
+            remain = &remain[length..];
+        }
+
+        Err(DescriptorParseError::NotFound)
+    }
+
+    /// Finds and parses the functional descriptor of the given DFU interface in a complete
+    /// configuration descriptor. Other classes use the same descriptor type, such as the HID
+    /// class descriptor of a composite device, so only a descriptor following the DFU
+    /// interface descriptor is accepted.
+    pub fn from_config(config: &[u8], interface: u8) -> Result<Self, DescriptorParseError> {
+        let mut remain = config;
+        let mut in_dfu_interface = false;
+
+        while remain.len() >= 2 {
+            let length = remain[0] as usize;
+            if length < 2 || length > remain.len() {
+                return Err(DescriptorParseError::InvalidLength);
+            }
+
+            let desc = &remain[..length];
+            match desc[1] {
+                DESCRIPTOR_TYPE_INTERFACE => {
+                    in_dfu_interface = length >= 9
+                        && desc[2] == interface
+                        && desc[5] == DFU_INTERFACE_CLASS
+                        && desc[6] == DFU_INTERFACE_SUBCLASS;
+                }
+                DESCRIPTOR_TYPE_DFU_FUNCTIONAL if in_dfu_interface => return Self::parse(desc),
+                _ => {}
+            }
+            remain = &remain[length..];
+        }
+
+        Err(DescriptorParseError::NotFound)
+    }
+
+    /// Reads the configuration descriptor through the transport, and parses the functional
+    /// descriptor of the given interface
+    pub fn read<T: DfuTransport>(transport: &mut T, interface: u8) -> Result<Self, DescriptorParseError> {
+        let mut buf = [0u8; 1024];
+        let count = transport
+            .read_descriptor(DESCRIPTOR_TYPE_CONFIG, 0, &mut buf)
+            .map_err(DescriptorParseError::Transport)?;
+        Self::from_config(&buf[..count], interface)
+    }
+
+    /// Parses a single functional descriptor
+    fn parse(desc: &[u8]) -> Result<Self, DescriptorParseError> {
+        // DFU 1.0 devices may leave out bcdDFUVersion, giving 7 bytes
+        if desc.len() < 7 {
+            return Err(DescriptorParseError::InvalidLength);
+        }
+
+        let transfer_size = u16::from_le_bytes([desc[5], desc[6]]);
+        if transfer_size == 0 {
+            return Err(DescriptorParseError::InvalidTransferSize);
+        }
+
+        let dfu_version = if desc.len() >= 9 {
+            u16::from_le_bytes([desc[7], desc[8]])
+        } else {
+            0x0100
+        };
+
+        Ok(DfuFunctionalDescriptor {
+            attributes: DfuAttributes::from_bits_truncate(desc[2]),
+            detach_timeout: u16::from_le_bytes([desc[3], desc[4]]),
+            transfer_size,
+            dfu_version,
+        })
+    }
+
+    /// Checks if the device uses the ST DfuSe protocol extensions
+    pub fn is_dfuse(&self) -> bool {
+        self.dfu_version == DFUSE_VERSION
+    }
+}
+
+impl fmt::Display for DfuFunctionalDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "DFU {:X}.{:X}: Transfer size [{} byte], Detach timeout [{} ms]. Attributes [{}]",
+            self.dfu_version >> 8,
+            self.dfu_version & 0xFF,
+            self.transfer_size,
+            self.detach_timeout,
+            self.attributes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_functional_descriptor() {
+        // As reported by the STM32F4 bootloader
+        let extra = [0x09, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x08, 0x1A, 0x01];
+        let desc = DfuFunctionalDescriptor::from_bytes(&extra).unwrap();
+
+        assert_eq!(
+            DfuAttributes::WILL_DETACH | DfuAttributes::CAN_UPLOAD | DfuAttributes::CAN_DOWNLOAD,
+            desc.attributes
+        );
+        assert_eq!(255, desc.detach_timeout);
+        assert_eq!(2048, desc.transfer_size);
+        assert!(desc.is_dfuse());
+    }
+
+    #[test]
+    fn test_parse_functional_descriptor_after_other_descriptors() {
+        let extra = [0x03, 0x24, 0x00, 0x07, 0x21, 0x05, 0x10, 0x27, 0x40, 0x00];
+        let desc = DfuFunctionalDescriptor::from_bytes(&extra).unwrap();
+
+        assert_eq!(DfuAttributes::CAN_DOWNLOAD | DfuAttributes::MANIFESTATION_TOLERANT, desc.attributes);
+        assert_eq!(10000, desc.detach_timeout);
+        assert_eq!(64, desc.transfer_size);
+        assert!(!desc.is_dfuse());
+
+        assert_eq!(Err(DescriptorParseError::NotFound), DfuFunctionalDescriptor::from_bytes(&extra[..3]));
+        assert_eq!(Err(DescriptorParseError::InvalidLength), DfuFunctionalDescriptor::from_bytes(&[0x09, 0x21, 0x0B]));
+        assert_eq!(
+            Err(DescriptorParseError::InvalidTransferSize),
+            DfuFunctionalDescriptor::from_bytes(&[0x09, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x00, 0x1A, 0x01])
+        );
+    }
+
+    #[test]
+    fn test_read_functional_descriptor() {
+        let mut device = crate::usb::sim::SimDevice::stm32f4();
+        let desc = DfuFunctionalDescriptor::read(&mut device, 0).unwrap();
+
+        assert_eq!(2048, desc.transfer_size);
+        assert!(desc.attributes.contains(DfuAttributes::WILL_DETACH));
+        assert_eq!(Err(DescriptorParseError::NotFound), DfuFunctionalDescriptor::read(&mut device, 1));
+    }
+
+    #[test]
+    fn test_functional_descriptor_of_composite_device() {
+        // A HID interface with its class descriptor, also of type 0x21, before the DFU runtime interface
+        let config = [
+            0x09, 0x02, 0x2D, 0x00, 0x02, 0x01, 0x00, 0xC0, 0x32,
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00,
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x20, 0x00,
+            0x09, 0x04, 0x01, 0x00, 0x00, 0xFE, 0x01, 0x01, 0x00,
+            0x09, 0x21, 0x0B, 0xFF, 0x00, 0x00, 0x04, 0x1A, 0x01,
+        ];
+        let desc = DfuFunctionalDescriptor::from_config(&config, 1).unwrap();
+
+        assert_eq!(1024, desc.transfer_size);
+        assert!(desc.is_dfuse());
+        assert_eq!(Err(DescriptorParseError::NotFound), DfuFunctionalDescriptor::from_config(&config, 0));
+    }
+}
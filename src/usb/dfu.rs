@@ -0,0 +1,794 @@
+//! Implementation of the DFU 1.1 class requests and the device state machine.
+//!
+//! The driver issues the class specific requests (DFU_DNLOAD, DFU_UPLOAD, DFU_GETSTATUS etc.)
+//! over a `DfuTransport`, and walks the state machine defined by the specification:
+//!
+//! ```text
+//! dfuIDLE -> dfuDNLOAD-SYNC -> dfuDNBUSY -> dfuDNLOAD-SYNC -> dfuDNLOAD-IDLE -> ...
+//!         -> dfuMANIFEST-SYNC -> dfuMANIFEST -> dfuIDLE / dfuMANIFEST-WAIT-RESET
+//! ```
+//!
+//! Whenever the device reports a bwPollTimeout, the driver waits at least that long before
+//! sending the next DFU_GETSTATUS request.
+
+use core::fmt;
+
+use super::descriptor::{DfuAttributes, DfuFunctionalDescriptor};
+use super::transport::{DfuTransport, TransportError};
+
+/// bmRequestType for class specific requests to the interface, host to device
+const REQUEST_TYPE_OUT: u8 = 0x21;
+/// bmRequestType for class specific requests to the interface, device to host
+const REQUEST_TYPE_IN: u8 = 0xA1;
+
+/// DFU class request codes
+pub const DFU_DETACH: u8 = 0;
+pub const DFU_DNLOAD: u8 = 1;
+pub const DFU_UPLOAD: u8 = 2;
+pub const DFU_GETSTATUS: u8 = 3;
+pub const DFU_CLRSTATUS: u8 = 4;
+pub const DFU_GETSTATE: u8 = 5;
+pub const DFU_ABORT: u8 = 6;
+
+/// The states of the DFU state machine, as reported by DFU_GETSTATUS and DFU_GETSTATE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DfuDnloadSync = 3,
+    DfuDnBusy = 4,
+    DfuDnloadIdle = 5,
+    DfuManifestSync = 6,
+    DfuManifest = 7,
+    DfuManifestWaitReset = 8,
+    DfuUploadIdle = 9,
+    DfuError = 10,
+}
+
+/// The status codes reported by DFU_GETSTATUS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DfuStatus {
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrCheckErased = 0x05,
+    ErrProg = 0x06,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrFirmware = 0x0A,
+    ErrVendor = 0x0B,
+    ErrUsbr = 0x0C,
+    ErrPor = 0x0D,
+    ErrUnknown = 0x0E,
+    ErrStalledPkt = 0x0F,
+}
+
+/// The decoded response to a DFU_GETSTATUS request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusReport {
+    /// The result of the most recent request
+    pub status: DfuStatus,
+    /// Minimum time in milliseconds to wait before the next DFU_GETSTATUS
+    pub poll_timeout: u32,
+    /// The state the device will enter after this response
+    pub state: DfuState,
+    /// Index of a status description string
+    pub string_index: u8,
+}
+
+/// Errors produced by the DFU driver
+#[derive(Debug, Clone, PartialEq)]
+pub enum DfuError {
+    /// The underlying transport failed
+    Transport(TransportError),
+    /// The device reported an error status
+    Status { status: DfuStatus, state: DfuState },
+    /// The device ended up in a state not valid for the operation
+    UnexpectedState { expected: DfuState, actual: DfuState },
+    /// The device returned a malformed response
+    InvalidResponse,
+    /// The device does not support the operation, according to its functional descriptor
+    NotSupported(DfuAttributes),
+}
+
+impl DfuState {
+    /// Converts the raw bState value into a state
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => DfuState::AppIdle,
+            1 => DfuState::AppDetach,
+            2 => DfuState::DfuIdle,
+            3 => DfuState::DfuDnloadSync,
+            4 => DfuState::DfuDnBusy,
+            5 => DfuState::DfuDnloadIdle,
+            6 => DfuState::DfuManifestSync,
+            7 => DfuState::DfuManifest,
+            8 => DfuState::DfuManifestWaitReset,
+            9 => DfuState::DfuUploadIdle,
+            10 => DfuState::DfuError,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for DfuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DfuState::AppIdle => "appIDLE",
+            DfuState::AppDetach => "appDETACH",
+            DfuState::DfuIdle => "dfuIDLE",
+            DfuState::DfuDnloadSync => "dfuDNLOAD-SYNC",
+            DfuState::DfuDnBusy => "dfuDNBUSY",
+            DfuState::DfuDnloadIdle => "dfuDNLOAD-IDLE",
+            DfuState::DfuManifestSync => "dfuMANIFEST-SYNC",
+            DfuState::DfuManifest => "dfuMANIFEST",
+            DfuState::DfuManifestWaitReset => "dfuMANIFEST-WAIT-RESET",
+            DfuState::DfuUploadIdle => "dfuUPLOAD-IDLE",
+            DfuState::DfuError => "dfuERROR",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl DfuStatus {
+    /// Converts the raw bStatus value into a status
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x00 => DfuStatus::Ok,
+            0x01 => DfuStatus::ErrTarget,
+            0x02 => DfuStatus::ErrFile,
+            0x03 => DfuStatus::ErrWrite,
+            0x04 => DfuStatus::ErrErase,
+            0x05 => DfuStatus::ErrCheckErased,
+            0x06 => DfuStatus::ErrProg,
+            0x07 => DfuStatus::ErrVerify,
+            0x08 => DfuStatus::ErrAddress,
+            0x09 => DfuStatus::ErrNotDone,
+            0x0A => DfuStatus::ErrFirmware,
+            0x0B => DfuStatus::ErrVendor,
+            0x0C => DfuStatus::ErrUsbr,
+            0x0D => DfuStatus::ErrPor,
+            0x0E => DfuStatus::ErrUnknown,
+            0x0F => DfuStatus::ErrStalledPkt,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for DfuStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Descriptions are taken from the DFU 1.1 specification
+        let description = match self {
+            DfuStatus::Ok => "No error condition is present",
+            DfuStatus::ErrTarget => "File is not targeted for use by this device",
+            DfuStatus::ErrFile => "File is for this device but fails some vendor-specific verification test",
+            DfuStatus::ErrWrite => "Device is unable to write memory",
+            DfuStatus::ErrErase => "Memory erase function failed",
+            DfuStatus::ErrCheckErased => "Memory erase check failed",
+            DfuStatus::ErrProg => "Program memory function failed",
+            DfuStatus::ErrVerify => "Programmed memory failed verification",
+            DfuStatus::ErrAddress => "Cannot program memory due to received address that is out of range",
+            DfuStatus::ErrNotDone => "Received DFU_DNLOAD with wLength = 0, but device does not think it has all of the data yet",
+            DfuStatus::ErrFirmware => "Device's firmware is corrupt. It cannot return to run-time (non-DFU) operations",
+            DfuStatus::ErrVendor => "iString indicates a vendor-specific error",
+            DfuStatus::ErrUsbr => "Device detected unexpected USB reset signaling",
+            DfuStatus::ErrPor => "Device detected unexpected power on reset",
+            DfuStatus::ErrUnknown => "Something went wrong, but the device does not know what it was",
+            DfuStatus::ErrStalledPkt => "Device stalled an unexpected request",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl StatusReport {
+    /// Decodes the 6 byte DFU_GETSTATUS response
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DfuError> {
+        if bytes.len() < 6 {
+            return Err(DfuError::InvalidResponse);
+        }
+
+        let status = DfuStatus::from_u8(bytes[0]).ok_or(DfuError::InvalidResponse)?;
+        let state = DfuState::from_u8(bytes[4]).ok_or(DfuError::InvalidResponse)?;
+
+        // bwPollTimeout is a 3 byte little endian value
+        let poll_timeout = u32::from(bytes[1]) | u32::from(bytes[2]) << 8 | u32::from(bytes[3]) << 16;
+
+        Ok(StatusReport {
+            status,
+            poll_timeout,
+            state,
+            string_index: bytes[5],
+        })
+    }
+}
+
+impl fmt::Display for DfuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DfuError::Transport(e) => write!(f, "USB transfer failed: {}", e),
+            DfuError::Status { status, state } => {
+                write!(f, "Device reported error in state {}: {}", state, status)
+            }
+            DfuError::UnexpectedState { expected, actual } => {
+                write!(f, "Device is in state {}, expected {}", actual, expected)
+            }
+            DfuError::InvalidResponse => write!(f, "Device returned an invalid response"),
+            DfuError::NotSupported(attributes) => write!(f, "Device does not support [{}]", attributes),
+        }
+    }
+}
+
+impl From<TransportError> for DfuError {
+    fn from(e: TransportError) -> Self {
+        DfuError::Transport(e)
+    }
+}
+
+/// Driver for a single DFU interface
+pub struct Dfu<T: DfuTransport> {
+    /// The transport used to reach the device
+    transport: T,
+    /// The interface number of the DFU interface
+    interface: u16,
+    /// Maximum number of bytes per DFU_DNLOAD / DFU_UPLOAD request
+    transfer_size: usize,
+    /// The capabilities of the device
+    attributes: DfuAttributes,
+    /// Time in milliseconds the device waits for a reset after DFU_DETACH
+    detach_timeout: u16,
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Creates a new driver for the given interface. The device is assumed to support
+    /// both download and upload, and to be manifestation tolerant.
+    /// # Arguments
+    /// * `transport` - The transport to communicate through
+    /// * `interface` - The interface number of the DFU interface
+    /// * `transfer_size` - Maximum number of bytes per transfer (wTransferSize)
+    pub fn new(transport: T, interface: u16, transfer_size: usize) -> Self {
+        Dfu {
+            transport,
+            interface,
+            transfer_size,
+            attributes: DfuAttributes::CAN_DOWNLOAD
+                | DfuAttributes::CAN_UPLOAD
+                | DfuAttributes::MANIFESTATION_TOLERANT,
+            detach_timeout: 1000,
+        }
+    }
+
+    /// Creates a new driver for the given interface, using the transfer size, attributes and
+    /// detach timeout from the functional descriptor of the device
+    pub fn from_descriptor(transport: T, interface: u16, descriptor: &DfuFunctionalDescriptor) -> Self {
+        Dfu {
+            transport,
+            interface,
+            transfer_size: descriptor.transfer_size as usize,
+            attributes: descriptor.attributes,
+            detach_timeout: descriptor.detach_timeout,
+        }
+    }
+
+    /// Returns the maximum number of bytes per transfer
+    pub fn transfer_size(&self) -> usize {
+        self.transfer_size
+    }
+
+    /// Returns the capabilities of the device
+    pub fn attributes(&self) -> DfuAttributes {
+        self.attributes
+    }
+
+    /// Fails with `DfuError::NotSupported` unless the device has the given attributes
+    fn require(&self, attributes: DfuAttributes) -> Result<(), DfuError> {
+        if !self.attributes.contains(attributes) {
+            return Err(DfuError::NotSupported(attributes));
+        }
+        Ok(())
+    }
+
+    /// Gives access to the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consumes the driver, returning the transport
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Sends DFU_DETACH, requesting a run-time device to enter DFU mode
+    /// # Arguments
+    /// * `timeout_ms` - Time the device should wait for a USB reset before giving up
+    pub fn detach(&mut self, timeout_ms: u16) -> Result<(), DfuError> {
+        self.transport
+            .control_out(REQUEST_TYPE_OUT, DFU_DETACH, timeout_ms, self.interface, &[])?;
+        Ok(())
+    }
+
+    /// Requests a run-time device to enter DFU mode, using the detach timeout from the
+    /// functional descriptor. Devices which do not detach by themselves are reset.
+    pub fn detach_device(&mut self) -> Result<(), DfuError> {
+        // A device may leave the bus before completing the request
+        match self.detach(self.detach_timeout) {
+            Ok(()) | Err(DfuError::Transport(TransportError::NoDevice)) => {}
+            Err(e) => return Err(e),
+        }
+
+        if !self.attributes.contains(DfuAttributes::WILL_DETACH) {
+            self.reset_device()?;
+        }
+        Ok(())
+    }
+
+    /// Issues a USB reset, ignoring that the device drops off the bus as a result
+    fn reset_device(&mut self) -> Result<(), DfuError> {
+        match self.transport.reset() {
+            Ok(()) | Err(TransportError::NoDevice) => Ok(()),
+            Err(e) => Err(DfuError::Transport(e)),
+        }
+    }
+
+    /// Sends a single DFU_DNLOAD request, without waiting for the device to process it
+    pub fn dnload(&mut self, block: u16, data: &[u8]) -> Result<(), DfuError> {
+        self.require(DfuAttributes::CAN_DOWNLOAD)?;
+        self.transport
+            .control_out(REQUEST_TYPE_OUT, DFU_DNLOAD, block, self.interface, data)?;
+        Ok(())
+    }
+
+    /// Sends a single DFU_UPLOAD request
+    /// # Return
+    /// The number of bytes received into `buf`
+    pub fn upload(&mut self, block: u16, buf: &mut [u8]) -> Result<usize, DfuError> {
+        self.require(DfuAttributes::CAN_UPLOAD)?;
+        let count = self
+            .transport
+            .control_in(REQUEST_TYPE_IN, DFU_UPLOAD, block, self.interface, buf)?;
+        Ok(count)
+    }
+
+    /// Sends DFU_GETSTATUS, and returns the decoded report
+    pub fn get_status(&mut self) -> Result<StatusReport, DfuError> {
+        let mut buf = [0u8; 6];
+        let count = self
+            .transport
+            .control_in(REQUEST_TYPE_IN, DFU_GETSTATUS, 0, self.interface, &mut buf)?;
+        StatusReport::from_bytes(&buf[..count])
+    }
+
+    /// Sends DFU_CLRSTATUS, moving the device from dfuERROR to dfuIDLE
+    pub fn clear_status(&mut self) -> Result<(), DfuError> {
+        self.transport
+            .control_out(REQUEST_TYPE_OUT, DFU_CLRSTATUS, 0, self.interface, &[])?;
+        Ok(())
+    }
+
+    /// Sends DFU_GETSTATE, and returns the current state
+    pub fn get_state(&mut self) -> Result<DfuState, DfuError> {
+        let mut buf = [0u8; 1];
+        let count = self
+            .transport
+            .control_in(REQUEST_TYPE_IN, DFU_GETSTATE, 0, self.interface, &mut buf)?;
+        if count != 1 {
+            return Err(DfuError::InvalidResponse);
+        }
+        DfuState::from_u8(buf[0]).ok_or(DfuError::InvalidResponse)
+    }
+
+    /// Sends DFU_ABORT, returning the device to dfuIDLE
+    pub fn abort(&mut self) -> Result<(), DfuError> {
+        self.transport
+            .control_out(REQUEST_TYPE_OUT, DFU_ABORT, 0, self.interface, &[])?;
+        Ok(())
+    }
+
+    /// Polls DFU_GETSTATUS until the device leaves the synchronization and busy states,
+    /// waiting bwPollTimeout between every request.
+    /// # Return
+    /// The first report with a settled state, or an error if the device reports an error status
+    pub fn poll_status(&mut self) -> Result<StatusReport, DfuError> {
+        let mut report = self.get_status()?;
+
+        loop {
+            if report.status != DfuStatus::Ok {
+                return Err(DfuError::Status {
+                    status: report.status,
+                    state: report.state,
+                });
+            }
+
+            match report.state {
+                DfuState::DfuDnloadSync
+                | DfuState::DfuDnBusy
+                | DfuState::DfuManifestSync
+                | DfuState::DfuManifest => {
+                    self.transport.delay(report.poll_timeout);
+                    report = self.get_status()?;
+                }
+                _ => return Ok(report),
+            }
+        }
+    }
+
+    /// Brings the device into dfuIDLE, clearing any error condition and aborting
+    /// any unfinished transfer.
+    pub fn ensure_idle(&mut self) -> Result<(), DfuError> {
+        let report = self.get_status()?;
+
+        match report.state {
+            DfuState::DfuIdle => return Ok(()),
+            DfuState::DfuError => self.clear_status()?,
+            DfuState::AppIdle | DfuState::AppDetach => {
+                return Err(DfuError::UnexpectedState {
+                    expected: DfuState::DfuIdle,
+                    actual: report.state,
+                })
+            }
+            _ => self.abort()?,
+        }
+
+        // Verify that the device actually went idle
+        let state = self.get_state()?;
+        if state != DfuState::DfuIdle {
+            return Err(DfuError::UnexpectedState {
+                expected: DfuState::DfuIdle,
+                actual: state,
+            });
+        }
+        Ok(())
+    }
+
+    /// Downloads a single block, and waits for the device to finish processing it
+    pub fn download_block(&mut self, block: u16, data: &[u8]) -> Result<StatusReport, DfuError> {
+        self.dnload(block, data)?;
+        let report = self.poll_status()?;
+
+        if report.state != DfuState::DfuDnloadIdle {
+            return Err(DfuError::UnexpectedState {
+                expected: DfuState::DfuDnloadIdle,
+                actual: report.state,
+            });
+        }
+        Ok(report)
+    }
+
+    /// Ends a download by sending a zero length DFU_DNLOAD, and waits for the
+    /// manifestation phase to complete. A manifestation intolerant device which
+    /// does not detach by itself is reset afterwards.
+    /// # Arguments
+    /// * `block` - The block number to use for the zero length request
+    pub fn manifest(&mut self, block: u16) -> Result<(), DfuError> {
+        self.dnload(block, &[])?;
+
+        let tolerant = self.attributes.contains(DfuAttributes::MANIFESTATION_TOLERANT);
+        let expected = if tolerant {
+            DfuState::DfuIdle
+        } else {
+            DfuState::DfuManifestWaitReset
+        };
+
+        match self.poll_status() {
+            Ok(report) if report.state == expected => {}
+            // Not all devices agree on their tolerance, so accept both end states
+            Ok(report) if report.state == DfuState::DfuIdle => return Ok(()),
+            Ok(report) if report.state == DfuState::DfuManifestWaitReset => {}
+            Ok(report) => {
+                return Err(DfuError::UnexpectedState {
+                    expected,
+                    actual: report.state,
+                })
+            }
+            // A device may leave the bus as part of manifestation
+            Err(DfuError::Transport(TransportError::NoDevice)) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        if !tolerant && !self.attributes.contains(DfuAttributes::WILL_DETACH) {
+            self.reset_device()?;
+        }
+        Ok(())
+    }
+
+    /// Downloads the complete firmware to the device, split in transfer size chunks,
+    /// followed by the manifestation phase.
+    pub fn download(&mut self, data: &[u8]) -> Result<(), DfuError> {
+        self.ensure_idle()?;
+
+        let mut block: u16 = 0;
+        for chunk in data.chunks(self.transfer_size) {
+            self.download_block(block, chunk)?;
+            block = block.wrapping_add(1);
+        }
+
+        self.manifest(block)
+    }
+
+    /// Uploads the firmware from the device, until the device sends a short frame
+    /// or `max_length` bytes has been read.
+    pub fn upload_all(&mut self, max_length: usize) -> Result<Vec<u8>, DfuError> {
+        self.ensure_idle()?;
+
+        let mut data: Vec<u8> = Vec::new();
+        let mut buf = vec![0u8; self.transfer_size];
+        let mut block: u16 = 0;
+
+        while data.len() < max_length {
+            let request = self.transfer_size.min(max_length - data.len());
+            let count = self.upload(block, &mut buf[..request])?;
+            data.extend_from_slice(&buf[..count]);
+
+            // A short frame ends the upload, and the device returns to dfuIDLE by itself
+            if count < request {
+                return Ok(data);
+            }
+            block = block.wrapping_add(1);
+        }
+
+        // We stopped before the device did, so abort the upload
+        self.abort()?;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal in-process DFU device, storing downloaded data in memory.
+    /// Every block takes `busy_ms` to program, and the device refuses to answer
+    /// a status request before the poll timeout has passed.
+    struct FakeDevice {
+        state: DfuState,
+        status: DfuStatus,
+        memory: Vec<u8>,
+        pending: Vec<u8>,
+        busy_ms: u32,
+        elapsed_ms: u32,
+        ready_at_ms: u32,
+        early_polls: usize,
+        fail_block: Option<u16>,
+    }
+
+    impl FakeDevice {
+        fn new(busy_ms: u32) -> Self {
+            FakeDevice {
+                state: DfuState::DfuIdle,
+                status: DfuStatus::Ok,
+                memory: Vec::new(),
+                pending: Vec::new(),
+                busy_ms,
+                elapsed_ms: 0,
+                ready_at_ms: 0,
+                early_polls: 0,
+                fail_block: None,
+            }
+        }
+
+        fn fail(&mut self, status: DfuStatus) {
+            self.status = status;
+            self.state = DfuState::DfuError;
+        }
+
+        fn status_bytes(&self, poll_timeout: u32) -> [u8; 6] {
+            [
+                self.status as u8,
+                poll_timeout as u8,
+                (poll_timeout >> 8) as u8,
+                (poll_timeout >> 16) as u8,
+                self.state as u8,
+                0,
+            ]
+        }
+    }
+
+    impl DfuTransport for FakeDevice {
+        fn control_out(&mut self, _rt: u8, request: u8, value: u16, _ix: u16, data: &[u8]) -> Result<usize, TransportError> {
+            match (request, self.state) {
+                (DFU_DNLOAD, DfuState::DfuIdle) | (DFU_DNLOAD, DfuState::DfuDnloadIdle) => {
+                    if data.is_empty() {
+                        self.state = DfuState::DfuManifestSync;
+                    } else if self.fail_block == Some(value) {
+                        self.fail(DfuStatus::ErrWrite);
+                    } else {
+                        self.pending = data.to_vec();
+                        self.state = DfuState::DfuDnloadSync;
+                    }
+                }
+                (DFU_CLRSTATUS, DfuState::DfuError) => {
+                    self.status = DfuStatus::Ok;
+                    self.state = DfuState::DfuIdle;
+                }
+                (DFU_ABORT, _) => {
+                    self.pending.clear();
+                    self.state = DfuState::DfuIdle;
+                }
+                _ => {
+                    self.fail(DfuStatus::ErrStalledPkt);
+                    return Err(TransportError::Pipe);
+                }
+            }
+            Ok(data.len())
+        }
+
+        fn control_in(&mut self, _rt: u8, request: u8, _value: u16, _ix: u16, buf: &mut [u8]) -> Result<usize, TransportError> {
+            match request {
+                DFU_GETSTATUS => {
+                    let poll_timeout = match self.state {
+                        DfuState::DfuDnloadSync => {
+                            self.state = DfuState::DfuDnBusy;
+                            self.ready_at_ms = self.elapsed_ms + self.busy_ms;
+                            self.busy_ms
+                        }
+                        DfuState::DfuDnBusy => {
+                            if self.elapsed_ms < self.ready_at_ms {
+                                self.early_polls += 1;
+                                self.ready_at_ms - self.elapsed_ms
+                            } else {
+                                let pending = std::mem::take(&mut self.pending);
+                                self.memory.extend_from_slice(&pending);
+                                self.state = DfuState::DfuDnloadIdle;
+                                0
+                            }
+                        }
+                        DfuState::DfuManifestSync => {
+                            self.state = DfuState::DfuManifest;
+                            self.busy_ms
+                        }
+                        DfuState::DfuManifest => {
+                            self.state = DfuState::DfuIdle;
+                            0
+                        }
+                        _ => 0,
+                    };
+                    let bytes = self.status_bytes(poll_timeout);
+                    buf[..6].copy_from_slice(&bytes);
+                    Ok(6)
+                }
+                DFU_GETSTATE => {
+                    buf[0] = self.state as u8;
+                    Ok(1)
+                }
+                DFU_UPLOAD => {
+                    // Serve the memory in order, ending with a short frame
+                    let offset = self.pending.len();
+                    let count = buf.len().min(self.memory.len() - offset);
+                    buf[..count].copy_from_slice(&self.memory[offset..offset + count]);
+                    self.pending.extend_from_slice(&self.memory[offset..offset + count]);
+                    self.state = if count < buf.len() {
+                        self.pending.clear();
+                        DfuState::DfuIdle
+                    } else {
+                        DfuState::DfuUploadIdle
+                    };
+                    Ok(count)
+                }
+                _ => Err(TransportError::Pipe),
+            }
+        }
+
+        fn delay(&mut self, ms: u32) {
+            self.elapsed_ms += ms;
+        }
+    }
+
+    #[test]
+    fn test_status_report_from_bytes() {
+        let report = StatusReport::from_bytes(&[0x00, 0x10, 0x27, 0x00, 0x04, 0x00]).unwrap();
+        assert_eq!(DfuStatus::Ok, report.status);
+        assert_eq!(10000, report.poll_timeout);
+        assert_eq!(DfuState::DfuDnBusy, report.state);
+
+        assert_eq!(Err(DfuError::InvalidResponse), StatusReport::from_bytes(&[0x00, 0x00]));
+        assert_eq!(
+            Err(DfuError::InvalidResponse),
+            StatusReport::from_bytes(&[0x00, 0x00, 0x00, 0x00, 0x0B, 0x00])
+        );
+    }
+
+    #[test]
+    fn test_download_honors_poll_timeout() {
+        let mut device = FakeDevice::new(25);
+        let firmware: Vec<u8> = (0..1000u32).map(|v| v as u8).collect();
+
+        let mut dfu = Dfu::new(&mut device, 0, 64);
+        dfu.download(&firmware).expect("Download failed");
+
+        assert_eq!(firmware, device.memory);
+        assert_eq!(DfuState::DfuIdle, device.state);
+        assert_eq!(0, device.early_polls);
+        // 16 blocks and the manifestation phase, each waiting the busy time
+        assert_eq!(17 * 25, device.elapsed_ms);
+    }
+
+    #[test]
+    fn test_download_recovers_from_error_state() {
+        let mut device = FakeDevice::new(1);
+        device.fail(DfuStatus::ErrUnknown);
+
+        let mut dfu = Dfu::new(&mut device, 0, 16);
+        dfu.download(&[0xAA; 40]).expect("Download failed");
+
+        assert_eq!(vec![0xAA; 40], device.memory);
+    }
+
+    #[test]
+    fn test_download_reports_device_error() {
+        let mut device = FakeDevice::new(1);
+        device.fail_block = Some(2);
+
+        let mut dfu = Dfu::new(&mut device, 0, 16);
+        let result = dfu.download(&[0x55; 64]);
+
+        assert_eq!(
+            Err(DfuError::Status {
+                status: DfuStatus::ErrWrite,
+                state: DfuState::DfuError
+            }),
+            result
+        );
+        assert_eq!(32, device.memory.len());
+    }
+
+    #[test]
+    fn test_descriptor_limits_operations() {
+        let mut device = FakeDevice::new(1);
+        device.memory = vec![0x11; 8];
+
+        let descriptor = DfuFunctionalDescriptor {
+            attributes: DfuAttributes::CAN_DOWNLOAD | DfuAttributes::MANIFESTATION_TOLERANT,
+            detach_timeout: 100,
+            transfer_size: 4,
+            dfu_version: 0x0110,
+        };
+        let mut dfu = Dfu::from_descriptor(&mut device, 0, &descriptor);
+
+        assert_eq!(4, dfu.transfer_size());
+        assert_eq!(Err(DfuError::NotSupported(DfuAttributes::CAN_UPLOAD)), dfu.upload_all(8));
+
+        dfu.download(&[0x22; 10]).unwrap();
+        assert_eq!(vec![0x22; 10], device.memory[8..]);
+    }
+
+    #[test]
+    fn test_upload_all() {
+        let mut device = FakeDevice::new(1);
+        device.memory = (0..100u8).collect();
+
+        // Upload ends by a short frame
+        let mut dfu = Dfu::new(&mut device, 0, 32);
+        assert_eq!((0..100u8).collect::<Vec<u8>>(), dfu.upload_all(1000).unwrap());
+        assert_eq!(DfuState::DfuIdle, dfu.get_state().unwrap());
+
+        // Upload ends by length, and is aborted by the host
+        assert_eq!((0..64u8).collect::<Vec<u8>>(), dfu.upload_all(64).unwrap());
+        assert_eq!(DfuState::DfuIdle, dfu.get_state().unwrap());
+    }
+
+    #[test]
+    fn test_detach_runtime_device() {
+        // Without bitWillDetach the host resets the device
+        let mut device = crate::usb::sim::SimDevice::stm32f4();
+        device.attributes = 0x03;
+        device.set_runtime_mode();
+        let descriptor = DfuFunctionalDescriptor::read(&mut device, 0).unwrap();
+
+        let mut dfu = Dfu::from_descriptor(&mut device, 0, &descriptor);
+        assert_eq!(DfuState::AppIdle, dfu.get_state().unwrap());
+        dfu.detach_device().unwrap();
+        assert!(device.is_detached());
+
+        // With bitWillDetach the device leaves the bus by itself
+        let mut device = crate::usb::sim::SimDevice::stm32f4();
+        device.set_runtime_mode();
+        let descriptor = DfuFunctionalDescriptor::read(&mut device, 0).unwrap();
+
+        Dfu::from_descriptor(&mut device, 0, &descriptor).detach_device().unwrap();
+        assert!(device.is_detached());
+        assert_eq!(DfuState::AppIdle, device.state());
+    }
+}
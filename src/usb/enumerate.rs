@@ -0,0 +1,445 @@
+//! Enumeration of the DFU capable USB devices.
+//!
+//! A device is DFU capable when it has an interface with class 0xFE (application specific)
+//! and subclass 0x01 (DFU). The protocol tells if the device runs its application with a
+//! DFU runtime interface (1), or is in DFU mode (2). In DFU mode, each alt setting of the
+//! interface gives access to one memory, and the interface string of the alt setting
+//! describes it (see `stm32dfu::parse_memory_layout_string`).
+
+use core::fmt;
+use rusb::{Device, GlobalContext};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::descriptor::DfuFunctionalDescriptor;
+use super::stm32dfu::parse_memory_layout_string;
+use super::transport::TransportError;
+
+/// Time between the enumerations while waiting for a device
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Interface class, subclass and protocols of DFU interfaces
+pub const DFU_INTERFACE_CLASS: u8 = 0xFE;
+pub const DFU_INTERFACE_SUBCLASS: u8 = 0x01;
+pub const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+pub const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+
+/// An alt setting of a DFU interface
+#[derive(Debug, Clone, PartialEq)]
+pub struct AltSetting {
+    /// bAlternateSetting
+    pub alt: u8,
+    /// The interface string, empty if it could not be read
+    pub name: String,
+}
+
+/// The description of a DFU capable device, as found during enumeration
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuDevice {
+    /// The bus number
+    pub bus: u8,
+    /// The address on the bus
+    pub address: u8,
+    /// The port numbers from the root hub to the device
+    pub port_numbers: Vec<u8>,
+    /// idVendor
+    pub vendor_id: u16,
+    /// idProduct
+    pub product_id: u16,
+    /// The manufacturer string, if any
+    pub manufacturer: Option<String>,
+    /// The product string, if any
+    pub product: Option<String>,
+    /// The serial number string, if any
+    pub serial: Option<String>,
+    /// The number of the DFU interface
+    pub interface: u8,
+    /// The protocol of the DFU interface, see `DFU_PROTOCOL_RUNTIME` and `DFU_PROTOCOL_DFU_MODE`
+    pub protocol: u8,
+    /// The alt settings of the DFU interface
+    pub alt_settings: Vec<AltSetting>,
+    /// The functional descriptor, if given with the interface
+    pub descriptor: Option<DfuFunctionalDescriptor>,
+}
+
+impl DfuDevice {
+    /// Returns the port path on the format "bus-port.port", as used by Linux in sysfs
+    pub fn path(&self) -> String {
+        let ports: Vec<String> = self.port_numbers.iter().map(u8::to_string).collect();
+        format!("{}-{}", self.bus, ports.join("."))
+    }
+
+    /// Checks if the device runs its application, and must be detached to enter DFU mode
+    pub fn is_runtime(&self) -> bool {
+        self.protocol == DFU_PROTOCOL_RUNTIME
+    }
+
+    /// Checks if this DFU mode device is the given run-time device, after it was detached.
+    /// The IDs often change when entering DFU mode, so the device is tracked by the serial
+    /// number, or by the port path when the run-time device has no serial number.
+    pub fn is_reenumeration_of(&self, runtime: &DfuDevice) -> bool {
+        if self.is_runtime() {
+            return false;
+        }
+        match runtime.serial.as_deref() {
+            Some(serial) if !serial.is_empty() => self.serial.as_deref() == Some(serial),
+            _ => self.path() == runtime.path(),
+        }
+    }
+}
+
+impl fmt::Display for DfuDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ID [{:04x}:{:04x}] Path [{}] Serial [{}]: {} {}",
+            self.vendor_id,
+            self.product_id,
+            self.path(),
+            self.serial.as_deref().unwrap_or(""),
+            self.manufacturer.as_deref().unwrap_or(""),
+            self.product.as_deref().unwrap_or("")
+        )?;
+        if self.is_runtime() {
+            write!(f, " (runtime mode)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects devices and alt settings. Fields which are not set match anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceFilter {
+    /// idVendor of the device
+    pub vendor_id: Option<u16>,
+    /// idProduct of the device
+    pub product_id: Option<u16>,
+    /// The serial number string of the device
+    pub serial: Option<String>,
+    /// The port path of the device, see `DfuDevice::path`
+    pub path: Option<String>,
+    /// The number of the alt setting
+    pub alt: Option<u8>,
+    /// The interface string of the alt setting, or the name of its memory layout
+    pub alt_name: Option<String>,
+}
+
+/// Errors that can occur when selecting a device
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectError {
+    /// The vendor and product ID is not on the format "vid:pid"
+    InvalidId(String),
+    /// No device matches the filter. The devices found are given.
+    NoMatch(Vec<DfuDevice>),
+    /// Several devices match the filter. The candidates are given.
+    Ambiguous(Vec<DfuDevice>),
+    /// The detached device did not show up in DFU mode in time
+    Timeout,
+}
+
+impl fmt::Display for SelectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (message, devices) = match self {
+            SelectError::InvalidId(id) => return write!(f, "Invalid device ID [{}], expected vid:pid in hex", id),
+            SelectError::NoMatch(devices) if devices.is_empty() => return write!(f, "No DFU capable device found"),
+            SelectError::NoMatch(devices) => ("No DFU capable device matches the selection. Devices found:", devices),
+            SelectError::Ambiguous(devices) => ("Several devices match the selection, select one of:", devices),
+            SelectError::Timeout => return write!(f, "Timeout waiting for the device to enter DFU mode"),
+        };
+
+        write!(f, "{}", message)?;
+        for device in devices {
+            write!(f, "\n - {}", device)?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceFilter {
+    /// Sets the vendor and product ID from a string on the format "vid:pid", with the IDs in hex.
+    /// Either ID may be left empty or given as "*" to match any.
+    pub fn set_id(&mut self, id: &str) -> Result<(), SelectError> {
+        let invalid = || SelectError::InvalidId(id.to_string());
+        let parse = |part: &str| match part {
+            "" | "*" => Ok(None),
+            hex => u16::from_str_radix(hex.trim_start_matches("0x"), 16).map(Some).map_err(|_| invalid()),
+        };
+
+        let mut parts = id.splitn(2, ':');
+        self.vendor_id = parse(parts.next().unwrap_or(""))?;
+        self.product_id = parse(parts.next().ok_or_else(invalid)?)?;
+        Ok(())
+    }
+
+    /// Checks if the device, and one of its alt settings, matches the filter. The alt settings
+    /// of run-time devices are only known after detaching, so they are not checked.
+    pub fn matches(&self, device: &DfuDevice) -> bool {
+        self.vendor_id.map_or(true, |id| id == device.vendor_id)
+            && self.product_id.map_or(true, |id| id == device.product_id)
+            && self.serial.as_ref().map_or(true, |serial| device.serial.as_ref() == Some(serial))
+            && self.path.as_ref().map_or(true, |path| *path == device.path())
+            && (device.is_runtime() || self.find_alt(device).is_some())
+    }
+
+    /// Finds the alt setting of the device matching the filter. The first alt setting is used
+    /// when the filter does not give one.
+    pub fn find_alt(&self, device: &DfuDevice) -> Option<u8> {
+        device
+            .alt_settings
+            .iter()
+            .find(|alt| {
+                self.alt.map_or(true, |number| number == alt.alt)
+                    && self.alt_name.as_ref().map_or(true, |name| {
+                        alt.name == *name
+                            || parse_memory_layout_string(&alt.name).map_or(false, |map| map.name == name.trim())
+                    })
+            })
+            .map(|alt| alt.alt)
+    }
+
+    /// Selects the single device matching the filter
+    /// # Arguments
+    /// * `devices` - The devices to select from
+    ///
+    /// # Return
+    /// The index of the selected device
+    pub fn select<'a, I: IntoIterator<Item = &'a DfuDevice>>(&self, devices: I) -> Result<usize, SelectError> {
+        let devices: Vec<&DfuDevice> = devices.into_iter().collect();
+        let matching: Vec<usize> = (0..devices.len()).filter(|i| self.matches(devices[*i])).collect();
+
+        match matching[..] {
+            [index] => Ok(index),
+            [] => Err(SelectError::NoMatch(devices.into_iter().cloned().collect())),
+            _ => Err(SelectError::Ambiguous(matching.iter().map(|i| devices[*i].clone()).collect())),
+        }
+    }
+}
+
+/// Finds all the DFU capable devices, and reads their strings
+///
+/// # Return
+/// The USB device, and the description of it, for each DFU capable device
+pub fn find_devices() -> Result<Vec<(Device<GlobalContext>, DfuDevice)>, TransportError> {
+    let mut found = Vec::new();
+
+    for device in rusb::devices()?.iter() {
+        // Devices which can not be described are skipped
+        let device_desc = match device.device_descriptor() {
+            Ok(desc) => desc,
+            Err(_) => continue,
+        };
+        let config = match device.active_config_descriptor() {
+            Ok(config) => config,
+            Err(_) => continue,
+        };
+
+        // Find the first DFU interface
+        let dfu_interface = config.interfaces().find(|interface| {
+            interface
+                .descriptors()
+                .any(|d| d.class_code() == DFU_INTERFACE_CLASS && d.sub_class_code() == DFU_INTERFACE_SUBCLASS)
+        });
+        let interface = match dfu_interface {
+            Some(interface) => interface,
+            None => continue,
+        };
+
+        // The strings can only be read when the device can be opened
+        let handle = device.open().ok();
+        let read_string = |index: Option<u8>| {
+            let handle = handle.as_ref()?;
+            handle.read_string_descriptor_ascii(index?).ok()
+        };
+
+        let mut dfu_device = DfuDevice {
+            bus: device.bus_number(),
+            address: device.address(),
+            port_numbers: device.port_numbers().unwrap_or_default(),
+            vendor_id: device_desc.vendor_id(),
+            product_id: device_desc.product_id(),
+            manufacturer: read_string(device_desc.manufacturer_string_index()),
+            product: read_string(device_desc.product_string_index()),
+            serial: read_string(device_desc.serial_number_string_index()),
+            interface: interface.number(),
+            protocol: 0,
+            alt_settings: Vec::new(),
+            descriptor: None,
+        };
+
+        for if_desc in interface.descriptors() {
+            dfu_device.protocol = if_desc.protocol_code();
+            dfu_device.alt_settings.push(AltSetting {
+                alt: if_desc.setting_number(),
+                name: read_string(if_desc.description_string_index()).unwrap_or_default(),
+            });
+
+            // The functional descriptor is given as extra bytes of the interface
+            if let Ok(func_desc) = DfuFunctionalDescriptor::from_bytes(if_desc.extra().unwrap_or(&[])) {
+                dfu_device.descriptor = Some(func_desc);
+            }
+        }
+
+        found.push((device, dfu_device));
+    }
+
+    Ok(found)
+}
+
+/// Waits for a detached run-time device to show up in DFU mode
+/// # Arguments
+/// * `runtime` - The run-time device which was detached
+/// * `timeout` - How long to wait
+///
+/// # Return
+/// The USB device, and the description of it, in DFU mode
+pub fn wait_for_dfu_mode(runtime: &DfuDevice, timeout: Duration) -> Result<(Device<GlobalContext>, DfuDevice), SelectError> {
+    wait_for_device(|d| d.is_reenumeration_of(runtime), timeout)
+}
+
+/// Waits for a DFU mode device to leave the bus and show up again, such as after a reset.
+/// The device is known to be new when it has another bus address.
+/// # Arguments
+/// * `device` - The device which was reset
+/// * `timeout` - How long to wait
+///
+/// # Return
+/// The USB device, and the description of it, after the reset
+pub fn wait_for_reenumeration(device: &DfuDevice, timeout: Duration) -> Result<(Device<GlobalContext>, DfuDevice), SelectError> {
+    wait_for_device(
+        |d| d.is_reenumeration_of(device) && (d.bus, d.address) != (device.bus, device.address),
+        timeout,
+    )
+}
+
+/// Polls the DFU devices until one matches the predicate
+fn wait_for_device<P: Fn(&DfuDevice) -> bool>(predicate: P, timeout: Duration) -> Result<(Device<GlobalContext>, DfuDevice), SelectError> {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        thread::sleep(POLL_INTERVAL);
+
+        // The bus may be in flux while the device re-enumerates, so errors are retried
+        if let Ok(devices) = find_devices() {
+            if let Some(found) = devices.into_iter().find(|(_, d)| predicate(d)) {
+                return Ok(found);
+            }
+        }
+    }
+    Err(SelectError::Timeout)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates a device description for tests
+    pub(crate) fn test_device(vendor_id: u16, product_id: u16, serial: &str, port_numbers: &[u8], alts: &[&str]) -> DfuDevice {
+        DfuDevice {
+            bus: 1,
+            address: 10,
+            port_numbers: port_numbers.to_vec(),
+            vendor_id,
+            product_id,
+            manufacturer: Some("STMicroelectronics".to_string()),
+            product: Some("STM32  BOOTLOADER".to_string()),
+            serial: Some(serial.to_string()),
+            interface: 0,
+            protocol: DFU_PROTOCOL_DFU_MODE,
+            alt_settings: alts
+                .iter()
+                .enumerate()
+                .map(|(alt, name)| AltSetting {
+                    alt: alt as u8,
+                    name: name.to_string(),
+                })
+                .collect(),
+            descriptor: None,
+        }
+    }
+
+    #[test]
+    fn test_device_path() {
+        let device = test_device(0x0483, 0xDF11, "348435943539", &[2, 1, 4], &[]);
+        assert_eq!("1-2.1.4", device.path());
+        assert!(!device.is_runtime());
+        assert_eq!(
+            "ID [0483:df11] Path [1-2.1.4] Serial [348435943539]: STMicroelectronics STM32  BOOTLOADER",
+            device.to_string()
+        );
+    }
+
+    #[test]
+    fn test_select_device() {
+        let flash = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,03*128Kg";
+        let devices = vec![
+            test_device(0x0483, 0xDF11, "AAAA", &[1], &[flash, "@Option Bytes  /0x1FFFC000/01*016 e"]),
+            test_device(0x0483, 0xDF11, "BBBB", &[2, 3], &[flash]),
+            test_device(0x1209, 0x0001, "CCCC", &[4], &["Firmware"]),
+        ];
+
+        // Nothing selected gives all the devices as candidates
+        let filter = DeviceFilter::default();
+        assert!(matches!(filter.select(&devices), Err(SelectError::Ambiguous(d)) if d.len() == 3));
+        assert_eq!(Ok(0), filter.select(&devices[..1]));
+        assert_eq!(Err(SelectError::NoMatch(vec![])), filter.select(&[]));
+
+        let mut filter = DeviceFilter::default();
+        filter.set_id("0483:df11").unwrap();
+        assert!(matches!(filter.select(&devices), Err(SelectError::Ambiguous(d)) if d.len() == 2));
+
+        filter.serial = Some("BBBB".to_string());
+        assert_eq!(Ok(1), filter.select(&devices));
+
+        let filter = DeviceFilter {
+            path: Some("1-2.3".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(Ok(1), filter.select(&devices));
+
+        let filter = DeviceFilter {
+            alt_name: Some("Option Bytes".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(Ok(0), filter.select(&devices));
+        assert_eq!(Some(1), filter.find_alt(&devices[0]));
+
+        let filter = DeviceFilter {
+            alt: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(Ok(0), filter.select(&devices));
+
+        let mut filter = DeviceFilter::default();
+        filter.set_id(":0001").unwrap();
+        assert_eq!(Ok(2), filter.select(&devices));
+        assert_eq!(Err(SelectError::InvalidId("0483".to_string())), filter.set_id("0483"));
+        assert_eq!(Err(SelectError::InvalidId("xyz:1".to_string())), filter.set_id("xyz:1"));
+    }
+
+    #[test]
+    fn test_track_reenumerated_device() {
+        let mut runtime = test_device(0x1209, 0x0001, "BBBB", &[2, 3], &["Runtime"]);
+        runtime.protocol = DFU_PROTOCOL_RUNTIME;
+        let dfu_mode = test_device(0x0483, 0xDF11, "BBBB", &[2, 3], &["@Internal Flash  /0x08000000/04*016Kg"]);
+        let other = test_device(0x0483, 0xDF11, "AAAA", &[1], &["@Internal Flash  /0x08000000/04*016Kg"]);
+
+        // Run-time devices match without checking the alt settings
+        let filter = DeviceFilter {
+            alt_name: Some("Internal Flash".to_string()),
+            serial: Some("BBBB".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&runtime));
+
+        // Tracked by serial number, and by path without one
+        assert!(dfu_mode.is_reenumeration_of(&runtime));
+        assert!(!other.is_reenumeration_of(&runtime));
+        assert!(!runtime.is_reenumeration_of(&runtime));
+
+        runtime.serial = None;
+        let mut moved = dfu_mode.clone();
+        moved.port_numbers = vec![4];
+        assert!(dfu_mode.is_reenumeration_of(&runtime));
+        assert!(!moved.is_reenumeration_of(&runtime));
+    }
+}
@@ -1,4 +1,12 @@
 /// This module handles enumerating USB devices and detecting all supported devices.
 
+pub mod descriptor;
+pub mod dfu;
+pub mod enumerate;
+pub mod option_bytes;
+pub mod otp;
+#[cfg(test)]
+pub mod sim;
 pub mod stm32dfu;
+pub mod transport;
 
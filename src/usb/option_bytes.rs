@@ -0,0 +1,411 @@
+//! Decoder and encoder for the STM32 option bytes, as exposed by the "Option Bytes" alt setting
+//! of the system bootloader.
+//!
+//! On the STM32F2 and STM32F4 the option bytes are read as 16 bytes from 0x1FFFC000, made up of
+//! 16 bit values each followed by its bitwise complement:
+//! - 0: USER option byte (BOR level, watchdog and reset flags) and the RDP byte at 1
+//! - 2: Complement of the USER and RDP bytes
+//! - 8: nWRP write protection bits, one per flash sector
+//! - 10: Complement of the nWRP bits
+//!
+//! The remaining bytes are reserved. The second nWRP register of the dual bank STM32F42x/43x is
+//! located outside this alt setting, and is not handled here.
+
+use core::fmt;
+
+use crate::util::parse;
+
+/// Length of the option bytes region
+pub const OPTION_BYTES_LENGTH: usize = 16;
+
+/// Offsets of the 16 bit values which are followed by their complement
+const VALUE_OFFSETS: [usize; 2] = [0, 8];
+
+/// The RDP byte values with a special meaning. Any other value gives level 1.
+const RDP_LEVEL_0: u8 = 0xAA;
+const RDP_LEVEL_1: u8 = 0x55;
+const RDP_LEVEL_2: u8 = 0xCC;
+
+/// Errors that can occur when decoding or editing option bytes
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionBytesError {
+    /// The family name is not known
+    UnknownFamily(String),
+    /// The option bytes do not have the expected length
+    InvalidLength(usize),
+    /// The value at the offset does not match its complement
+    ComplementMismatch(usize),
+    /// The family has no field with the name
+    UnknownField(String),
+    /// The value can not be given to the field
+    InvalidValue { field: &'static str, value: String },
+}
+
+/// The supported STM32 families
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Family {
+    /// STM32F2, and the single bank STM32F40x/41x
+    Stm32F4,
+    /// The dual bank capable STM32F42x/43x
+    Stm32F42x,
+}
+
+/// The readout protection level
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum RdpLevel {
+    /// No protection
+    Level0,
+    /// Memory can not be read by debug or bootloader. Going back to level 0 mass erases the flash
+    Level1,
+    /// Debug and bootloader are disabled for good. This can not be undone
+    Level2,
+}
+
+/// How the bits of a field are shown and given
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    /// The readout protection byte
+    Rdp,
+    /// The brown out reset level
+    Bor,
+    /// A single bit
+    Flag,
+    /// A set of bits, one per flash sector
+    Mask,
+}
+
+/// A named field of the option bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    /// The name used to show and set the field
+    pub name: &'static str,
+    /// What the field controls
+    pub description: &'static str,
+    /// The offset of the 16 bit value holding the field
+    offset: usize,
+    /// The position of the lowest bit in the value
+    shift: u8,
+    /// The number of bits
+    width: u8,
+    kind: FieldKind,
+}
+
+/// Fields shared by all the supported families
+const COMMON_FIELDS: [Field; 6] = [
+    Field { name: "rdp", description: "Readout protection level", offset: 0, shift: 8, width: 8, kind: FieldKind::Rdp },
+    Field { name: "bor", description: "Brown out reset level", offset: 0, shift: 2, width: 2, kind: FieldKind::Bor },
+    Field { name: "wdg_sw", description: "1: Software watchdog, 0: Hardware watchdog", offset: 0, shift: 5, width: 1, kind: FieldKind::Flag },
+    Field { name: "nrst_stop", description: "1: No reset when entering Stop mode", offset: 0, shift: 6, width: 1, kind: FieldKind::Flag },
+    Field { name: "nrst_stdby", description: "1: No reset when entering Standby mode", offset: 0, shift: 7, width: 1, kind: FieldKind::Flag },
+    Field { name: "nwrp", description: "Write protection per sector, 0: Protected", offset: 8, shift: 0, width: 12, kind: FieldKind::Mask },
+];
+
+/// Fields only found on the STM32F42x/43x
+const F42X_FIELDS: [Field; 3] = [
+    Field { name: "bfb2", description: "1: Boot from bank 2 when valid", offset: 0, shift: 4, width: 1, kind: FieldKind::Flag },
+    Field { name: "db1m", description: "1: Dual bank mode on 1 Mbyte devices", offset: 8, shift: 14, width: 1, kind: FieldKind::Flag },
+    Field { name: "sprmod", description: "1: nWRP bits select readout protection (PCROP)", offset: 8, shift: 15, width: 1, kind: FieldKind::Flag },
+];
+
+/// The option bytes of a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionBytes {
+    /// The family the option bytes are decoded for
+    pub family: Family,
+    /// The raw option bytes, with complements
+    data: Vec<u8>,
+}
+
+impl fmt::Display for OptionBytesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionBytesError::UnknownFamily(name) => {
+                write!(f, "Unknown family '{}', use {}", name, Family::NAMES.join(" or "))
+            }
+            OptionBytesError::InvalidLength(length) => {
+                write!(f, "Expected {} option bytes, but got {}", OPTION_BYTES_LENGTH, length)
+            }
+            OptionBytesError::ComplementMismatch(offset) => {
+                write!(f, "The value at offset {} does not match its complement", offset)
+            }
+            OptionBytesError::UnknownField(name) => write!(f, "Unknown option byte field '{}'", name),
+            OptionBytesError::InvalidValue { field, value } => {
+                write!(f, "Invalid value '{}' for the {} field", value, field)
+            }
+        }
+    }
+}
+
+impl Family {
+    /// The names accepted by `from_name`
+    pub const NAMES: [&'static str; 2] = ["stm32f4", "stm32f42x"];
+
+    /// Finds the family from its name, such as "stm32f4"
+    pub fn from_name(name: &str) -> Result<Self, OptionBytesError> {
+        match name.to_lowercase().as_str() {
+            "stm32f2" | "stm32f4" | "f2" | "f4" => Ok(Family::Stm32F4),
+            "stm32f42x" | "stm32f43x" | "f42x" | "f43x" => Ok(Family::Stm32F42x),
+            _ => Err(OptionBytesError::UnknownFamily(name.to_string())),
+        }
+    }
+
+    /// Returns the fields of the family
+    pub fn fields(&self) -> Vec<&'static Field> {
+        let mut fields: Vec<&'static Field> = COMMON_FIELDS.iter().collect();
+        if *self == Family::Stm32F42x {
+            fields.extend(F42X_FIELDS.iter());
+        }
+        fields
+    }
+}
+
+impl fmt::Display for RdpLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RdpLevel::Level0 => write!(f, "Level 0"),
+            RdpLevel::Level1 => write!(f, "Level 1"),
+            RdpLevel::Level2 => write!(f, "Level 2"),
+        }
+    }
+}
+
+impl RdpLevel {
+    /// Decodes the RDP byte
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            RDP_LEVEL_0 => RdpLevel::Level0,
+            RDP_LEVEL_2 => RdpLevel::Level2,
+            _ => RdpLevel::Level1,
+        }
+    }
+}
+
+impl Field {
+    /// Formats the raw value of the field
+    pub fn format(&self, value: u16) -> String {
+        match self.kind {
+            FieldKind::Rdp => format!("{} (0x{:02X})", RdpLevel::from_u8(value as u8), value),
+            FieldKind::Bor => match value {
+                3 => "Off".to_string(),
+                level => format!("Level {}", 3 - level),
+            },
+            FieldKind::Flag => format!("{}", value),
+            FieldKind::Mask => format!("0x{:03X}", value),
+        }
+    }
+
+    /// Parses a value given by the user to the raw value of the field
+    /// - rdp: 0, 1 or 2
+    /// - bor: off, 1, 2 or 3
+    /// - Flags: 0 or 1
+    /// - nwrp: The bits as a number, such as 0xFFF
+    pub fn parse(&self, text: &str) -> Result<u16, OptionBytesError> {
+        let invalid = || OptionBytesError::InvalidValue {
+            field: self.name,
+            value: text.to_string(),
+        };
+
+        let value = match (self.kind, text.to_lowercase().as_str()) {
+            (FieldKind::Rdp, "0") => RDP_LEVEL_0,
+            (FieldKind::Rdp, "1") => RDP_LEVEL_1,
+            (FieldKind::Rdp, "2") => RDP_LEVEL_2,
+            (FieldKind::Bor, "off") => 3,
+            (FieldKind::Bor, "1") => 2,
+            (FieldKind::Bor, "2") => 1,
+            (FieldKind::Bor, "3") => 0,
+            (FieldKind::Flag, "0") => 0,
+            (FieldKind::Flag, "1") => 1,
+            (FieldKind::Mask, _) => {
+                let value = parse::usize_from_string(text).map_err(|_| invalid())?;
+                if value >= 1 << self.width {
+                    return Err(invalid());
+                }
+                return Ok(value as u16);
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(u16::from(value))
+    }
+
+    /// Returns the mask of the field bits, in place
+    fn mask(&self) -> u16 {
+        (((1u32 << self.width) - 1) << self.shift) as u16
+    }
+}
+
+impl OptionBytes {
+    /// Decodes the option bytes read from the device, checking the complements
+    /// # Arguments
+    /// * `family` - The family of the device
+    /// * `data` - The option bytes, as read from the alt setting
+    pub fn parse(family: Family, data: &[u8]) -> Result<Self, OptionBytesError> {
+        if data.len() != OPTION_BYTES_LENGTH {
+            return Err(OptionBytesError::InvalidLength(data.len()));
+        }
+
+        let option_bytes = OptionBytes {
+            family,
+            data: data.to_vec(),
+        };
+        for offset in &VALUE_OFFSETS {
+            if option_bytes.value(*offset) != !option_bytes.value(*offset + 2) {
+                return Err(OptionBytesError::ComplementMismatch(*offset));
+            }
+        }
+        Ok(option_bytes)
+    }
+
+    /// Returns the option bytes, with the complements updated
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        for offset in &VALUE_OFFSETS {
+            data[offset + 2..offset + 4].copy_from_slice(&(!self.value(*offset)).to_le_bytes());
+        }
+        data
+    }
+
+    /// Returns the raw value of the field
+    pub fn get(&self, field: &Field) -> u16 {
+        (self.value(field.offset) & field.mask()) >> field.shift
+    }
+
+    /// Sets the field with the given name, from a value given by the user
+    pub fn set(&mut self, name: &str, text: &str) -> Result<(), OptionBytesError> {
+        let field = self
+            .family
+            .fields()
+            .into_iter()
+            .find(|f| f.name == name.to_lowercase())
+            .ok_or_else(|| OptionBytesError::UnknownField(name.to_string()))?;
+        let value = field.parse(text)?;
+
+        let updated = (self.value(field.offset) & !field.mask()) | (value << field.shift);
+        self.data[field.offset..field.offset + 2].copy_from_slice(&updated.to_le_bytes());
+        Ok(())
+    }
+
+    /// Returns the readout protection level
+    pub fn rdp_level(&self) -> RdpLevel {
+        RdpLevel::from_u8(self.data[1])
+    }
+
+    /// Reads the 16 bit little endian value at the offset
+    fn value(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.data[offset], self.data[offset + 1]])
+    }
+}
+
+impl fmt::Display for OptionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for field in self.family.fields() {
+            writeln!(f, " - {:<10} = {:<16} {}", field.name, field.format(self.get(field)), field.description)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The option bytes of an STM32F4 in its default state
+    const DEFAULT_F4: [u8; 16] = [
+        0xEC, 0xAA, 0x13, 0x55, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF,
+    ];
+
+    #[test]
+    fn test_decode_default_option_bytes() {
+        let option_bytes = OptionBytes::parse(Family::Stm32F4, &DEFAULT_F4).unwrap();
+        let fields = Family::Stm32F4.fields();
+        let value = |name: &str| option_bytes.get(fields.iter().find(|f| f.name == name).unwrap());
+
+        assert_eq!(RdpLevel::Level0, option_bytes.rdp_level());
+        assert_eq!(3, value("bor"));
+        assert_eq!(1, value("wdg_sw"));
+        assert_eq!(1, value("nrst_stdby"));
+        assert_eq!(0xFFF, value("nwrp"));
+        assert_eq!(DEFAULT_F4.to_vec(), option_bytes.to_bytes());
+
+        let mut corrupt = DEFAULT_F4;
+        corrupt[10] = 0x01;
+        assert_eq!(Err(OptionBytesError::ComplementMismatch(8)), OptionBytes::parse(Family::Stm32F4, &corrupt));
+        assert_eq!(Err(OptionBytesError::InvalidLength(8)), OptionBytes::parse(Family::Stm32F4, &DEFAULT_F4[..8]));
+    }
+
+    #[test]
+    fn test_set_fields_updates_complements() {
+        let mut option_bytes = OptionBytes::parse(Family::Stm32F4, &DEFAULT_F4).unwrap();
+        option_bytes.set("rdp", "1").unwrap();
+        option_bytes.set("bor", "2").unwrap();
+        option_bytes.set("nwrp", "0xFFC").unwrap();
+
+        let bytes = option_bytes.to_bytes();
+        assert_eq!(&[0xE4, 0x55, 0x1B, 0xAA], &bytes[0..4]);
+        assert_eq!(&[0xFC, 0x0F, 0x03, 0xF0], &bytes[8..12]);
+        assert_eq!(RdpLevel::Level1, OptionBytes::parse(Family::Stm32F4, &bytes).unwrap().rdp_level());
+        assert!(option_bytes.rdp_level() > RdpLevel::Level0);
+
+        assert_eq!(Err(OptionBytesError::UnknownField("sprmod".to_string())), option_bytes.set("sprmod", "1"));
+        assert_eq!(
+            Err(OptionBytesError::InvalidValue { field: "nwrp", value: "0x1000".to_string() }),
+            option_bytes.set("nwrp", "0x1000")
+        );
+
+        let mut option_bytes = OptionBytes::parse(Family::Stm32F42x, &DEFAULT_F4).unwrap();
+        option_bytes.set("sprmod", "1").unwrap();
+        assert_eq!(&[0xFF, 0x8F, 0x00, 0x70], &option_bytes.to_bytes()[8..12]);
+    }
+
+    #[test]
+    fn test_rdp_level_changes() {
+        // Raising from level 0 to 1, and on to 2
+        let mut option_bytes = OptionBytes::parse(Family::Stm32F4, &DEFAULT_F4).unwrap();
+        option_bytes.set("rdp", "1").unwrap();
+        let level1 = OptionBytes::parse(Family::Stm32F4, &option_bytes.to_bytes()).unwrap();
+        assert_eq!(RdpLevel::Level1, level1.rdp_level());
+        option_bytes.set("rdp", "2").unwrap();
+        assert_eq!(&[0xEC, 0xCC, 0x13, 0x33], &option_bytes.to_bytes()[0..4]);
+        assert_eq!(RdpLevel::Level2, option_bytes.rdp_level());
+        assert!(option_bytes.rdp_level() > level1.rdp_level());
+
+        // Lowering from level 1 back to 0 keeps the other fields
+        let mut option_bytes = level1.clone();
+        option_bytes.set("rdp", "0").unwrap();
+        assert_eq!(DEFAULT_F4.to_vec(), option_bytes.to_bytes());
+        assert!(option_bytes.rdp_level() < level1.rdp_level());
+
+        // Any value other than the level 0 and 2 markers is level 1
+        assert_eq!(RdpLevel::Level1, RdpLevel::from_u8(0x00));
+        assert_eq!(RdpLevel::Level1, RdpLevel::from_u8(0xFF));
+        assert_eq!("Level 1 (0x12)", COMMON_FIELDS[0].format(0x12));
+    }
+
+    #[test]
+    fn test_set_rejects_invalid_values() {
+        let mut option_bytes = OptionBytes::parse(Family::Stm32F4, &DEFAULT_F4).unwrap();
+        let invalid = |field: &'static str, value: &str| {
+            Err(OptionBytesError::InvalidValue {
+                field,
+                value: value.to_string(),
+            })
+        };
+
+        for value in &["3", "0xAA", "level0", ""] {
+            assert_eq!(invalid("rdp", value), option_bytes.set("rdp", value));
+        }
+        assert_eq!(invalid("bor", "0"), option_bytes.set("bor", "0"));
+        assert_eq!(invalid("bor", "4"), option_bytes.set("bor", "4"));
+        assert_eq!(invalid("wdg_sw", "2"), option_bytes.set("wdg_sw", "2"));
+        assert_eq!(invalid("nwrp", "sector1"), option_bytes.set("nwrp", "sector1"));
+        assert_eq!(Err(OptionBytesError::UnknownField("rdp2".to_string())), option_bytes.set("rdp2", "0"));
+
+        // Rejected values leave the option bytes unchanged
+        assert_eq!(DEFAULT_F4.to_vec(), option_bytes.to_bytes());
+        assert_eq!(RdpLevel::Level0, option_bytes.rdp_level());
+
+        // Field names and values are not case sensitive
+        option_bytes.set("BOR", "OFF").unwrap();
+        option_bytes.set("Rdp", "1").unwrap();
+        assert_eq!(RdpLevel::Level1, option_bytes.rdp_level());
+    }
+}
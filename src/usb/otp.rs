@@ -0,0 +1,265 @@
+//! Safeguards for programming the one-time programmable (OTP) memory of the STM32F2/F4.
+//!
+//! The OTP memory holds 512 data bytes in 16 blocks of 32 bytes, directly followed by 16 lock
+//! bytes, one per block. These are the two sectors of the "OTP Memory" alt setting. Programming
+//! can only clear bits, so a byte can never go back to 0xFF. Writing 0x00 to a lock byte makes
+//! its block read-only for good.
+
+use core::fmt;
+use core::fmt::Write;
+
+use crate::image::firmware::FirmwareImage;
+use crate::image::Segment;
+use crate::util::memory::MemoryMap;
+
+/// The number of data bytes covered by each lock byte
+pub const OTP_BLOCK_SIZE: usize = 32;
+
+/// The value of a lock byte of an unlocked block
+const UNLOCKED: u8 = 0xFF;
+
+/// Number of bytes per row in the hex diff
+const DIFF_ROW_SIZE: usize = 16;
+
+/// Errors that can occur when checking an OTP write
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtpError {
+    /// The memory map is not a data area directly followed by one lock byte per block
+    InvalidLayout,
+    /// The address is outside the OTP memory
+    OutsideOtp(usize),
+    /// Writing the byte would need bits to go from 0 back to 1
+    SetsClearedBits { address: usize, current: u8, requested: u8 },
+    /// The byte is a lock byte, and changing lock bytes is not allowed
+    LockByte(usize),
+    /// The byte is in a locked block
+    BlockLocked { block: usize, address: usize },
+}
+
+/// The location of the OTP data and lock bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OtpLayout {
+    /// The address of the first data byte
+    pub data_address: usize,
+    /// The number of data bytes
+    pub data_length: usize,
+    /// The number of lock bytes, which follow directly after the data
+    pub lock_length: usize,
+}
+
+impl fmt::Display for OtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OtpError::InvalidLayout => write!(f, "The memory layout is not OTP data followed by lock bytes"),
+            OtpError::OutsideOtp(address) => write!(f, "Address 0x{:08X} is outside the OTP memory", address),
+            OtpError::SetsClearedBits { address, current, requested } => write!(
+                f,
+                "Address 0x{:08X} holds 0x{:02X}, and can not be changed to 0x{:02X} as bits can not go from 0 to 1",
+                address, current, requested
+            ),
+            OtpError::LockByte(address) => {
+                write!(f, "Address 0x{:08X} is a lock byte, which is only written when allowed", address)
+            }
+            OtpError::BlockLocked { block, address } => {
+                write!(f, "Address 0x{:08X} is in block {}, which is locked", address, block)
+            }
+        }
+    }
+}
+
+impl OtpLayout {
+    /// Finds the layout from the memory map of the OTP alt setting
+    pub fn from_memory_map(memory_map: &MemoryMap) -> Result<Self, OtpError> {
+        let sectors: Vec<_> = memory_map.banks().iter().flat_map(|bank| bank.sectors()).collect();
+        match sectors[..] {
+            [data, lock]
+                if lock.address == data.address + data.total_size()
+                    && lock.total_size() * OTP_BLOCK_SIZE == data.total_size() =>
+            {
+                Ok(OtpLayout {
+                    data_address: data.address,
+                    data_length: data.total_size(),
+                    lock_length: lock.total_size(),
+                })
+            }
+            _ => Err(OtpError::InvalidLayout),
+        }
+    }
+
+    /// Returns the address of the first lock byte
+    pub fn lock_address(&self) -> usize {
+        self.data_address + self.data_length
+    }
+
+    /// Returns the length of the whole OTP memory, data and lock bytes
+    pub fn length(&self) -> usize {
+        self.data_length + self.lock_length
+    }
+}
+
+/// Checks the image against the current OTP contents, and applies it
+/// # Arguments
+/// * `layout` - The location of the OTP memory
+/// * `current` - The current contents of the whole OTP memory
+/// * `image` - The bytes to program
+/// * `allow_lock` - Allow the image to change lock bytes
+///
+/// # Return
+/// The new contents of the whole OTP memory, or the first byte which can not be written
+pub fn apply(layout: &OtpLayout, current: &[u8], image: &FirmwareImage, allow_lock: bool) -> Result<Vec<u8>, OtpError> {
+    let mut new = current.to_vec();
+
+    for segment in image.segments() {
+        for (index, requested) in segment.data.iter().enumerate() {
+            let address = segment.address + index;
+            if address < layout.data_address || address >= layout.data_address + current.len() {
+                return Err(OtpError::OutsideOtp(address));
+            }
+
+            let offset = address - layout.data_address;
+            let old = current[offset];
+            if *requested == old {
+                continue;
+            }
+
+            if address >= layout.lock_address() {
+                if !allow_lock {
+                    return Err(OtpError::LockByte(address));
+                }
+            } else {
+                let block = offset / OTP_BLOCK_SIZE;
+                if current.get(layout.data_length + block).map_or(false, |lock| *lock != UNLOCKED) {
+                    return Err(OtpError::BlockLocked { block, address });
+                }
+            }
+
+            if requested & !old != 0 {
+                return Err(OtpError::SetsClearedBits {
+                    address,
+                    current: old,
+                    requested: *requested,
+                });
+            }
+            new[offset] = *requested;
+        }
+    }
+    Ok(new)
+}
+
+/// Returns the runs of bytes differing between the current and new contents
+/// # Arguments
+/// * `address` - The address of the first byte
+/// * `current` - The current contents
+/// * `new` - The new contents, of the same length
+pub fn changes(address: usize, current: &[u8], new: &[u8]) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    for (offset, (old, byte)) in current.iter().zip(new).enumerate() {
+        if old == byte {
+            continue;
+        }
+        match segments.last_mut() {
+            Some(last) if last.end_address() == address + offset => last.data.push(*byte),
+            _ => segments.push(Segment::new(address + offset, vec![*byte])),
+        }
+    }
+    segments
+}
+
+/// Formats the rows with changes as a hex diff, with the current bytes on a line starting
+/// with '-', and the new bytes on a line starting with '+'
+/// # Arguments
+/// * `address` - The address of the first byte
+/// * `current` - The current contents
+/// * `new` - The new contents, of the same length
+pub fn hex_diff(address: usize, current: &[u8], new: &[u8]) -> String {
+    let mut out = String::new();
+    for (row, (old, updated)) in current.chunks(DIFF_ROW_SIZE).zip(new.chunks(DIFF_ROW_SIZE)).enumerate() {
+        if old == updated {
+            continue;
+        }
+
+        let row_address = address + row * DIFF_ROW_SIZE;
+        for (sign, bytes) in &[('-', old), ('+', updated)] {
+            let _ = write!(out, "{} 0x{:08X}:", sign, row_address);
+            for byte in bytes.iter() {
+                let _ = write!(out, " {:02X}", byte);
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::sim::STM32F4_ALT_SETTINGS;
+    use crate::usb::stm32dfu::parse_memory_layout_string;
+
+    #[test]
+    fn test_apply_refuses_unsafe_writes() {
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[2]).unwrap();
+        let layout = OtpLayout::from_memory_map(&memmap).unwrap();
+        assert_eq!(0x1FFF_7A00, layout.lock_address());
+        assert_eq!(0x210, layout.length());
+
+        // Block 1 is locked, and the first byte is already programmed
+        let mut current = vec![0xFF; layout.length()];
+        current[0] = 0x0F;
+        current[0x201] = 0x00;
+
+        let image = |address: usize, data: Vec<u8>| FirmwareImage::from_segments(vec![Segment::new(address, data)]).unwrap();
+        let new = apply(&layout, &current, &image(0x1FFF_7800, vec![0x0A, 0x12, 0x34]), false).unwrap();
+        assert_eq!(&[0x0A, 0x12, 0x34, 0xFF], &new[0..4]);
+        assert_eq!(vec![Segment::new(0x1FFF_7800, vec![0x0A, 0x12, 0x34])], changes(0x1FFF_7800, &current, &new));
+        assert!(hex_diff(0x1FFF_7800, &current, &new).starts_with("- 0x1FFF7800: 0F FF FF FF"));
+
+        assert_eq!(
+            Err(OtpError::SetsClearedBits { address: 0x1FFF_7800, current: 0x0F, requested: 0x1F }),
+            apply(&layout, &current, &image(0x1FFF_7800, vec![0x1F]), false)
+        );
+        assert_eq!(
+            Err(OtpError::BlockLocked { block: 1, address: 0x1FFF_7820 }),
+            apply(&layout, &current, &image(0x1FFF_7820, vec![0x00]), false)
+        );
+        assert_eq!(Err(OtpError::LockByte(0x1FFF_7A02)), apply(&layout, &current, &image(0x1FFF_7A02, vec![0x00]), false));
+        assert!(apply(&layout, &current, &image(0x1FFF_7A02, vec![0x00]), true).is_ok());
+        assert_eq!(Err(OtpError::OutsideOtp(0x1FFF_7A10)), apply(&layout, &current, &image(0x1FFF_7A0F, vec![0xFF; 2]), true));
+
+        // Bytes already holding the requested value are fine, even in a locked block
+        assert_eq!(current, apply(&layout, &current, &image(0x1FFF_7820, vec![0xFF; 32]), false).unwrap());
+    }
+
+    #[test]
+    fn test_apply_refuses_clearing_programmed_bits() {
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[2]).unwrap();
+        let layout = OtpLayout::from_memory_map(&memmap).unwrap();
+
+        // Block 0 holds programmed data, and block 2 is locked
+        let mut current = vec![0xFF; layout.length()];
+        current[0..4].copy_from_slice(&[0x0F, 0xA5, 0x00, 0xFF]);
+        current[0x202] = 0x00;
+        let image = |address: usize, data: Vec<u8>| FirmwareImage::from_segments(vec![Segment::new(address, data)]).unwrap();
+
+        // Clearing more bits of programmed bytes is allowed
+        let new = apply(&layout, &current, &image(0x1FFF_7800, vec![0x05, 0x21, 0x00, 0x7E]), false).unwrap();
+        assert_eq!(&[0x05, 0x21, 0x00, 0x7E], &new[0..4]);
+
+        // Any bit going from 0 back to 1 is refused, reporting the first byte needing it
+        assert_eq!(
+            Err(OtpError::SetsClearedBits { address: 0x1FFF_7801, current: 0xA5, requested: 0x5A }),
+            apply(&layout, &current, &image(0x1FFF_7800, vec![0x0F, 0x5A, 0x00]), false)
+        );
+        assert_eq!(
+            Err(OtpError::SetsClearedBits { address: 0x1FFF_7802, current: 0x00, requested: 0xFF }),
+            apply(&layout, &current, &image(0x1FFF_7800, vec![0x0E, 0xA4, 0xFF]), false)
+        );
+
+        // Unlocking a block is refused even when lock bytes may be written
+        assert_eq!(
+            Err(OtpError::SetsClearedBits { address: 0x1FFF_7A02, current: 0x00, requested: 0xFF }),
+            apply(&layout, &current, &image(0x1FFF_7A02, vec![0xFF]), true)
+        );
+        assert!(apply(&layout, &current, &image(0x1FFF_7A02, vec![0x00]), true).is_ok());
+    }
+}
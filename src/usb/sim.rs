@@ -0,0 +1,704 @@
+//! In-memory simulation of an STM32 style DfuSe bootloader, used to exercise the
+//! DFU stack in tests without any hardware attached.
+//!
+//! The simulated device exposes one alternate setting per interface string, with the
+//! memory of each alt setting laid out as described by the string. It implements the
+//! DfuSe command set sent through DNLOAD block 0, the wBlockNum-2 addressing scheme for
+//! data blocks, and reports realistic bwPollTimeout values. Time is virtual: it only
+//! advances when the host calls `delay`, so the tests can check that poll timeouts
+//! are honored without actually sleeping.
+
+use super::dfu::{
+    DfuState, DfuStatus, DFU_ABORT, DFU_CLRSTATUS, DFU_DETACH, DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS, DFU_UPLOAD,
+};
+use super::stm32dfu::{
+    parse_memory_layout_string, DFUSE_CMD_ERASE, DFUSE_CMD_GET_COMMANDS, DFUSE_CMD_READ_UNPROTECT,
+    DFUSE_CMD_SET_ADDRESS_POINTER,
+};
+use super::transport::{
+    DfuTransport, TransportError, DESCRIPTOR_TYPE_CONFIG, DESCRIPTOR_TYPE_DEVICE, DESCRIPTOR_TYPE_STRING,
+};
+use crate::util::memory::Accessibility;
+
+/// The alternate setting strings reported by the STM32F4 system bootloader
+pub const STM32F4_ALT_SETTINGS: [&str; 4] = [
+    "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,03*128Kg",
+    "@Option Bytes  /0x1FFFC000/01*016 e",
+    "@OTP Memory /0x1FFF7800/01*512 e,01*016 e",
+    "@Device Feature/0xFFFF0000/01*004 e",
+];
+
+/// Index of the first interface string descriptor
+const FIRST_ALT_STRING: u8 = 4;
+
+/// A continuous set of equally sized blocks
+struct SimSector {
+    address: usize,
+    block_size: usize,
+    block_count: usize,
+    access: Accessibility,
+}
+
+/// A bank of memory, backed by a byte vector
+struct SimBank {
+    address: usize,
+    memory: Vec<u8>,
+    sectors: Vec<SimSector>,
+}
+
+/// A single alternate setting, with its own memory
+struct SimAlt {
+    name: String,
+    banks: Vec<SimBank>,
+}
+
+/// An operation received through DNLOAD, executed on the following DFU_GETSTATUS requests
+#[derive(Debug, Clone)]
+enum Operation {
+    SetAddress(usize),
+    Erase(usize),
+    MassErase,
+    ReadUnprotect,
+    Write(usize, Vec<u8>),
+    Manifest,
+    Invalid,
+}
+
+/// The simulated device
+pub struct SimDevice {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+    /// bmAttributes of the DFU functional descriptor
+    pub attributes: u8,
+    /// wDetachTimeOut of the DFU functional descriptor
+    pub detach_timeout: u16,
+    /// wTransferSize of the DFU functional descriptor
+    pub transfer_size: u16,
+    /// bcdDFUVersion of the DFU functional descriptor
+    pub dfu_version: u16,
+    /// bInterfaceProtocol of the interfaces, 2 for DFU mode
+    pub protocol: u8,
+    /// Readout protection is active, so every command but read unprotect is rejected
+    pub read_protected: bool,
+    alts: Vec<SimAlt>,
+    current_alt: usize,
+    state: DfuState,
+    status: DfuStatus,
+    address_pointer: usize,
+    pending: Option<Operation>,
+    elapsed_ms: u32,
+    ready_at_ms: u32,
+    early_polls: usize,
+    erase_count: usize,
+    write_count: usize,
+    largest_upload: usize,
+    detached: bool,
+}
+
+impl SimAlt {
+    /// Creates the alt setting memory from an interface string
+    fn from_string(name: &str) -> Self {
+        let map = parse_memory_layout_string(name).expect("Invalid alt setting string");
+
+        let banks = map
+            .banks()
+            .iter()
+            .map(|bank| {
+                let sectors: Vec<SimSector> = bank
+                    .sectors()
+                    .iter()
+                    .map(|s| SimSector {
+                        address: s.address,
+                        block_size: s.block_size,
+                        block_count: s.block_count,
+                        access: s.access,
+                    })
+                    .collect();
+                let size: usize = sectors.iter().map(|s| s.block_size * s.block_count).sum();
+
+                SimBank {
+                    address: bank.address,
+                    memory: vec![0xFF; size],
+                    sectors,
+                }
+            })
+            .collect();
+
+        SimAlt {
+            name: name.to_string(),
+            banks,
+        }
+    }
+
+    /// Finds the sector containing the given address, returning the bank index as well
+    fn find_sector(&self, address: usize) -> Option<(usize, &SimSector)> {
+        for (bank_index, bank) in self.banks.iter().enumerate() {
+            for sector in &bank.sectors {
+                let end = sector.address + sector.block_size * sector.block_count;
+                if address >= sector.address && address < end {
+                    return Some((bank_index, sector));
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks that every byte in the range is located in one bank, and has the given access
+    fn check_range(&self, address: usize, length: usize, access: Accessibility) -> Option<usize> {
+        let (bank_index, _) = self.find_sector(address)?;
+        let mut current = address;
+        while current < address + length {
+            let (ix, sector) = self.find_sector(current)?;
+            if ix != bank_index || sector.access & access != access {
+                return None;
+            }
+            current = sector.address + sector.block_size * sector.block_count;
+        }
+        Some(bank_index)
+    }
+}
+
+impl SimDevice {
+    /// Creates a new simulated device with one alt setting per given interface string
+    pub fn new(vendor_id: u16, product_id: u16, alt_settings: &[&str]) -> Self {
+        SimDevice {
+            vendor_id,
+            product_id,
+            manufacturer: "STMicroelectronics".to_string(),
+            product: "STM32  BOOTLOADER".to_string(),
+            serial: "348435943539".to_string(),
+            attributes: 0x0B,
+            detach_timeout: 255,
+            transfer_size: 2048,
+            dfu_version: 0x011A,
+            protocol: 2,
+            read_protected: false,
+            alts: alt_settings.iter().map(|s| SimAlt::from_string(s)).collect(),
+            current_alt: 0,
+            state: DfuState::DfuIdle,
+            status: DfuStatus::Ok,
+            address_pointer: 0,
+            pending: None,
+            elapsed_ms: 0,
+            ready_at_ms: 0,
+            early_polls: 0,
+            erase_count: 0,
+            write_count: 0,
+            largest_upload: 0,
+            detached: false,
+        }
+    }
+
+    /// Creates a device matching the STM32F4 system bootloader
+    pub fn stm32f4() -> Self {
+        Self::new(0x0483, 0xDF11, &STM32F4_ALT_SETTINGS)
+    }
+
+    /// Puts the device in run-time mode, as an application with a DFU runtime interface
+    pub fn set_runtime_mode(&mut self) {
+        self.protocol = 1;
+        self.state = DfuState::AppIdle;
+    }
+
+    /// Reads memory directly, bypassing the protocol
+    pub fn read_memory(&self, alt: usize, address: usize, length: usize) -> Vec<u8> {
+        let (bank, offset) = self.locate(alt, address);
+        self.alts[alt].banks[bank].memory[offset..offset + length].to_vec()
+    }
+
+    /// Writes memory directly, bypassing the protocol
+    pub fn write_memory(&mut self, alt: usize, address: usize, data: &[u8]) {
+        let (bank, offset) = self.locate(alt, address);
+        self.alts[alt].banks[bank].memory[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Total virtual time waited by the host
+    pub fn elapsed_ms(&self) -> u32 {
+        self.elapsed_ms
+    }
+
+    /// Number of status requests received before the poll timeout expired
+    pub fn early_polls(&self) -> usize {
+        self.early_polls
+    }
+
+    /// Number of erase operations performed (page or mass erase)
+    pub fn erase_count(&self) -> usize {
+        self.erase_count
+    }
+
+    /// Number of data blocks written
+    pub fn write_count(&self) -> usize {
+        self.write_count
+    }
+
+    /// The largest number of bytes asked for by a DfuSe data upload
+    pub fn largest_upload(&self) -> usize {
+        self.largest_upload
+    }
+
+    /// The current state of the device
+    pub fn state(&self) -> DfuState {
+        self.state
+    }
+
+    /// Checks if the device has left the bus, after manifestation or a detach from run-time mode
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Returns the bank index and offset of an address in the given alt setting
+    fn locate(&self, alt: usize, address: usize) -> (usize, usize) {
+        let (bank, _) = self.alts[alt]
+            .find_sector(address)
+            .expect("Address outside simulated memory");
+        (bank, address - self.alts[alt].banks[bank].address)
+    }
+
+    /// Puts the device in the error state with the given status
+    fn fail(&mut self, status: DfuStatus) {
+        self.status = status;
+        self.state = DfuState::DfuError;
+        self.pending = None;
+    }
+
+    /// Decodes a DNLOAD request into an operation
+    fn decode_download(&self, block: u16, data: &[u8]) -> Operation {
+        if data.is_empty() {
+            return Operation::Manifest;
+        }
+
+        match block {
+            0 => match (data[0], data.len()) {
+                (DFUSE_CMD_SET_ADDRESS_POINTER, 5) => Operation::SetAddress(read_address(&data[1..])),
+                (DFUSE_CMD_ERASE, 5) => Operation::Erase(read_address(&data[1..])),
+                (DFUSE_CMD_ERASE, 1) => Operation::MassErase,
+                (DFUSE_CMD_READ_UNPROTECT, 1) => Operation::ReadUnprotect,
+                _ => Operation::Invalid,
+            },
+            1 => Operation::Invalid,
+            _ => {
+                let address = self.address_pointer + (block as usize - 2) * self.transfer_size as usize;
+                Operation::Write(address, data.to_vec())
+            }
+        }
+    }
+
+    /// Returns the time the given operation keeps the device busy
+    fn operation_time(&self, operation: &Operation) -> u32 {
+        match operation {
+            Operation::Erase(address) => match self.alts[self.current_alt].find_sector(*address) {
+                Some((_, sector)) => (sector.block_size / 64) as u32,
+                None => 1,
+            },
+            Operation::MassErase | Operation::ReadUnprotect => 10_000,
+            Operation::Write(_, data) => (data.len() / 64).max(1) as u32,
+            _ => 1,
+        }
+    }
+
+    /// Executes the given operation, returning the resulting status
+    fn execute(&mut self, operation: Operation) -> DfuStatus {
+        let unprotect = matches!(operation, Operation::ReadUnprotect);
+        if self.read_protected && !unprotect {
+            return DfuStatus::ErrVendor;
+        }
+        let alt = &mut self.alts[self.current_alt];
+
+        match operation {
+            Operation::SetAddress(address) => {
+                if alt.find_sector(address).is_none() {
+                    return DfuStatus::ErrTarget;
+                }
+                self.address_pointer = address;
+            }
+            Operation::Erase(address) => {
+                let (bank_index, block_address, block_size) = match alt.find_sector(address) {
+                    Some((ix, s)) if s.access.contains(Accessibility::ERASE) => {
+                        let block = (address - s.address) / s.block_size;
+                        (ix, s.address + block * s.block_size, s.block_size)
+                    }
+                    _ => return DfuStatus::ErrTarget,
+                };
+                let bank = &mut alt.banks[bank_index];
+                let offset = block_address - bank.address;
+                bank.memory[offset..offset + block_size].iter_mut().for_each(|b| *b = 0xFF);
+                self.erase_count += 1;
+            }
+            Operation::MassErase | Operation::ReadUnprotect => {
+                for bank in alt.banks.iter_mut() {
+                    for sector in &bank.sectors {
+                        if sector.access.contains(Accessibility::ERASE) {
+                            let offset = sector.address - bank.address;
+                            let size = sector.block_size * sector.block_count;
+                            bank.memory[offset..offset + size].iter_mut().for_each(|b| *b = 0xFF);
+                        }
+                    }
+                }
+                self.erase_count += 1;
+
+                // Removing the protection resets the device
+                if unprotect {
+                    self.read_protected = false;
+                    self.detached = true;
+                }
+            }
+            Operation::Write(address, data) => {
+                let bank_index = match alt.check_range(address, data.len(), Accessibility::WRITE) {
+                    Some(ix) => ix,
+                    None => return DfuStatus::ErrTarget,
+                };
+                // Flash programming can only clear bits, so an unerased cell keeps its zeroes
+                let bank = &mut alt.banks[bank_index];
+                let offset = address - bank.address;
+                for (cell, value) in bank.memory[offset..offset + data.len()].iter_mut().zip(data) {
+                    *cell &= value;
+                }
+                self.write_count += 1;
+            }
+            Operation::Manifest | Operation::Invalid => return DfuStatus::ErrStalledPkt,
+        }
+        DfuStatus::Ok
+    }
+
+    /// Handles DFU_GETSTATUS, advancing the state machine
+    fn get_status(&mut self) -> [u8; 6] {
+        let poll_timeout = match self.state {
+            DfuState::DfuDnloadSync => {
+                let time = self.pending.as_ref().map(|op| self.operation_time(op)).unwrap_or(0);
+                self.ready_at_ms = self.elapsed_ms + time;
+                self.state = DfuState::DfuDnBusy;
+                time
+            }
+            DfuState::DfuDnBusy if self.elapsed_ms < self.ready_at_ms => {
+                self.early_polls += 1;
+                self.ready_at_ms - self.elapsed_ms
+            }
+            DfuState::DfuDnBusy => {
+                let operation = self.pending.take().unwrap_or(Operation::Invalid);
+                match self.execute(operation) {
+                    DfuStatus::Ok => self.state = DfuState::DfuDnloadIdle,
+                    status => self.fail(status),
+                }
+                0
+            }
+            DfuState::DfuManifestSync => {
+                self.ready_at_ms = self.elapsed_ms + 10;
+                self.state = DfuState::DfuManifest;
+                10
+            }
+            DfuState::DfuManifest if self.elapsed_ms < self.ready_at_ms => {
+                self.early_polls += 1;
+                self.ready_at_ms - self.elapsed_ms
+            }
+            DfuState::DfuManifest => {
+                // The bootloader is manifestation intolerant, and leaves DFU mode
+                self.state = DfuState::DfuManifestWaitReset;
+                self.detached = true;
+                0
+            }
+            _ => 0,
+        };
+
+        [
+            self.status as u8,
+            poll_timeout as u8,
+            (poll_timeout >> 8) as u8,
+            (poll_timeout >> 16) as u8,
+            self.state as u8,
+            0,
+        ]
+    }
+
+    /// Handles DFU_UPLOAD
+    fn upload(&mut self, block: u16, buf: &mut [u8]) -> Result<usize, TransportError> {
+        if block == 0 {
+            let commands = [DFUSE_CMD_GET_COMMANDS, DFUSE_CMD_SET_ADDRESS_POINTER, DFUSE_CMD_ERASE, DFUSE_CMD_READ_UNPROTECT];
+            let count = commands.len().min(buf.len());
+            buf[..count].copy_from_slice(&commands[..count]);
+            self.state = DfuState::DfuUploadIdle;
+            return Ok(count);
+        }
+        if block == 1 {
+            self.fail(DfuStatus::ErrStalledPkt);
+            return Err(TransportError::Pipe);
+        }
+
+        if self.read_protected {
+            self.fail(DfuStatus::ErrVendor);
+            return Err(TransportError::Pipe);
+        }
+
+        self.largest_upload = self.largest_upload.max(buf.len());
+        let address = self.address_pointer + (block as usize - 2) * self.transfer_size as usize;
+        let alt = &self.alts[self.current_alt];
+        let bank_index = match alt.check_range(address, 1, Accessibility::READ) {
+            Some(ix) => ix,
+            None => {
+                self.fail(DfuStatus::ErrTarget);
+                return Err(TransportError::Pipe);
+            }
+        };
+
+        // Read until the end of the bank, ending with a short frame there
+        let bank = &alt.banks[bank_index];
+        let offset = address - bank.address;
+        let count = buf.len().min(bank.memory.len() - offset);
+        buf[..count].copy_from_slice(&bank.memory[offset..offset + count]);
+
+        self.state = if count < buf.len() {
+            DfuState::DfuIdle
+        } else {
+            DfuState::DfuUploadIdle
+        };
+        Ok(count)
+    }
+
+    /// Builds the device descriptor
+    fn device_descriptor(&self) -> Vec<u8> {
+        let vid = self.vendor_id.to_le_bytes();
+        let pid = self.product_id.to_le_bytes();
+        vec![18, DESCRIPTOR_TYPE_DEVICE, 0x00, 0x01, 0, 0, 0, 64, vid[0], vid[1], pid[0], pid[1], 0x00, 0x22, 1, 2, 3, 1]
+    }
+
+    /// Builds the configuration descriptor, with all alt settings and the functional descriptor
+    fn config_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![9, DESCRIPTOR_TYPE_CONFIG, 0, 0, 1, 1, 0, 0xC0, 50];
+
+        for alt in 0..self.alts.len() {
+            desc.extend_from_slice(&[9, 0x04, 0, alt as u8, 0, 0xFE, 0x01, self.protocol, FIRST_ALT_STRING + alt as u8]);
+        }
+
+        let detach = self.detach_timeout.to_le_bytes();
+        let transfer = self.transfer_size.to_le_bytes();
+        let version = self.dfu_version.to_le_bytes();
+        desc.extend_from_slice(&[9, 0x21, self.attributes, detach[0], detach[1], transfer[0], transfer[1], version[0], version[1]]);
+
+        // Fill in wTotalLength
+        let total = (desc.len() as u16).to_le_bytes();
+        desc[2] = total[0];
+        desc[3] = total[1];
+        desc
+    }
+
+    /// Builds a string descriptor
+    fn string_descriptor(&self, index: u8) -> Option<Vec<u8>> {
+        let string = match index {
+            0 => return Some(vec![4, DESCRIPTOR_TYPE_STRING, 0x09, 0x04]),
+            1 => &self.manufacturer,
+            2 => &self.product,
+            3 => &self.serial,
+            ix => &self.alts.get((ix - FIRST_ALT_STRING) as usize)?.name,
+        };
+
+        let mut desc = vec![0, DESCRIPTOR_TYPE_STRING];
+        for unit in string.encode_utf16() {
+            desc.extend_from_slice(&unit.to_le_bytes());
+        }
+        desc[0] = desc.len() as u8;
+        Some(desc)
+    }
+}
+
+impl DfuTransport for SimDevice {
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        _index: u16,
+        data: &[u8],
+    ) -> Result<usize, TransportError> {
+        if self.detached {
+            return Err(TransportError::NoDevice);
+        }
+
+        // SET_INTERFACE selects the alt setting
+        if request_type == 0x01 && request == 0x0B {
+            if value as usize >= self.alts.len() {
+                return Err(TransportError::Pipe);
+            }
+            self.current_alt = value as usize;
+            return Ok(0);
+        }
+
+        match (request, self.state) {
+            (DFU_DETACH, DfuState::AppIdle) => {
+                // Devices with bitWillDetach leave the bus by themselves
+                if self.attributes & 0x08 != 0 {
+                    self.detached = true;
+                } else {
+                    self.state = DfuState::AppDetach;
+                }
+            }
+            (DFU_DNLOAD, DfuState::DfuIdle) | (DFU_DNLOAD, DfuState::DfuDnloadIdle) => {
+                if data.len() > self.transfer_size as usize {
+                    self.fail(DfuStatus::ErrStalledPkt);
+                    return Err(TransportError::Pipe);
+                }
+                let operation = self.decode_download(value, data);
+                self.state = match operation {
+                    Operation::Manifest => DfuState::DfuManifestSync,
+                    _ => DfuState::DfuDnloadSync,
+                };
+                self.pending = Some(operation);
+            }
+            (DFU_CLRSTATUS, DfuState::DfuError) => {
+                self.status = DfuStatus::Ok;
+                self.state = DfuState::DfuIdle;
+            }
+            (DFU_ABORT, _) => {
+                self.pending = None;
+                self.state = DfuState::DfuIdle;
+            }
+            _ => {
+                self.fail(DfuStatus::ErrStalledPkt);
+                return Err(TransportError::Pipe);
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        _index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransportError> {
+        if self.detached && self.state != DfuState::DfuManifestWaitReset {
+            return Err(TransportError::NoDevice);
+        }
+
+        // Standard GET_DESCRIPTOR requests
+        if request_type == 0x80 && request == 0x06 {
+            let desc = match (value >> 8) as u8 {
+                DESCRIPTOR_TYPE_DEVICE => self.device_descriptor(),
+                DESCRIPTOR_TYPE_CONFIG => self.config_descriptor(),
+                DESCRIPTOR_TYPE_STRING => self.string_descriptor(value as u8).ok_or(TransportError::Pipe)?,
+                _ => return Err(TransportError::Pipe),
+            };
+            let count = desc.len().min(buf.len());
+            buf[..count].copy_from_slice(&desc[..count]);
+            return Ok(count);
+        }
+
+        match (request, self.state) {
+            (DFU_GETSTATUS, _) => {
+                let status = self.get_status();
+                // Once the wait-reset state has been reported, the device is gone
+                if self.detached {
+                    self.state = DfuState::AppIdle;
+                }
+                let count = status.len().min(buf.len());
+                buf[..count].copy_from_slice(&status[..count]);
+                Ok(count)
+            }
+            (DFU_GETSTATE, _) => {
+                buf[0] = self.state as u8;
+                Ok(1)
+            }
+            (DFU_UPLOAD, DfuState::DfuIdle) | (DFU_UPLOAD, DfuState::DfuUploadIdle) => self.upload(value, buf),
+            _ => {
+                self.fail(DfuStatus::ErrStalledPkt);
+                Err(TransportError::Pipe)
+            }
+        }
+    }
+
+    fn delay(&mut self, ms: u32) {
+        self.elapsed_ms += ms;
+    }
+
+    fn reset(&mut self) -> Result<(), TransportError> {
+        if self.detached {
+            return Err(TransportError::NoDevice);
+        }
+
+        // A reset after manifestation starts the application, and a reset after detach
+        // enters DFU mode
+        if self.state == DfuState::DfuManifestWaitReset || self.state == DfuState::AppDetach {
+            self.detached = true;
+        }
+        self.state = DfuState::DfuIdle;
+        self.status = DfuStatus::Ok;
+        self.pending = None;
+        Ok(())
+    }
+}
+
+/// Reads a little endian 32 bit address
+fn read_address(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::dfu::Dfu;
+
+    #[test]
+    fn test_descriptors_and_strings() {
+        let mut device = SimDevice::stm32f4();
+
+        let mut buf = [0u8; 255];
+        let count = device.read_descriptor(DESCRIPTOR_TYPE_CONFIG, 0, &mut buf).unwrap();
+        assert_eq!(9 + 4 * 9 + 9, count);
+        assert_eq!(count, u16::from_le_bytes([buf[2], buf[3]]) as usize);
+
+        assert_eq!("STMicroelectronics", device.read_string(1).unwrap());
+        assert_eq!(STM32F4_ALT_SETTINGS[1], device.read_string(5).unwrap());
+        assert_eq!(Err(TransportError::Pipe), device.read_string(20));
+    }
+
+    #[test]
+    fn test_dfuse_erase_write_and_read() {
+        let mut device = SimDevice::stm32f4();
+        device.write_memory(0, 0x0800_4000, &[0x00; 16]);
+
+        {
+            let mut dfu = Dfu::new(&mut device, 0, 2048);
+            dfu.download_block(0, &[DFUSE_CMD_SET_ADDRESS_POINTER, 0x00, 0x40, 0x00, 0x08]).unwrap();
+            dfu.download_block(0, &[DFUSE_CMD_ERASE, 0x00, 0x40, 0x00, 0x08]).unwrap();
+            dfu.download_block(2, &[0x12, 0x34, 0x56, 0x78]).unwrap();
+            dfu.download_block(3, &[0x9A]).unwrap();
+            dfu.abort().unwrap();
+
+            let mut buf = [0u8; 4];
+            assert_eq!(4, dfu.upload(2, &mut buf).unwrap());
+            assert_eq!([0x12, 0x34, 0x56, 0x78], buf);
+            dfu.abort().unwrap();
+        }
+
+        assert_eq!(vec![0x9A], device.read_memory(0, 0x0800_4800, 1));
+        assert_eq!(vec![0xFF; 4], device.read_memory(0, 0x0800_4004, 4));
+        assert_eq!(1, device.erase_count());
+        assert_eq!(0, device.early_polls());
+        // Erasing a 16K page reports a 256 ms poll timeout
+        assert!(device.elapsed_ms() >= 256);
+    }
+
+    #[test]
+    fn test_dfuse_rejects_invalid_address() {
+        let mut device = SimDevice::stm32f4();
+        let mut dfu = Dfu::new(&mut device, 0, 2048);
+
+        let result = dfu.download_block(0, &[DFUSE_CMD_SET_ADDRESS_POINTER, 0x00, 0x00, 0x00, 0x10]);
+        assert_eq!(
+            Err(crate::usb::dfu::DfuError::Status {
+                status: DfuStatus::ErrTarget,
+                state: DfuState::DfuError
+            }),
+            result
+        );
+
+        // The device can be recovered, and the option bytes are not erasable
+        dfu.ensure_idle().unwrap();
+        dfu.transport_mut().set_alt_setting(0, 1).unwrap();
+        assert!(dfu.download_block(0, &[DFUSE_CMD_ERASE, 0x00, 0xC0, 0xFF, 0x1F]).is_err());
+    }
+}
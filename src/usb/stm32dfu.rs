@@ -94,7 +94,7 @@ PageSection have the following format
 SizeType is either:
 - "M" - Mebi, or 1024 * 1024
 - "K" - Kibi, or 1024
-- " " - Just bytes
+- " " or "B" - Just bytes
 
 The last char indicates the accessibility of the memory
 – a (0x41): Readable
@@ -107,9 +107,23 @@ The last char indicates the accessibility of the memory
 
 */
 
-use crate::util::memory::{Accessibility, Bank, MemoryMap, Sector};
+use core::fmt;
+
+use super::dfu::{Dfu, DfuError, DfuStatus};
+use super::transport::DfuTransport;
+use crate::image::firmware::{FirmwareError, FirmwareImage};
+use crate::util::memory::{Accessibility, Bank, Block, MemoryError, MemoryMap, Sector};
 use crate::util::parse;
 
+/// DfuSe commands, sent as the first byte of a DNLOAD with wBlockNum 0
+pub const DFUSE_CMD_GET_COMMANDS: u8 = 0x00;
+pub const DFUSE_CMD_SET_ADDRESS_POINTER: u8 = 0x21;
+pub const DFUSE_CMD_ERASE: u8 = 0x41;
+pub const DFUSE_CMD_READ_UNPROTECT: u8 = 0x92;
+
+/// Data blocks start at wBlockNum 2, located at: pointer + (wBlockNum - 2) * wTransferSize
+const DFUSE_FIRST_DATA_BLOCK: u16 = 2;
+
 #[derive(Debug)]
 pub enum DefParseError {
     InvalidStartChar,
@@ -233,7 +247,7 @@ fn parse_sector_layout(
     block_size *= match size_multiplier_char {
         'M' => 1024 * 1024,
         'K' => 1024,
-        ' ' => 0,
+        ' ' | 'B' => 1,
         _ => return Err(DefParseError::InvalidSectorDefinition),
     };
 
@@ -267,6 +281,399 @@ fn parse_sector_layout(
     ))
 }
 
+/// Errors produced by a DfuSe session
+#[derive(Debug, Clone, PartialEq)]
+pub enum DfuSeError {
+    /// The DFU layer failed
+    Dfu(DfuError),
+    /// The image data could not be combined
+    Image(FirmwareError),
+    /// The address is not part of the memory map
+    AddressNotMapped(usize),
+    /// The sector at the given address does not support the required access
+    AccessDenied { address: usize, access: Accessibility },
+    /// The range reaches past the end of the address space
+    RangeOverflow { address: usize, length: usize },
+    /// The device rejects commands, as readout protection is active
+    ReadProtected,
+    /// The device ended the upload with a short frame, before all bytes were read
+    ShortUpload { address: usize, missing: usize },
+}
+
+impl fmt::Display for DfuSeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DfuSeError::Dfu(e) => write!(f, "{}", e),
+            DfuSeError::Image(e) => write!(f, "{}", e),
+            DfuSeError::AddressNotMapped(address) => {
+                write!(f, "Address 0x{:08X} is not part of the memory map", address)
+            }
+            DfuSeError::AccessDenied { address, access } => {
+                write!(f, "Memory at 0x{:08X} does not support [{}]", address, access)
+            }
+            DfuSeError::RangeOverflow { address, length } => {
+                write!(f, "Range of 0x{:X} bytes at 0x{:08X} reaches past the end of the address space", length, address)
+            }
+            DfuSeError::ReadProtected => write!(f, "The device rejects commands, as readout protection is active"),
+            DfuSeError::ShortUpload { address, missing } => {
+                write!(f, "The upload ended early at 0x{:08X}, [0x{:X} bytes] short", address, missing)
+            }
+        }
+    }
+}
+
+impl From<DfuError> for DfuSeError {
+    fn from(e: DfuError) -> Self {
+        DfuSeError::Dfu(e)
+    }
+}
+
+impl From<FirmwareError> for DfuSeError {
+    fn from(e: FirmwareError) -> Self {
+        DfuSeError::Image(e)
+    }
+}
+
+impl From<MemoryError> for DfuSeError {
+    fn from(e: MemoryError) -> Self {
+        match e {
+            MemoryError::AddressNotMapped(address) => DfuSeError::AddressNotMapped(address),
+            MemoryError::AccessDenied { address, access } => DfuSeError::AccessDenied { address, access },
+            MemoryError::RangeOverflow { address, length } => DfuSeError::RangeOverflow { address, length },
+        }
+    }
+}
+
+/// The differences found in a block when verifying memory. Differences are counted per
+/// block, the smallest erasable unit of the memory map, and not per [`Sector`] of the map,
+/// which groups equally sized blocks. On STM32 devices, a block is a flash sector, and the
+/// block index is the flash sector number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockMismatch {
+    /// The index of the block
+    pub block: usize,
+    /// The base address of the block
+    pub block_address: usize,
+    /// The first address holding an unexpected value
+    pub first_address: usize,
+    /// The number of bytes holding unexpected values
+    pub count: usize,
+}
+
+/// The result of comparing device memory with the expected data
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VerifyReport {
+    /// The blocks holding unexpected data, in address order
+    pub mismatches: Vec<BlockMismatch>,
+    /// The address ranges not verified because the memory is not readable, as start address and length
+    pub skipped: Vec<(usize, usize)>,
+}
+
+impl VerifyReport {
+    /// Checks if all the verified memory holds the expected data
+    pub fn is_match(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Adds the findings of another report, such as the report of another segment. The counts
+    /// of a block found in both reports are added up, so each block is listed once.
+    pub fn merge(&mut self, other: VerifyReport) {
+        for mismatch in other.mismatches {
+            match self.mismatches.iter_mut().find(|m| m.block_address == mismatch.block_address) {
+                Some(existing) => {
+                    existing.first_address = existing.first_address.min(mismatch.first_address);
+                    existing.count += mismatch.count;
+                }
+                None => self.mismatches.push(mismatch),
+            }
+        }
+        self.mismatches.sort_by_key(|m| m.block_address);
+        self.skipped.extend(other.skipped);
+        self.skipped.sort_unstable();
+    }
+}
+
+impl fmt::Display for BlockMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block [{}] @ [0x{:08X}]: [{}] bytes differ, first at [0x{:08X}]",
+            self.block, self.block_address, self.count, self.first_address)
+    }
+}
+
+/// A session with an STM32 DfuSe bootloader, for a single alt setting.
+/// The memory map parsed from the alt setting string is used to validate
+/// accesses, and to determine which pages to erase.
+pub struct Stm32DfuSe<'a, T: DfuTransport> {
+    /// The DFU driver used to send the requests
+    dfu: Dfu<T>,
+    /// The memory layout of the selected alt setting
+    memory_map: MemoryMap<'a>,
+}
+
+impl<'a, T: DfuTransport> Stm32DfuSe<'a, T> {
+    /// Creates a new session from a DFU driver and the memory map of the selected alt setting
+    pub fn new(dfu: Dfu<T>, memory_map: MemoryMap<'a>) -> Self {
+        Stm32DfuSe { dfu, memory_map }
+    }
+
+    /// Provides access to the memory map
+    pub fn memory_map(&self) -> &MemoryMap<'a> {
+        &self.memory_map
+    }
+
+    /// Provides access to the DFU driver
+    pub fn dfu_mut(&mut self) -> &mut Dfu<T> {
+        &mut self.dfu
+    }
+
+    /// Consumes the session, returning the DFU driver
+    pub fn into_dfu(self) -> Dfu<T> {
+        self.dfu
+    }
+
+    /// Sends a command through DNLOAD block 0, and waits for it to complete
+    fn command(&mut self, command: u8, address: Option<usize>) -> Result<(), DfuSeError> {
+        let mut data = vec![command];
+        if let Some(address) = address {
+            data.extend_from_slice(&(address as u32).to_le_bytes());
+        }
+        self.dfu.download_block(0, &data)?;
+        Ok(())
+    }
+
+    /// Sets the address pointer used by the following data blocks
+    pub fn set_address_pointer(&mut self, address: usize) -> Result<(), DfuSeError> {
+        self.command(DFUSE_CMD_SET_ADDRESS_POINTER, Some(address))
+    }
+
+    /// Erases the page containing the given address
+    pub fn erase_page(&mut self, address: usize) -> Result<(), DfuSeError> {
+        self.command(DFUSE_CMD_ERASE, Some(address))
+    }
+
+    /// Erases all the erasable memory of the device
+    pub fn mass_erase(&mut self) -> Result<(), DfuSeError> {
+        self.command(DFUSE_CMD_ERASE, None)
+    }
+
+    /// Removes the readout protection. The device mass erases its flash and
+    /// resets as a consequence.
+    pub fn read_unprotect(&mut self) -> Result<(), DfuSeError> {
+        self.command(DFUSE_CMD_READ_UNPROTECT, None)
+    }
+
+    /// Checks that the device accepts commands, by setting the address pointer to the start of
+    /// the memory. With readout protection active, the device answers with errVENDOR or errTARGET
+    /// to every command but read unprotect.
+    pub fn check_protection(&mut self) -> Result<(), DfuSeError> {
+        let address = match self.memory_map.banks().iter().flat_map(|bank| bank.sectors()).next() {
+            Some(sector) => sector.address,
+            None => return Ok(()),
+        };
+        self.dfu.ensure_idle()?;
+
+        match self.set_address_pointer(address) {
+            Err(DfuSeError::Dfu(DfuError::Status { status: DfuStatus::ErrVendor, .. }))
+            | Err(DfuSeError::Dfu(DfuError::Status { status: DfuStatus::ErrTarget, .. })) => {
+                // Leave the device ready for the read unprotect command
+                self.dfu.ensure_idle()?;
+                Err(DfuSeError::ReadProtected)
+            }
+            result => result,
+        }
+    }
+
+    /// Erases all pages touched by the given address range
+    /// # Return
+    /// The number of pages erased
+    pub fn erase_range(&mut self, address: usize, length: usize) -> Result<usize, DfuSeError> {
+        let blocks = self.memory_map.blocks_in_range(address, length, Accessibility::ERASE)?;
+        self.erase_blocks(&blocks)?;
+        Ok(blocks.len())
+    }
+
+    /// Erases the given blocks, such as the blocks of an erase plan
+    pub fn erase_blocks(&mut self, blocks: &[Block]) -> Result<(), DfuSeError> {
+        if let Some(block) = blocks.iter().find(|b| !b.is_accessible(Accessibility::ERASE)) {
+            return Err(DfuSeError::AccessDenied {
+                address: block.address,
+                access: Accessibility::ERASE,
+            });
+        }
+        self.dfu.ensure_idle()?;
+
+        for block in blocks {
+            self.erase_page(block.address)?;
+        }
+        Ok(())
+    }
+
+    /// Writes data to already erased memory, in wTransferSize chunks
+    pub fn write(&mut self, address: usize, data: &[u8]) -> Result<(), DfuSeError> {
+        self.memory_map.blocks_in_range(address, data.len(), Accessibility::WRITE)?;
+        self.dfu.ensure_idle()?;
+
+        let transfer_size = self.dfu.transfer_size();
+        let mut block = DFUSE_FIRST_DATA_BLOCK;
+        let mut block_address = address;
+        self.set_address_pointer(address)?;
+
+        for chunk in data.chunks(transfer_size) {
+            // The block number would overflow, so move the pointer and start over
+            if block == u16::MAX {
+                self.set_address_pointer(block_address)?;
+                block = DFUSE_FIRST_DATA_BLOCK;
+            }
+
+            self.dfu.download_block(block, chunk)?;
+            block += 1;
+            block_address += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Erases the pages touched by the data, and writes it. Memory which can not be erased
+    /// is written directly.
+    pub fn download(&mut self, address: usize, data: &[u8]) -> Result<(), DfuSeError> {
+        let plan = self.memory_map.erase_plan(&[(address, data.len())])?;
+        self.erase_blocks(&plan.blocks)?;
+        self.write(address, data)
+    }
+
+    /// Reads memory from the device, in wTransferSize chunks
+    pub fn read(&mut self, address: usize, length: usize) -> Result<Vec<u8>, DfuSeError> {
+        self.memory_map.blocks_in_range(address, length, Accessibility::READ)?;
+        self.dfu.ensure_idle()?;
+        self.set_address_pointer(address)?;
+
+        // Uploads are only accepted from dfuIDLE
+        self.dfu.abort()?;
+
+        let transfer_size = self.dfu.transfer_size();
+        let mut data = Vec::with_capacity(length);
+        let mut buf = vec![0u8; transfer_size];
+        let mut block = DFUSE_FIRST_DATA_BLOCK;
+
+        while data.len() < length {
+            if block == u16::MAX {
+                let next_address = address + data.len();
+                self.dfu.ensure_idle()?;
+                self.set_address_pointer(next_address)?;
+                self.dfu.abort()?;
+                block = DFUSE_FIRST_DATA_BLOCK;
+            }
+
+            // Only ask for the bytes still needed, so the last upload does not read past the range
+            let request = transfer_size.min(length - data.len());
+            let count = self.dfu.upload(block, &mut buf[..request])?;
+            data.extend_from_slice(&buf[..count]);
+
+            if count < request {
+                self.dfu.abort()?;
+                return Err(DfuSeError::ShortUpload {
+                    address: address + data.len(),
+                    missing: length - data.len(),
+                });
+            }
+            block += 1;
+        }
+
+        self.dfu.abort()?;
+        Ok(data)
+    }
+
+    /// Reads back memory and compares it with the expected data, counting the differences
+    /// per block. Blocks which are not readable are skipped, and listed in the report.
+    pub fn verify(&mut self, address: usize, data: &[u8]) -> Result<VerifyReport, DfuSeError> {
+        let mut report = VerifyReport::default();
+        let mut offset = 0;
+
+        while offset < data.len() {
+            let current = address + offset;
+            let block = self.memory_map.find_block(current).ok_or(DfuSeError::AddressNotMapped(current))?;
+            let length = (block.end_address() - current).min(data.len() - offset);
+            let expected = &data[offset..offset + length];
+            offset += length;
+
+            if !block.is_accessible(Accessibility::READ) {
+                // Join with the previous range, if it ends here
+                match report.skipped.last_mut() {
+                    Some((start, size)) if *start + *size == current => *size += length,
+                    _ => report.skipped.push((current, length)),
+                }
+                continue;
+            }
+
+            // Missing bytes in a short read are counted as differences
+            let actual = self.read(current, length)?;
+            let differs = |i: &usize| actual.get(*i) != Some(&expected[*i]);
+            let count = (0..length).filter(differs).count();
+            if let Some(first) = (0..length).find(differs) {
+                report.mismatches.push(BlockMismatch {
+                    block: block.index,
+                    block_address: block.address,
+                    first_address: current + first,
+                    count,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reads back the blocks, and returns the ones where the memory differs from the image.
+    /// Only the bytes covered by the image are compared. Blocks which are not readable can
+    /// not be compared, and are always returned.
+    pub fn changed_blocks(&mut self, blocks: &[Block], image: &FirmwareImage) -> Result<Vec<Block>, DfuSeError> {
+        let mut changed: Vec<Block> = Vec::new();
+
+        for block in blocks {
+            if !block.is_accessible(Accessibility::READ) {
+                changed.push(*block);
+                continue;
+            }
+
+            let actual = self.read(block.address, block.size)?;
+            let expected = image.slice(block.address, block.size).ok_or(DfuSeError::AddressNotMapped(block.address))?;
+            let differs = expected.segments().iter().any(|segment| {
+                let offset = segment.address - block.address;
+                actual.get(offset..offset + segment.data.len()) != Some(&segment.data[..])
+            });
+            if differs {
+                changed.push(*block);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Finds what to erase and write in differential mode. Only the blocks to erase which differ
+    /// from the image are erased, and the image data in them is written. Image data in memory
+    /// which is not erased, such as option bytes, can not be compared, and is always written.
+    /// # Arguments
+    /// * `blocks` - The blocks the image needs erased, such as the blocks of an erase plan
+    /// * `image` - The image to download
+    ///
+    /// # Return
+    /// The blocks to erase, and the part of the image to write
+    pub fn plan_differential(&mut self, blocks: &[Block], image: &FirmwareImage) -> Result<(Vec<Block>, FirmwareImage), DfuSeError> {
+        let changed = self.changed_blocks(blocks, image)?;
+
+        let mut write_blocks = changed.clone();
+        for segment in image.segments() {
+            let touched = self.memory_map.blocks_in_range(segment.address, segment.data.len(), Accessibility::empty())?;
+            write_blocks.extend(touched.into_iter().filter(|b| !b.is_accessible(Accessibility::ERASE)));
+        }
+        write_blocks.sort_by_key(|b| b.address);
+        write_blocks.dedup();
+
+        let mut write_image = FirmwareImage::new();
+        for block in &write_blocks {
+            let part = image.slice(block.address, block.size).ok_or(DfuSeError::AddressNotMapped(block.address))?;
+            write_image.merge(part)?;
+        }
+        Ok((changed, write_image))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,4 +725,241 @@ mod tests {
         assert_eq!(0x8020000, sectors[2].address);
         assert_eq!(0x60000, sectors[2].total_size());
     }
+
+    #[test]
+    fn test_parse_byte_sized_sectors() {
+        let memmap = parse_memory_layout_string("@OTP Memory /0x1FFF7800/01*512 e,01*016 e").unwrap();
+        let sectors = memmap.banks()[0].sectors();
+
+        assert_eq!(512, sectors[0].block_size);
+        assert_eq!(16, sectors[1].block_size);
+        assert_eq!(0x1FFF7A00, sectors[1].address);
+        assert!(sectors[1].is_accessible(Accessibility::READ_WRITE));
+
+        // "B" gives the size in bytes as well
+        let memmap = parse_memory_layout_string("@Option Bytes  /0x1FFFC000/01*016Be").unwrap();
+        assert_eq!(16, memmap.banks()[0].sectors()[0].total_size());
+        assert!(parse_memory_layout_string("@Option Bytes  /0x1FFFC000/01*016Xe").is_err());
+    }
+
+    #[test]
+    fn test_dfuse_download_erases_touched_pages() {
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+
+        let mut device = SimDevice::stm32f4();
+        device.write_memory(0, 0x0800_0000, &[0x00; 0xC000]);
+
+        let firmware: Vec<u8> = (0..0x1000u32).map(|v| v as u8).collect();
+        {
+            let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[0]).unwrap();
+            let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+
+            // Crosses the border between page 0 and page 1
+            dfuse.download(0x0800_3800, &firmware).unwrap();
+            assert_eq!(firmware, dfuse.read(0x0800_3800, firmware.len()).unwrap());
+        }
+
+        assert_eq!(2, device.erase_count());
+        assert_eq!(firmware, device.read_memory(0, 0x0800_3800, firmware.len()));
+        assert_eq!(vec![0xFF; 0x800], device.read_memory(0, 0x0800_4800, 0x800));
+        assert_eq!(vec![0x00; 16], device.read_memory(0, 0x0800_8000, 16));
+        assert_eq!(0, device.early_polls());
+    }
+
+    #[test]
+    fn test_dfuse_download_file_with_option_bytes_target() {
+        use crate::image::dfuse::{self, DfuSeTarget};
+        use crate::image::Segment;
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+        use crate::usb::transport::DfuTransport;
+
+        let targets = vec![
+            DfuSeTarget {
+                alt_setting: 0,
+                name: Some("ST...".to_string()),
+                elements: vec![Segment::new(0x0800_0000, vec![0x11; 0x100])],
+            },
+            DfuSeTarget {
+                alt_setting: 1,
+                name: Some("Option Bytes".to_string()),
+                elements: vec![Segment::new(0x1FFF_C000, vec![0xAA; 16])],
+            },
+        ];
+        let file = dfuse::parse(&dfuse::write(&targets, 0x0483, 0xDF11, 0xFFFF)).unwrap();
+
+        // The option bytes can not be erased, so they are written directly
+        let mut device = SimDevice::stm32f4();
+        for target in &file.targets {
+            let alt = target.find_alt_setting(&STM32F4_ALT_SETTINGS).unwrap();
+            device.set_alt_setting(0, alt).unwrap();
+
+            let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[alt as usize]).unwrap();
+            let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+            for element in &target.elements {
+                dfuse.download(element.address, &element.data).unwrap();
+            }
+        }
+
+        assert_eq!(1, device.erase_count());
+        assert_eq!(vec![0x11; 0x100], device.read_memory(0, 0x0800_0000, 0x100));
+        assert_eq!(vec![0xAA; 16], device.read_memory(1, 0x1FFF_C000, 16));
+    }
+
+    #[test]
+    fn test_dfuse_rejects_unmapped_and_protected_memory() {
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+
+        let mut device = SimDevice::stm32f4();
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[0]).unwrap();
+        let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+
+        assert_eq!(
+            Err(DfuSeError::AddressNotMapped(0x0808_0000)),
+            dfuse.download(0x0807_FF00, &[0u8; 0x200])
+        );
+
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[1]).unwrap();
+        let mut dfuse = Stm32DfuSe::new(dfuse.into_dfu(), memmap);
+        assert_eq!(
+            Err(DfuSeError::AccessDenied {
+                address: 0x1FFF_C000,
+                access: Accessibility::ERASE
+            }),
+            dfuse.erase_range(0x1FFF_C000, 16)
+        );
+    }
+
+    #[test]
+    fn test_dfuse_read_requests_only_the_remaining_bytes() {
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+        use crate::usb::transport::DfuTransport;
+
+        let mut device = SimDevice::stm32f4();
+        device.set_alt_setting(0, 1).unwrap();
+        {
+            let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[1]).unwrap();
+            let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+            assert_eq!(16, dfuse.read(0x1FFF_C000, 16).unwrap().len());
+        }
+        assert_eq!(16, device.largest_upload());
+
+        // The memory map claims more than the device returns
+        let memmap = parse_memory_layout_string("@Option Bytes  /0x1FFFC000/01*032 e").unwrap();
+        let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+        assert_eq!(
+            Err(DfuSeError::ShortUpload { address: 0x1FFF_C010, missing: 16 }),
+            dfuse.read(0x1FFF_C000, 32)
+        );
+    }
+
+    #[test]
+    fn test_dfuse_verify_reports_blocks_and_skips_unreadable() {
+        use crate::usb::sim::SimDevice;
+
+        let layout = "@Internal Flash  /0x08000000/02*016Kg,01*016Kf";
+        let mut device = SimDevice::new(0x0483, 0xDF11, &[layout]);
+        let firmware = vec![0x5A; 0xA000];
+        device.write_memory(0, 0x0800_0000, &firmware);
+        device.write_memory(0, 0x0800_0010, &[0x00, 0x5A, 0x00]);
+        device.write_memory(0, 0x0800_7FFF, &[0x00]);
+
+        let memmap = parse_memory_layout_string(layout).unwrap();
+        let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+        let report = dfuse.verify(0x0800_0000, &firmware).unwrap();
+
+        assert!(!report.is_match());
+        assert_eq!(
+            vec![
+                BlockMismatch { block: 0, block_address: 0x0800_0000, first_address: 0x0800_0010, count: 2 },
+                BlockMismatch { block: 1, block_address: 0x0800_4000, first_address: 0x0800_7FFF, count: 1 },
+            ],
+            report.mismatches
+        );
+        assert_eq!(vec![(0x0800_8000, 0x2000)], report.skipped);
+
+        assert!(dfuse.verify(0x0800_4000, &firmware[..0x100]).unwrap().is_match());
+
+        // Two segments in the same block are counted together
+        let mut merged = dfuse.verify(0x0800_0000, &firmware[..0x11]).unwrap();
+        merged.merge(dfuse.verify(0x0800_0012, &firmware[0x12..0x100]).unwrap());
+        assert_eq!(
+            vec![BlockMismatch { block: 0, block_address: 0x0800_0000, first_address: 0x0800_0010, count: 2 }],
+            merged.mismatches
+        );
+    }
+
+    #[test]
+    fn test_dfuse_changed_blocks() {
+        use crate::image::Segment;
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+
+        let mut device = SimDevice::stm32f4();
+        device.write_memory(0, 0x0800_0000, &[0x11; 0xC000]);
+        device.write_memory(0, 0x0800_4000, &[0x22; 0x10]);
+
+        // Block 0 matches, block 1 differs and only the covered part of block 2 is compared
+        let image = FirmwareImage::from_segments(vec![Segment::new(0x0800_0000, vec![0x11; 0x9000])]).unwrap();
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[0]).unwrap();
+        let blocks = memmap.erase_plan(&[(0x0800_0000, 0x9000)]).unwrap().blocks;
+
+        let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+        let changed = dfuse.changed_blocks(&blocks, &image).unwrap();
+        assert_eq!(vec![blocks[1]], changed);
+        assert_eq!(0, device.erase_count());
+    }
+
+    #[test]
+    fn test_dfuse_plan_differential() {
+        use crate::image::Segment;
+        use crate::usb::sim::SimDevice;
+
+        let layout = "@Internal Flash  /0x08000000/02*016Kg,01*016Ke";
+        let mut device = SimDevice::new(0x0483, 0xDF11, &[layout]);
+        device.write_memory(0, 0x0800_0000, &[0x11; 0x4000]);
+        device.write_memory(0, 0x0800_8000, &[0x33; 0x100]);
+
+        // Block 0 matches, block 1 differs, and block 2 can not be erased
+        let image = FirmwareImage::from_segments(vec![
+            Segment::new(0x0800_3000, vec![0x11; 0x2000]),
+            Segment::new(0x0800_8000, vec![0x33; 0x100]),
+        ])
+        .unwrap();
+        let memmap = parse_memory_layout_string(layout).unwrap();
+        let blocks = memmap.erase_plan(&[(0x0800_3000, 0x2000), (0x0800_8000, 0x100)]).unwrap().blocks;
+        assert_eq!(2, blocks.len());
+
+        let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+        let (erase, write) = dfuse.plan_differential(&blocks, &image).unwrap();
+        assert_eq!(vec![blocks[1]], erase);
+        assert_eq!(
+            &[Segment::new(0x0800_4000, vec![0x11; 0x1000]), Segment::new(0x0800_8000, vec![0x33; 0x100])],
+            write.segments()
+        );
+        assert_eq!(0, device.erase_count());
+    }
+
+    #[test]
+    fn test_dfuse_detects_and_removes_read_protection() {
+        use crate::usb::sim::{SimDevice, STM32F4_ALT_SETTINGS};
+
+        let mut device = SimDevice::stm32f4();
+        device.write_memory(0, 0x0800_0000, &[0x00; 16]);
+        device.read_protected = true;
+        {
+            let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[0]).unwrap();
+            let mut dfuse = Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap);
+
+            assert_eq!(Err(DfuSeError::ReadProtected), dfuse.check_protection());
+            dfuse.read_unprotect().unwrap();
+        }
+
+        // The flash is mass erased, and the device resets
+        assert!(!device.read_protected);
+        assert!(device.is_detached());
+        assert_eq!(vec![0xFF; 16], device.read_memory(0, 0x0800_0000, 16));
+
+        let mut device = SimDevice::stm32f4();
+        let memmap = parse_memory_layout_string(STM32F4_ALT_SETTINGS[0]).unwrap();
+        assert_eq!(Ok(()), Stm32DfuSe::new(Dfu::new(&mut device, 0, 2048), memmap).check_protection());
+    }
 }
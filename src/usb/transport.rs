@@ -0,0 +1,286 @@
+//! Abstraction of the USB control transfers needed to talk to a DFU interface.
+//!
+//! The DFU protocol is implemented purely through control requests on endpoint 0,
+//! so the driver only needs a way to issue IN and OUT control transfers. Keeping
+//! this behind a trait allows the protocol logic to be exercised without hardware.
+//!
+//! `RusbTransport` implements the trait on top of a libusb device handle.
+
+use core::fmt;
+use std::thread;
+use std::time::Duration;
+
+use rusb::{Device, DeviceHandle, UsbContext};
+
+/// Standard request codes and descriptor types used by the transport
+const REQUEST_TYPE_STANDARD_IN: u8 = 0x80;
+const REQUEST_TYPE_STANDARD_INTERFACE_OUT: u8 = 0x01;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+const REQUEST_SET_INTERFACE: u8 = 0x0B;
+pub const DESCRIPTOR_TYPE_DEVICE: u8 = 0x01;
+pub const DESCRIPTOR_TYPE_CONFIG: u8 = 0x02;
+pub const DESCRIPTOR_TYPE_STRING: u8 = 0x03;
+pub const DESCRIPTOR_TYPE_INTERFACE: u8 = 0x04;
+
+/// Default timeout for control transfers
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors reported by a transport
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    /// The device stalled the request (STALL handshake)
+    Pipe,
+    /// The request timed out
+    Timeout,
+    /// The device is no longer available, e.g. it was detached or reset
+    NoDevice,
+    /// Any other error, described by the given message
+    Other(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Pipe => write!(f, "Request stalled by device"),
+            TransportError::Timeout => write!(f, "Request timed out"),
+            TransportError::NoDevice => write!(f, "Device is no longer available"),
+            TransportError::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Defines the operations a DFU driver needs from the underlying USB connection
+pub trait DfuTransport {
+    /// Performs a control OUT transfer
+    /// # Arguments
+    /// * `request_type` - The bmRequestType field of the setup packet
+    /// * `request` - The bRequest field of the setup packet
+    /// * `value` - The wValue field of the setup packet
+    /// * `index` - The wIndex field of the setup packet
+    /// * `data` - The data stage payload (may be empty)
+    ///
+    /// # Return
+    /// The number of bytes transferred
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<usize, TransportError>;
+
+    /// Performs a control IN transfer
+    /// # Arguments
+    /// * `request_type` - The bmRequestType field of the setup packet
+    /// * `request` - The bRequest field of the setup packet
+    /// * `value` - The wValue field of the setup packet
+    /// * `index` - The wIndex field of the setup packet
+    /// * `buf` - The buffer to receive data into. Its length is used as wLength
+    ///
+    /// # Return
+    /// The number of bytes received
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransportError>;
+
+    /// Waits the given number of milliseconds. Used when honoring bwPollTimeout.
+    /// The default implementation sleeps the calling thread.
+    fn delay(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(u64::from(ms)));
+    }
+
+    /// Reads a standard descriptor using GET_DESCRIPTOR
+    /// # Arguments
+    /// * `descriptor_type` - The descriptor type, e.g. `DESCRIPTOR_TYPE_CONFIG`
+    /// * `index` - The descriptor index
+    /// * `buf` - The buffer to read the descriptor into
+    fn read_descriptor(&mut self, descriptor_type: u8, index: u8, buf: &mut [u8]) -> Result<usize, TransportError> {
+        let value = u16::from(descriptor_type) << 8 | u16::from(index);
+        self.control_in(REQUEST_TYPE_STANDARD_IN, REQUEST_GET_DESCRIPTOR, value, 0, buf)
+    }
+
+    /// Reads and decodes a string descriptor, using the first language supported by the device
+    /// # Arguments
+    /// * `index` - The string descriptor index
+    fn read_string(&mut self, index: u8) -> Result<String, TransportError> {
+        let mut buf = [0u8; 255];
+
+        // String descriptor zero holds the list of supported language IDs
+        let count = self.read_descriptor(DESCRIPTOR_TYPE_STRING, 0, &mut buf)?;
+        if count < 4 {
+            return Err(TransportError::Other("Device reports no string languages".to_string()));
+        }
+        let language = u16::from_le_bytes([buf[2], buf[3]]);
+
+        // Then read the string itself using the language
+        let value = u16::from(DESCRIPTOR_TYPE_STRING) << 8 | u16::from(index);
+        let count = self.control_in(REQUEST_TYPE_STANDARD_IN, REQUEST_GET_DESCRIPTOR, value, language, &mut buf)?;
+        if count < 2 || count % 2 != 0 || usize::from(buf[0]) != count {
+            return Err(TransportError::Other(format!("Malformed string descriptor [{}]", index)));
+        }
+
+        // Decode the UTF-16LE payload following the length and type bytes
+        let utf16: Vec<u16> = buf[2..count]
+            .chunks(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        Ok(String::from_utf16_lossy(&utf16))
+    }
+
+    /// Issues a USB port reset. Transports without reset support return an error.
+    fn reset(&mut self) -> Result<(), TransportError> {
+        Err(TransportError::Other("USB reset is not supported by the transport".to_string()))
+    }
+
+    /// Selects the given alternate setting of an interface
+    fn set_alt_setting(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransportError> {
+        self.control_out(
+            REQUEST_TYPE_STANDARD_INTERFACE_OUT,
+            REQUEST_SET_INTERFACE,
+            u16::from(alt_setting),
+            u16::from(interface),
+            &[],
+        )?;
+        Ok(())
+    }
+}
+
+/// Allow a mutable reference to a transport to be used as a transport, so the
+/// driver can borrow a transport instead of consuming it.
+impl<T: DfuTransport + ?Sized> DfuTransport for &mut T {
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<usize, TransportError> {
+        (**self).control_out(request_type, request, value, index, data)
+    }
+
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransportError> {
+        (**self).control_in(request_type, request, value, index, buf)
+    }
+
+    fn delay(&mut self, ms: u32) {
+        (**self).delay(ms)
+    }
+
+    fn read_descriptor(&mut self, descriptor_type: u8, index: u8, buf: &mut [u8]) -> Result<usize, TransportError> {
+        (**self).read_descriptor(descriptor_type, index, buf)
+    }
+
+    fn read_string(&mut self, index: u8) -> Result<String, TransportError> {
+        (**self).read_string(index)
+    }
+
+    fn reset(&mut self) -> Result<(), TransportError> {
+        (**self).reset()
+    }
+
+    fn set_alt_setting(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransportError> {
+        (**self).set_alt_setting(interface, alt_setting)
+    }
+}
+
+impl From<rusb::Error> for TransportError {
+    fn from(e: rusb::Error) -> Self {
+        match e {
+            rusb::Error::Pipe => TransportError::Pipe,
+            rusb::Error::Timeout => TransportError::Timeout,
+            rusb::Error::NoDevice | rusb::Error::NotFound => TransportError::NoDevice,
+            other => TransportError::Other(other.to_string()),
+        }
+    }
+}
+
+/// Transport using a libusb device handle, with the DFU interface claimed
+pub struct RusbTransport<T: UsbContext> {
+    /// The open device handle
+    handle: DeviceHandle<T>,
+    /// The claimed interface number
+    interface: u8,
+    /// Timeout applied to every control transfer
+    timeout: Duration,
+}
+
+impl<T: UsbContext> RusbTransport<T> {
+    /// Opens the given device, and claims the given interface
+    /// # Arguments
+    /// * `device` - The USB device to open
+    /// * `interface` - The number of the DFU interface to claim
+    pub fn open(device: &Device<T>, interface: u8) -> Result<Self, TransportError> {
+        let mut handle = device.open()?;
+        handle.claim_interface(interface)?;
+
+        Ok(RusbTransport {
+            handle,
+            interface,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Sets the timeout used for control transfers
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Gives access to the underlying device handle
+    pub fn handle(&self) -> &DeviceHandle<T> {
+        &self.handle
+    }
+}
+
+impl<T: UsbContext> DfuTransport for RusbTransport<T> {
+    fn control_out(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> Result<usize, TransportError> {
+        Ok(self.handle.write_control(request_type, request, value, index, data, self.timeout)?)
+    }
+
+    fn control_in(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> Result<usize, TransportError> {
+        Ok(self.handle.read_control(request_type, request, value, index, buf, self.timeout)?)
+    }
+
+    fn reset(&mut self) -> Result<(), TransportError> {
+        Ok(self.handle.reset()?)
+    }
+
+    fn set_alt_setting(&mut self, interface: u8, alt_setting: u8) -> Result<(), TransportError> {
+        // Must go through libusb, so it keeps track of the selected setting
+        Ok(self.handle.set_alternate_setting(interface, alt_setting)?)
+    }
+}
+
+impl<T: UsbContext> Drop for RusbTransport<T> {
+    fn drop(&mut self) {
+        // Nothing sensible can be done if this fails, the handle is closed anyway
+        let _ = self.handle.release_interface(self.interface);
+    }
+}
@@ -0,0 +1,29 @@
+//! CRC calculation used by the DFU file suffix.
+
+/// Calculates the CRC32 used in the DFU file suffix.
+///
+/// This is the standard reflected CRC32 (polynomial 0xEDB88320, initial value 0xFFFFFFFF),
+/// but without the final inversion, as defined by the DFU 1.1 specification.
+pub fn dfu_crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dfu_crc32() {
+        // The standard CRC32 check value is 0xCBF43926, this is the same without inversion
+        assert_eq!(!0xCBF4_3926, dfu_crc32(b"123456789"));
+        assert_eq!(0xFFFF_FFFF, dfu_crc32(&[]));
+    }
+}
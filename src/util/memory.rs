@@ -27,6 +27,17 @@ bitflags! {
 }
 
 
+/// Errors when looking up memory in a memory map
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryError {
+    /// The address is not part of the memory map
+    AddressNotMapped(usize),
+    /// The block at the given address does not support the required access
+    AccessDenied { address: usize, access: Accessibility },
+    /// The range reaches past the end of the address space
+    RangeOverflow { address: usize, length: usize },
+}
+
 /// A memory area defines a set of memory banks, which in turn contains 
 /// Banks with sectors consisting of pages
 #[derive(Debug)]
@@ -69,6 +80,28 @@ pub struct Sector {
     pub access: Accessibility
 }
 
+/// A single block of a sector, which is the smallest unit that can be erased
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    /// The index of the block
+    pub index: usize,
+    /// The base address of the block
+    pub address: usize,
+    /// The size of the block
+    pub size: usize,
+    /// The accessibility of the block
+    pub access: Accessibility
+}
+
+/// The blocks to erase before writing a set of address ranges
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ErasePlan {
+    /// The blocks to erase, in address order
+    pub blocks: Vec<Block>,
+    /// The parts of the erased blocks outside the ranges, which are lost by erasing. Given as start address and length
+    pub collateral: Vec<(usize, usize)>,
+}
+
 
 /// Implement display for accessibility
 impl fmt::Display for Accessibility {
@@ -90,6 +123,22 @@ impl fmt::Display for Accessibility {
     }
 }
 
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::AddressNotMapped(address) => {
+                write!(f, "Address 0x{:08X} is not part of the memory map", address)
+            }
+            MemoryError::AccessDenied { address, access } => {
+                write!(f, "Memory at 0x{:08X} does not support [{}]", address, access)
+            }
+            MemoryError::RangeOverflow { address, length } => {
+                write!(f, "Range of 0x{:X} bytes at 0x{:08X} reaches past the end of the address space", length, address)
+            }
+        }
+    }
+}
+
 /// Implement a memory map
 impl<'a> MemoryMap<'a> {
 
@@ -105,6 +154,183 @@ impl<'a> MemoryMap<'a> {
     pub fn banks(&self) -> &[Bank] {
         &self.banks[..]
     }
+
+    /// Returns the continuous address ranges made up of sectors supporting the given access
+    /// # Arguments
+    /// * `access` - The access the sectors must support
+    ///
+    /// # Return
+    /// The start address and length of each range, in address order
+    pub fn regions(&self, access: Accessibility) -> Vec<(usize, usize)> {
+        let mut sectors: Vec<&Sector> = self.banks.iter()
+            .flat_map(|bank| bank.sectors())
+            .filter(|sector| sector.is_accessible(access))
+            .collect();
+        sectors.sort_by_key(|sector| sector.address);
+
+        // Join the sectors following directly after each other
+        let mut regions: Vec<(usize, usize)> = Vec::new();
+        for sector in sectors {
+            push_range(&mut regions, sector.address, sector.total_size());
+        }
+        regions
+    }
+
+    /// Finds the sector containing the given address
+    pub fn find_sector(&self, address: usize) -> Option<&Sector> {
+        self.banks.iter()
+            .flat_map(|bank| bank.sectors())
+            .find(|sector| address >= sector.address && address < sector.address + sector.total_size())
+    }
+
+    /// Finds the block containing the given address
+    pub fn find_block(&self, address: usize) -> Option<Block> {
+        self.find_sector(address)
+            .map(|sector| sector.block((address - sector.address) / sector.block_size))
+    }
+
+    /// Returns every block touched by the given range, making sure all of them support the given access
+    /// # Arguments
+    /// * `address` - The start of the range
+    /// * `length` - The length of the range
+    /// * `access` - The access the blocks must support
+    ///
+    /// # Return
+    /// The blocks in address order
+    pub fn blocks_in_range(&self, address: usize, length: usize, access: Accessibility) -> Result<Vec<Block>, MemoryError> {
+        let end = address.checked_add(length).ok_or(MemoryError::RangeOverflow { address, length })?;
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut current = address;
+
+        while current < end {
+            let block = self.find_block(current).ok_or(MemoryError::AddressNotMapped(current))?;
+            if !block.is_accessible(access) {
+                return Err(MemoryError::AccessDenied { address: current, access });
+            }
+
+            current = block.end_address();
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    /// Returns every block of the memory map, making sure all of them support the given access
+    /// # Arguments
+    /// * `access` - The access the blocks must support
+    ///
+    /// # Return
+    /// The blocks in bank order
+    pub fn blocks(&self, access: Accessibility) -> Result<Vec<Block>, MemoryError> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for bank in &self.banks {
+            blocks.extend(bank.blocks(access)?);
+        }
+        Ok(blocks)
+    }
+
+    /// Finds the regions of another memory map supporting the given access, which are not
+    /// completely inside memory of this map supporting it as well
+    /// # Arguments
+    /// * `other` - The memory map to check
+    /// * `access` - The access of the regions
+    ///
+    /// # Return
+    /// The start address and length of each region outside this map
+    pub fn regions_outside(&self, other: &MemoryMap, access: Accessibility) -> Vec<(usize, usize)> {
+        other.regions(access)
+            .into_iter()
+            .filter(|(address, length)| self.blocks_in_range(*address, *length, access).is_err())
+            .collect()
+    }
+
+    /// Finds the ranges to read, making sure all of them are readable
+    /// # Arguments
+    /// * `address` - The address to start at, or the start of the readable memory if not given
+    /// * `length` - The number of bytes to read. If not given, the continuous readable memory at the
+    ///   address is read to its end, or all the readable memory when no address is given either
+    ///
+    /// # Return
+    /// The start address and length of each range, in address order
+    pub fn read_ranges(&self, address: Option<usize>, length: Option<usize>) -> Result<Vec<(usize, usize)>, MemoryError> {
+        let regions = self.regions(Accessibility::READ);
+        let start = match (address, regions.first()) {
+            (Some(address), _) => address,
+            (None, Some((first, _))) if length.is_some() => *first,
+            (None, _) => return Ok(regions),
+        };
+
+        match length {
+            Some(length) => {
+                self.blocks_in_range(start, length, Accessibility::READ)?;
+                Ok(vec![(start, length)])
+            }
+            None => {
+                // Make sure the address itself is readable, then read to the end of its region
+                self.blocks_in_range(start, 1, Accessibility::READ)?;
+                let (region, size) = regions.into_iter().find(|(region, size)| start >= *region && start - region < *size)
+                    .ok_or(MemoryError::AccessDenied { address: start, access: Accessibility::READ })?;
+                Ok(vec![(start, region + size - start)])
+            }
+        }
+    }
+
+    /// Finds the blocks to erase before writing the given ranges, and the bytes outside
+    /// the ranges which are lost by erasing partially covered blocks. Memory which can not
+    /// be erased, such as option bytes or RAM, is written without erasing.
+    /// # Arguments
+    /// * `ranges` - The ranges to write, as start address and length
+    ///
+    /// # Return
+    /// The erase plan, or an error if a range touches memory which can neither be erased nor written
+    pub fn erase_plan(&self, ranges: &[(usize, usize)]) -> Result<ErasePlan, MemoryError> {
+        let mut ranges: Vec<(usize, usize)> = ranges.iter().filter(|(_, length)| *length > 0).copied().collect();
+        ranges.sort_unstable();
+
+        // The ranges are sorted, so a block shared by two ranges is always the last one added
+        let mut plan = ErasePlan::default();
+        for (address, length) in &ranges {
+            for block in self.blocks_in_range(*address, *length, Accessibility::empty())? {
+                if !block.is_accessible(Accessibility::ERASE) {
+                    if !block.is_accessible(Accessibility::WRITE) {
+                        let denied = block.address.max(*address);
+                        return Err(MemoryError::AccessDenied { address: denied, access: Accessibility::WRITE });
+                    }
+                } else if plan.blocks.last() != Some(&block) {
+                    plan.blocks.push(block);
+                }
+            }
+        }
+
+        // Then collect the parts of each block not covered by any range
+        for block in &plan.blocks {
+            let mut current = block.address;
+            for (address, length) in ranges.iter().filter(|(a, l)| *a < block.end_address() && a + l > block.address) {
+                if *address > current {
+                    push_range(&mut plan.collateral, current, address - current);
+                }
+                current = current.max(address + length);
+            }
+            if current < block.end_address() {
+                push_range(&mut plan.collateral, current, block.end_address() - current);
+            }
+        }
+        Ok(plan)
+    }
+}
+
+/// Adds the range to the list, joining it with the last range if it follows directly after
+fn push_range(ranges: &mut Vec<(usize, usize)>, address: usize, length: usize) {
+    match ranges.last_mut() {
+        Some((start, size)) if *start + *size == address => *size += length,
+        _ => ranges.push((address, length)),
+    }
+}
+
+impl ErasePlan {
+    /// Returns the number of bytes lost by erasing
+    pub fn collateral_size(&self) -> usize {
+        self.collateral.iter().map(|(_, length)| length).sum()
+    }
 }
 
 impl<'a> fmt::Display for MemoryMap<'a> {
@@ -152,6 +378,23 @@ impl Bank {
         &self.sectors[..]
     }
 
+    /// Returns every block of the bank, making sure all of them support the given access
+    /// # Arguments
+    /// * `access` - The access the blocks must support
+    ///
+    /// # Return
+    /// The blocks in sector order
+    pub fn blocks(&self, access: Accessibility) -> Result<Vec<Block>, MemoryError> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for sector in &self.sectors {
+            if !sector.is_accessible(access) {
+                return Err(MemoryError::AccessDenied { address: sector.address, access });
+            }
+            blocks.extend((0..sector.block_count).map(|block| sector.block(block)));
+        }
+        Ok(blocks)
+    }
+
 }
 
 impl fmt::Display for Bank {
@@ -208,6 +451,16 @@ impl Sector {
         self.block_count * self.block_size
     }
 
+    /// Returns the given block of the sector, counting from 0
+    pub fn block(&self, block: usize) -> Block {
+        Block {
+            index: self.index + block,
+            address: self.address + block * self.block_size,
+            size: self.block_size,
+            access: self.access
+        }
+    }
+
 }
 
 
@@ -217,3 +470,139 @@ impl fmt::Display for Sector {
                     self.index, self.address, self.block_count, self.block_size, self.total_size(), self.access)
     }
 }
+
+/// Block implementation
+impl Block {
+
+    /// Returns the address directly after the block
+    pub fn end_address(&self) -> usize {
+        self.address + self.size
+    }
+
+    /// Checks if the given access flags are supported by the block
+    pub fn is_accessible(&self, access: Accessibility) -> bool {
+        access & self.access == access
+    }
+
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Block [{}] @ [0x{:08X}]: [0x{:X} byte]. Access [{}]", self.index, self.address, self.size, self.access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regions_join_sectors() {
+        let first = Sector::new(0, 0x0800_0000, 4, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let second = first.next(1, 0x10000, Accessibility::READ_WRITE_ERASE);
+        let locked = second.next(1, 0x20000, Accessibility::WRITE_ERASE);
+        let last = locked.next(2, 0x20000, Accessibility::READ_ERASE);
+        let memmap = MemoryMap::new("Flash", vec![Bank::from_sectors(0, vec![first, second, locked, last])]);
+
+        assert_eq!(vec![(0x0800_0000, 0x20000), (0x0804_0000, 0x40000)], memmap.regions(Accessibility::READ));
+        assert_eq!(vec![(0x0800_0000, 0x40000)], memmap.regions(Accessibility::WRITE));
+        assert_eq!(vec![(0x0800_0000, 0x80000)], memmap.regions(Accessibility::ERASE));
+    }
+
+    #[test]
+    fn test_erase_plan_with_collateral() {
+        let first = Sector::new(0, 0x0800_0000, 4, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let second = first.next(1, 0x10000, Accessibility::READ_WRITE_ERASE);
+        let locked = second.next(1, 0x20000, Accessibility::READ_WRITE);
+        let read_only = locked.next(1, 0x20000, Accessibility::READ);
+        let memmap = MemoryMap::new("Flash", vec![Bank::from_sectors(0, vec![first, second, locked, read_only])]);
+
+        let block = memmap.find_block(0x0800_5000).unwrap();
+        assert_eq!(1, block.index);
+        assert_eq!(0x0800_4000, block.address);
+        assert_eq!(None, memmap.find_block(0x0806_0000));
+
+        // Two ranges sharing block 1, and a range ending in the middle of block 4
+        let plan = memmap.erase_plan(&[(0x0800_6000, 0x3000), (0x0800_3000, 0x2000), (0x0801_0000, 0x100)]).unwrap();
+        let indexes: Vec<usize> = plan.blocks.iter().map(|b| b.index).collect();
+        assert_eq!(vec![0, 1, 2, 4], indexes);
+        assert_eq!(
+            vec![(0x0800_0000, 0x3000), (0x0800_5000, 0x1000), (0x0800_9000, 0x3000), (0x0801_0100, 0xFF00)],
+            plan.collateral
+        );
+        assert_eq!(0x16F00, plan.collateral_size());
+
+        // Full blocks leave nothing behind
+        assert!(memmap.erase_plan(&[(0x0800_0000, 0x8000)]).unwrap().collateral.is_empty());
+
+        // Memory which can not be erased is written directly, but must be writable
+        let plan = memmap.erase_plan(&[(0x0801_F000, 0x2000)]).unwrap();
+        assert_eq!(vec![0x0801_0000], plan.blocks.iter().map(|b| b.address).collect::<Vec<usize>>());
+        assert_eq!(vec![(0x0801_0000, 0xF000)], plan.collateral);
+        assert_eq!(
+            Err(MemoryError::AccessDenied { address: 0x0804_0000, access: Accessibility::WRITE }),
+            memmap.erase_plan(&[(0x0803_F000, 0x2000)])
+        );
+        assert_eq!(Err(MemoryError::AddressNotMapped(0x0806_0000)), memmap.erase_plan(&[(0x0806_0000, 1)]));
+    }
+
+    #[test]
+    fn test_read_ranges() {
+        let first = Sector::new(0, 0x0800_0000, 4, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let locked = first.next(1, 0x10000, Accessibility::WRITE_ERASE);
+        let last = locked.next(1, 0x20000, Accessibility::READ_WRITE_ERASE);
+        let memmap = MemoryMap::new("Flash", vec![Bank::from_sectors(0, vec![first, locked, last])]);
+
+        assert_eq!(Ok(vec![(0x0800_0000, 0x10000), (0x0802_0000, 0x20000)]), memmap.read_ranges(None, None));
+        assert_eq!(Ok(vec![(0x0800_0000, 0x100)]), memmap.read_ranges(None, Some(0x100)));
+        assert_eq!(Ok(vec![(0x0800_F000, 0x1000)]), memmap.read_ranges(Some(0x0800_F000), None));
+        assert_eq!(Ok(vec![(0x0802_0000, 0x20000)]), memmap.read_ranges(Some(0x0802_0000), Some(0x20000)));
+
+        // Explicit ranges must stay in readable memory
+        assert_eq!(
+            Err(MemoryError::AccessDenied { address: 0x0801_0000, access: Accessibility::READ }),
+            memmap.read_ranges(Some(0x0800_F000), Some(0x2000))
+        );
+        assert_eq!(Err(MemoryError::AddressNotMapped(0x0804_0000)), memmap.read_ranges(Some(0x0803_F000), Some(0x2000)));
+        assert_eq!(Err(MemoryError::AddressNotMapped(0x0900_0000)), memmap.read_ranges(Some(0x0900_0000), None));
+        assert_eq!(
+            Err(MemoryError::RangeOverflow { address: usize::MAX, length: 2 }),
+            memmap.read_ranges(Some(usize::MAX), Some(2))
+        );
+    }
+
+    #[test]
+    fn test_erase_whole_memory_map() {
+        let first = Sector::new(0, 0x0800_0000, 2, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let bank2 = Sector::new(2, 0x0810_0000, 1, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let memmap = MemoryMap::new("Flash", vec![Bank::from_sectors(0, vec![first]), Bank::from_sectors(1, vec![bank2])]);
+
+        let addresses: Vec<usize> = memmap.blocks(Accessibility::ERASE).unwrap().iter().map(|b| b.address).collect();
+        assert_eq!(vec![0x0800_0000, 0x0800_4000, 0x0810_0000], addresses);
+
+        // Only erasable memory of the other map outside this map is reported
+        let inside = Sector::new(0, 0x0800_4000, 1, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let outside = Sector::new(0, 0x0820_0000, 1, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let options = Sector::new(0, 0x1FFF_C000, 1, 0x10, Accessibility::READ_WRITE);
+        let other = MemoryMap::new("Other", vec![Bank::from_sectors(0, vec![inside]), Bank::from_sectors(1, vec![outside, options])]);
+        assert_eq!(vec![(0x0820_0000, 0x4000)], memmap.regions_outside(&other, Accessibility::ERASE));
+        assert!(memmap.regions_outside(&memmap, Accessibility::ERASE).is_empty());
+    }
+
+    #[test]
+    fn test_bank_blocks() {
+        let first = Sector::new(0, 0x0800_0000, 2, 0x4000, Accessibility::READ_WRITE_ERASE);
+        let second = first.next(1, 0x10000, Accessibility::READ_WRITE_ERASE);
+        let bank = Bank::from_sectors(0, vec![first, second]);
+
+        let addresses: Vec<usize> = bank.blocks(Accessibility::ERASE).unwrap().iter().map(|b| b.address).collect();
+        assert_eq!(vec![0x0800_0000, 0x0800_4000, 0x0800_8000], addresses);
+        assert_eq!(2, bank.blocks(Accessibility::ERASE).unwrap()[2].index);
+
+        let locked = Bank::from_sectors(1, vec![Sector::new(4, 0x0801_8000, 1, 0x20000, Accessibility::READ)]);
+        assert_eq!(
+            Err(MemoryError::AccessDenied { address: 0x0801_8000, access: Accessibility::ERASE }),
+            locked.blocks(Accessibility::ERASE)
+        );
+    }
+}
@@ -1,7 +1,9 @@
 
+pub mod crc;
 pub mod parse;
 pub mod memory;
 
+use std::fmt;
 use std::process;
 
 pub trait UnwrapOrDie<T> {
@@ -16,3 +18,17 @@ impl<R, E> UnwrapOrDie<R> for Result<R, E> {
         })
     }
 }
+
+pub trait UnwrapOrReport<T> {
+    fn unwrap_or_report(self, exit_code: i32, error: &str) -> T;
+}
+
+/// Like `UnwrapOrDie`, but also prints the error carried by the result
+impl<R, E: fmt::Display> UnwrapOrReport<R> for Result<R, E> {
+    fn unwrap_or_report(self, exit_code: i32, error: &str) -> R {
+        self.unwrap_or_else(|e| {
+            eprintln!("Error: {}: {}", error, e);
+            process::exit(exit_code)
+        })
+    }
+}
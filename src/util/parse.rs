@@ -4,6 +4,10 @@ pub fn usize_from_string(instr: &str) -> Result<usize, ()> {
     if instr.len() == 0 { 
         return Err(());
     }
+    // A single 0 has nothing to skip, and is simply zero
+    if instr == "0" {
+        return Ok(0);
+    }
     // By default, set the num offset to 0 and radix to 10
     let mut radix = 10;
     let mut remain = &instr[0..];
@@ -42,4 +46,20 @@ fn skip(instr: &str) -> Result<&str, ()> {
         return Err(())
     }
     Ok(&instr[1..])
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usize_from_string() {
+        assert_eq!(Ok(0), usize_from_string("0"));
+        assert_eq!(Ok(42), usize_from_string("42"));
+        assert_eq!(Ok(0x0800_0000), usize_from_string("0x08000000"));
+        assert_eq!(Ok(8), usize_from_string("010"));
+        assert_eq!(Ok(5), usize_from_string("0b101"));
+        assert_eq!(Err(()), usize_from_string(""));
+        assert_eq!(Err(()), usize_from_string("0x"));
+    }
+}